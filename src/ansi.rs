@@ -0,0 +1,142 @@
+// Module for parsing ANSI/VT100 escape sequences
+//
+// The parser only splits the character stream into printable characters and
+// complete control sequences; interpreting them is up to the consumer (the
+// framebuffer console). Serial terminals receive the same stream unchanged.
+
+const ESC: char = '\x1b';
+
+/// Maximum number of numeric parameters kept for a CSI sequence.
+pub const MAX_PARAMS: usize = 16;
+
+/// A complete unit of output produced by the parser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// A character to draw at the cursor.
+    Print(char),
+    /// A C0 control character such as `\n`, `\r`, `\t` or backspace.
+    Control(char),
+    /// A CSI sequence, `ESC [ params final`.
+    Csi(Csi),
+    /// A two-character escape, `ESC final`, e.g. `ESC 7` (save cursor).
+    Escape(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    count: usize,
+    /// Set for private sequences such as `ESC [ ? 25 h`.
+    pub private: bool,
+    pub final_char: char,
+}
+
+impl Csi {
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.count]
+    }
+
+    /// The parameter at `index`, treating absent or zero values as `default`.
+    pub fn param_or(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&0) | None => default,
+            Some(&value) => value,
+        }
+    }
+}
+
+enum State {
+    Ground,
+    Escape,
+    CsiEntry,
+}
+
+pub struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    count: usize,
+    private: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            count: 0,
+            private: false,
+        }
+    }
+
+    /// Feeds one character, returning an action once one is complete.
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                }
+                c if c.is_control() => Some(Action::Control(c)),
+                c => Some(Action::Print(c)),
+            },
+            State::Escape => match c {
+                '[' => {
+                    self.state = State::CsiEntry;
+                    self.params = [0; MAX_PARAMS];
+                    self.count = 0;
+                    self.private = false;
+                    None
+                }
+                c => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(c))
+                }
+            },
+            State::CsiEntry => self.advance_csi(c),
+        }
+    }
+
+    fn advance_csi(&mut self, c: char) -> Option<Action> {
+        match c {
+            '0'..='9' => {
+                if self.count == 0 {
+                    self.count = 1;
+                }
+                let slot = &mut self.params[self.count - 1];
+                *slot = slot.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                None
+            }
+            ';' | ':' => {
+                // An empty parameter counts as zero; extra parameters are dropped.
+                if self.count == 0 {
+                    self.count = 1;
+                }
+                if self.count < MAX_PARAMS {
+                    self.count += 1;
+                }
+                None
+            }
+            '?' | '>' | '=' => {
+                self.private = true;
+                None
+            }
+            // Final bytes end the sequence.
+            '\x40'..='\x7e' => {
+                self.state = State::Ground;
+                Some(Action::Csi(Csi {
+                    params: self.params,
+                    count: self.count,
+                    private: self.private,
+                    final_char: c,
+                }))
+            }
+            // A new escape aborts the sequence in progress.
+            ESC => {
+                self.state = State::Escape;
+                None
+            }
+            // Intermediate bytes are accepted but not interpreted.
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,63 @@
+// Module for the audit log of permission decisions
+//
+// Every grant or refusal of an event subscription or output capability is
+// recorded here, so the system's behavior can be reviewed after the fact.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::event_loop;
+use crate::hardware::Instant;
+
+/// Entries kept before the oldest are discarded.
+pub const AUDIT_LOG_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Allowed,
+    Denied,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub timestamp: Instant,
+    /// The program that made the request.
+    pub subject: &'static str,
+    /// What was requested, e.g. `subscribe serial-input`.
+    pub action: String,
+    pub outcome: Outcome,
+}
+
+static AUDIT_LOG: Mutex<VecDeque<AuditEntry>> = Mutex::new(VecDeque::new());
+
+/// Records a permission decision.
+pub fn record(subject: &'static str, action: String, outcome: Outcome) {
+    if outcome == Outcome::Denied {
+        log::warn!("Denied {}: {}", subject, action);
+    }
+    let entry = AuditEntry {
+        timestamp: event_loop::now(),
+        subject,
+        action,
+        outcome,
+    };
+    interrupts::without_interrupts(|| {
+        let mut audit_log = AUDIT_LOG.lock();
+        if audit_log.len() == AUDIT_LOG_CAPACITY {
+            audit_log.pop_front();
+        }
+        audit_log.push_back(entry);
+    });
+}
+
+/// The most recent `count` audit entries, oldest first.
+pub fn recent(count: usize) -> Vec<AuditEntry> {
+    interrupts::without_interrupts(|| {
+        let audit_log = AUDIT_LOG.lock();
+        let skip = audit_log.len().saturating_sub(count);
+        audit_log.iter().skip(skip).cloned().collect()
+    })
+}
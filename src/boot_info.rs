@@ -0,0 +1,22 @@
+// Module for the information the bootloader hands over to the kernel
+
+use alloc::vec::Vec;
+
+use crate::config::KernelConfig;
+use crate::video::FramebufferInfo;
+
+/// Everything the kernel needs from the boot stage, gathered while UEFI boot
+/// services are still available.
+#[derive(Debug, Clone)]
+pub struct BootInfo {
+    /// Kernel policy read from `optios.conf` on the ESP.
+    pub config: KernelConfig,
+    /// The GOP framebuffer, if a usable graphics mode was found.
+    pub framebuffer: Option<FramebufferInfo>,
+    /// Raw PSF font read from `console_font`, parsed by the console.
+    pub console_font: Option<Vec<u8>>,
+    /// Compiled TZif file for the configured `timezone`, parsed by `tz`.
+    pub timezone: Option<Vec<u8>>,
+    /// Physical address of the ACPI RSDP from the UEFI configuration table.
+    pub rsdp: Option<u64>,
+}
@@ -0,0 +1,359 @@
+// Module for the kernel policy configuration file (optios.conf)
+//
+// The file lives at the root of the EFI system partition and holds one
+// `key = value` pair per line. Blank lines and lines starting with `#` are
+// ignored. Unknown keys are an error so typos do not silently fall back to
+// defaults.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use uefi::prelude::*;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::file::{File, FileAttribute, FileMode};
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::CStr16;
+
+use crate::console::DEFAULT_SCROLLBACK_LINES;
+use crate::keyboard::Layout;
+use crate::loader::DEFAULT_MAX_FOREGROUND;
+use crate::logger::LogFilter;
+use crate::power::DEFAULT_GRACE_PERIOD_SECS;
+use crate::serial;
+use crate::video::ModePolicy;
+
+/// Size of the buffer the config file is read into.
+const CONFIG_READ_CHUNK: usize = 4096;
+
+/// Kernel policies, with the defaults used when the file or a key is absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelConfig {
+    /// Number of concurrent foreground processes.
+    pub max_foreground: usize,
+    /// Longest a background handler may run, in seconds.
+    pub background_timeout_secs: u64,
+    /// Interval between `background-schedule` events, in seconds.
+    pub schedule_interval_secs: u64,
+    /// How the bootloader picks a graphics mode.
+    pub video_mode: ModePolicy,
+    /// Largest graphics mode the bootloader will select.
+    pub max_width: usize,
+    pub max_height: usize,
+    /// PSF1/PSF2 console font on the ESP; the built-in font is used when unset.
+    pub console_font: Option<String>,
+    /// Lines of console output kept for scrolling back.
+    pub scrollback_lines: usize,
+    /// Log levels, globally and per module.
+    pub log_filter: LogFilter,
+    /// Serial port the GDB stub listens on; disabled when unset.
+    pub gdb_port: Option<u16>,
+    /// Time handlers get to react to `system-shutdown`, in seconds.
+    pub shutdown_grace_secs: u64,
+    /// Timezone name, read from `zoneinfo/<name>` on the ESP; UTC when unset.
+    pub timezone: Option<String>,
+    /// Layout used to turn PS/2 keyboard keys into characters.
+    pub keyboard_layout: Layout,
+}
+
+impl Default for KernelConfig {
+    fn default() -> KernelConfig {
+        KernelConfig {
+            max_foreground: DEFAULT_MAX_FOREGROUND,
+            background_timeout_secs: 5 * 60,
+            schedule_interval_secs: 15 * 60,
+            video_mode: ModePolicy::Native,
+            max_width: 1920,
+            max_height: 1080,
+            console_font: None,
+            scrollback_lines: DEFAULT_SCROLLBACK_LINES,
+            log_filter: LogFilter::default(),
+            gdb_port: None,
+            shutdown_grace_secs: DEFAULT_GRACE_PERIOD_SECS,
+            timezone: None,
+            keyboard_layout: Layout::Us,
+        }
+    }
+}
+
+// --- Errors ---
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigErrorKind {
+    /// The line is not of the form `key = value`.
+    MissingEquals,
+    UnknownKey(String),
+    DuplicateKey(String),
+    /// The value could not be parsed as the key's type.
+    InvalidValue { key: String, value: String },
+    /// The value parsed but is outside the key's allowed range.
+    OutOfRange { key: String, min: u64, max: u64 },
+}
+
+/// A parse or validation error, tied to the 1-based line it occurred on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub line: usize,
+    pub kind: ConfigErrorKind,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: ", CONFIG_FILE_NAME, self.line)?;
+        match &self.kind {
+            ConfigErrorKind::MissingEquals => write!(f, "expected `key = value`"),
+            ConfigErrorKind::UnknownKey(key) => write!(f, "unknown key `{}`", key),
+            ConfigErrorKind::DuplicateKey(key) => write!(f, "duplicate key `{}`", key),
+            ConfigErrorKind::InvalidValue { key, value } => {
+                write!(f, "invalid value `{}` for `{}`", value, key)
+            }
+            ConfigErrorKind::OutOfRange { key, min, max } => {
+                write!(f, "`{}` must be between {} and {}", key, min, max)
+            }
+        }
+    }
+}
+
+// --- Parsing ---
+
+pub const CONFIG_FILE_NAME: &str = "optios.conf";
+
+/// Parses an unsigned integer value and checks it against an inclusive range.
+fn parse_ranged(line: usize, key: &str, value: &str, min: u64, max: u64) -> Result<u64, ConfigError> {
+    let parsed: u64 = value.parse().map_err(|_| ConfigError {
+        line,
+        kind: ConfigErrorKind::InvalidValue {
+            key: String::from(key),
+            value: String::from(value),
+        },
+    })?;
+    if parsed < min || parsed > max {
+        return Err(ConfigError {
+            line,
+            kind: ConfigErrorKind::OutOfRange {
+                key: String::from(key),
+                min,
+                max,
+            },
+        });
+    }
+    Ok(parsed)
+}
+
+impl KernelConfig {
+    /// Parses the contents of `optios.conf`, starting from the defaults.
+    pub fn parse(text: &str) -> Result<KernelConfig, ConfigError> {
+        let mut config = KernelConfig::default();
+        let mut seen: Vec<&str> = Vec::new();
+
+        for (index, raw_line) in text.lines().enumerate() {
+            let line = index + 1;
+            let content = raw_line.trim();
+            if content.is_empty() || content.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = content.split_once('=').ok_or(ConfigError {
+                line,
+                kind: ConfigErrorKind::MissingEquals,
+            })?;
+            let key = key.trim();
+            let value = value.trim();
+
+            if seen.contains(&key) {
+                return Err(ConfigError {
+                    line,
+                    kind: ConfigErrorKind::DuplicateKey(String::from(key)),
+                });
+            }
+            config.apply(line, key, value)?;
+            seen.push(key);
+        }
+        Ok(config)
+    }
+
+    /// Sets a single key. Ranges are validated here so every error carries
+    /// the line that caused it.
+    fn apply(&mut self, line: usize, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "max_foreground" => {
+                self.max_foreground = parse_ranged(line, key, value, 1, 64)? as usize;
+            }
+            "background_timeout_secs" => {
+                self.background_timeout_secs = parse_ranged(line, key, value, 1, 24 * 60 * 60)?;
+            }
+            "schedule_interval_secs" => {
+                self.schedule_interval_secs = parse_ranged(line, key, value, 1, 7 * 24 * 60 * 60)?;
+            }
+            "video_mode" => {
+                self.video_mode = ModePolicy::parse(value).ok_or(ConfigError {
+                    line,
+                    kind: ConfigErrorKind::InvalidValue {
+                        key: String::from(key),
+                        value: String::from(value),
+                    },
+                })?;
+            }
+            "max_width" => {
+                self.max_width = parse_ranged(line, key, value, 320, 16384)? as usize;
+            }
+            "max_height" => {
+                self.max_height = parse_ranged(line, key, value, 200, 16384)? as usize;
+            }
+            "log_filter" => {
+                self.log_filter = LogFilter::parse(value).ok_or(ConfigError {
+                    line,
+                    kind: ConfigErrorKind::InvalidValue {
+                        key: String::from(key),
+                        value: String::from(value),
+                    },
+                })?;
+            }
+            "gdb_port" => {
+                // COM1 carries the kernel console, so it cannot be shared.
+                self.gdb_port = match value {
+                    "off" => None,
+                    "com2" => Some(serial::COM2),
+                    "com3" => Some(serial::COM3),
+                    "com4" => Some(serial::COM4),
+                    _ => {
+                        return Err(ConfigError {
+                            line,
+                            kind: ConfigErrorKind::InvalidValue {
+                                key: String::from(key),
+                                value: String::from(value),
+                            },
+                        })
+                    }
+                };
+            }
+            "shutdown_grace_secs" => {
+                self.shutdown_grace_secs = parse_ranged(line, key, value, 0, 60)?;
+            }
+            "scrollback_lines" => {
+                self.scrollback_lines = parse_ranged(line, key, value, 0, 100_000)? as usize;
+            }
+            "console_font" => {
+                if value.is_empty() {
+                    return Err(ConfigError {
+                        line,
+                        kind: ConfigErrorKind::InvalidValue {
+                            key: String::from(key),
+                            value: String::from(value),
+                        },
+                    });
+                }
+                self.console_font = Some(String::from(value));
+            }
+            "keyboard_layout" => {
+                self.keyboard_layout = Layout::from_name(value).ok_or_else(|| ConfigError {
+                    line,
+                    kind: ConfigErrorKind::InvalidValue {
+                        key: String::from(key),
+                        value: String::from(value),
+                    },
+                })?;
+            }
+            "timezone" => {
+                // Names are relative to zoneinfo/, e.g. Europe/London.
+                if value.is_empty() || value.starts_with('/') || value.split('/').any(|part| part == "..") {
+                    return Err(ConfigError {
+                        line,
+                        kind: ConfigErrorKind::InvalidValue {
+                            key: String::from(key),
+                            value: String::from(value),
+                        },
+                    });
+                }
+                self.timezone = match value {
+                    "UTC" => None,
+                    _ => Some(String::from(value)),
+                };
+            }
+            _ => {
+                return Err(ConfigError {
+                    line,
+                    kind: ConfigErrorKind::UnknownKey(String::from(key)),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+// --- Loading from the ESP ---
+
+/// Reads a file from the root of the volume this image was loaded from.
+pub fn read_esp_file(
+    bt: &BootServices,
+    image_handle: Handle,
+    name: &CStr16,
+) -> uefi::Result<Vec<u8>> {
+    let loaded_image = bt.open_protocol_exclusive::<LoadedImage>(image_handle)?;
+    let mut fs = bt.open_protocol_exclusive::<SimpleFileSystem>(loaded_image.device())?;
+    let mut root = fs.open_volume()?;
+    let handle = root.open(name, FileMode::Read, FileAttribute::empty())?;
+    let mut file = handle
+        .into_regular_file()
+        .ok_or(uefi::Error::from(Status::INVALID_PARAMETER))?;
+
+    let mut contents = Vec::new();
+    let mut chunk = [0u8; CONFIG_READ_CHUNK];
+    loop {
+        let read = file
+            .read(&mut chunk)
+            .map_err(|e| uefi::Error::from(e.status()))?;
+        if read == 0 {
+            break;
+        }
+        contents.extend_from_slice(&chunk[..read]);
+    }
+    Ok(contents)
+}
+
+/// Maximum length of an ESP path, in UCS-2 characters.
+const MAX_PATH_LENGTH: usize = 256;
+
+/// Reads a file from the ESP given a `/`-separated path relative to its root.
+pub fn read_esp_path(bt: &BootServices, image_handle: Handle, path: &str) -> uefi::Result<Vec<u8>> {
+    let uefi_path: String = path.trim_start_matches('/').replace('/', "\\");
+    let mut buf = [0u16; MAX_PATH_LENGTH];
+    let name = CStr16::from_str_with_buf(&uefi_path, &mut buf)
+        .map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+    read_esp_file(bt, image_handle, name)
+}
+
+/// Loads `optios.conf` from the ESP. A missing or invalid file is logged and
+/// the defaults are used, so a bad config can never stop the system booting.
+pub fn load(bt: &BootServices, image_handle: Handle) -> KernelConfig {
+    let contents = match read_esp_file(bt, image_handle, cstr16!("optios.conf")) {
+        Ok(contents) => contents,
+        Err(e) if e.status() == Status::NOT_FOUND => {
+            log::info!("No {} found, using default kernel policy.", CONFIG_FILE_NAME);
+            return KernelConfig::default();
+        }
+        Err(e) => {
+            log::error!("Failed to read {}: {:?}", CONFIG_FILE_NAME, e);
+            return KernelConfig::default();
+        }
+    };
+
+    let text = match core::str::from_utf8(&contents) {
+        Ok(text) => text,
+        Err(_) => {
+            log::error!("{} is not valid UTF-8, using default kernel policy.", CONFIG_FILE_NAME);
+            return KernelConfig::default();
+        }
+    };
+
+    match KernelConfig::parse(text) {
+        Ok(config) => {
+            log::info!("Loaded kernel policy from {}: {:?}", CONFIG_FILE_NAME, config);
+            config
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            log::error!("Using default kernel policy.");
+            KernelConfig::default()
+        }
+    }
+}
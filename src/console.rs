@@ -0,0 +1,634 @@
+// Module for the text console drawn on the GOP framebuffer
+//
+// This replaces vga_text.rs on UEFI systems, where there is no VGA text
+// buffer at 0xb8000. The API mirrors vga_text.rs so callers can switch over
+// by changing the module name. Output is interpreted as a VT100-style
+// terminal stream, so ANSI colors and cursor movement work as over serial.
+
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::ansi::{Action, Csi, Parser};
+use crate::font::Font;
+use crate::video::FramebufferInfo;
+
+// --- Color Definitions ---
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Color {
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+impl Color {
+    /// The standard VGA palette entry for this color.
+    pub fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Black => (0x00, 0x00, 0x00),
+            Color::Blue => (0x00, 0x00, 0xAA),
+            Color::Green => (0x00, 0xAA, 0x00),
+            Color::Cyan => (0x00, 0xAA, 0xAA),
+            Color::Red => (0xAA, 0x00, 0x00),
+            Color::Magenta => (0xAA, 0x00, 0xAA),
+            Color::Brown => (0xAA, 0x55, 0x00),
+            Color::LightGray => (0xAA, 0xAA, 0xAA),
+            Color::DarkGray => (0x55, 0x55, 0x55),
+            Color::LightBlue => (0x55, 0x55, 0xFF),
+            Color::LightGreen => (0x55, 0xFF, 0x55),
+            Color::LightCyan => (0x55, 0xFF, 0xFF),
+            Color::LightRed => (0xFF, 0x55, 0x55),
+            Color::Pink => (0xFF, 0x55, 0xFF),
+            Color::Yellow => (0xFF, 0xFF, 0x55),
+            Color::White => (0xFF, 0xFF, 0xFF),
+        }
+    }
+
+    /// Maps an ANSI color index (black, red, green, yellow, blue, magenta,
+    /// cyan, white, then the bright variants) onto the VGA palette.
+    pub fn from_ansi(index: u8) -> Color {
+        const ANSI_ORDER: [Color; 16] = [
+            Color::Black,
+            Color::Red,
+            Color::Green,
+            Color::Brown,
+            Color::Blue,
+            Color::Magenta,
+            Color::Cyan,
+            Color::LightGray,
+            Color::DarkGray,
+            Color::LightRed,
+            Color::LightGreen,
+            Color::Yellow,
+            Color::LightBlue,
+            Color::Pink,
+            Color::LightCyan,
+            Color::White,
+        ];
+        ANSI_ORDER[(index & 0x0F) as usize]
+    }
+}
+
+/// A color as set by the program: a palette entry, which bold may brighten,
+/// or an exact RGB value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorSpec {
+    Ansi(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl ColorSpec {
+    fn from_color(color: Color) -> ColorSpec {
+        let index = (0..16).find(|&i| Color::from_ansi(i) == color).unwrap_or(7);
+        ColorSpec::Ansi(index)
+    }
+
+    /// Resolves an xterm 256-color index.
+    fn from_256(index: u16) -> ColorSpec {
+        match index {
+            0..=15 => ColorSpec::Ansi(index as u8),
+            16..=231 => {
+                const LEVELS: [u8; 6] = [0x00, 0x5f, 0x87, 0xaf, 0xd7, 0xff];
+                let i = (index - 16) as usize;
+                ColorSpec::Rgb(LEVELS[i / 36], LEVELS[(i / 6) % 6], LEVELS[i % 6])
+            }
+            _ => {
+                let level = (8 + 10 * (index.min(255) - 232)) as u8;
+                ColorSpec::Rgb(level, level, level)
+            }
+        }
+    }
+
+    fn rgb(self, bold: bool) -> (u8, u8, u8) {
+        match self {
+            ColorSpec::Ansi(index) if bold && index < 8 => Color::from_ansi(index + 8).rgb(),
+            ColorSpec::Ansi(index) => Color::from_ansi(index).rgb(),
+            ColorSpec::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+}
+
+/// Cell height small fonts are scaled up towards on high-resolution screens.
+const TARGET_CELL_HEIGHT: usize = 16;
+
+/// Columns between tab stops.
+const TAB_WIDTH: usize = 8;
+
+/// Lines kept in the scrollback buffer unless the boot config says otherwise.
+pub const DEFAULT_SCROLLBACK_LINES: usize = 1000;
+
+/// One character cell with its encoded pixel colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    c: char,
+    foreground: u32,
+    background: u32,
+}
+
+/// Ways to move the scrollback view, bound to keys by the keyboard driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrollCommand {
+    LineUp,
+    LineDown,
+    PageUp,
+    PageDown,
+    /// Jump to the oldest line in the scrollback buffer.
+    Top,
+    /// Return to the live screen and follow new output.
+    FollowTail,
+}
+
+// --- Writer Implementation ---
+pub struct Writer {
+    framebuffer: FramebufferInfo,
+    font: Font,
+    parser: Parser,
+    column_position: usize,
+    row_position: usize,
+    saved_position: (usize, usize),
+    columns: usize,
+    rows: usize,
+    /// Integer scale applied to the font, so text stays legible at high resolutions.
+    scale: usize,
+    /// Contents of the live screen, row by row.
+    screen: Vec<Cell>,
+    /// Lines that scrolled off the top of the screen, oldest first.
+    scrollback: VecDeque<Vec<Cell>>,
+    scrollback_capacity: usize,
+    /// How many lines the view is scrolled back; zero follows the tail.
+    view_offset: usize,
+    foreground: ColorSpec,
+    background: ColorSpec,
+    /// Colors restored by SGR 0, 39 and 49; set with `set_color`.
+    default_foreground: ColorSpec,
+    default_background: ColorSpec,
+    bold: bool,
+    reverse: bool,
+}
+
+impl Writer {
+    pub fn new(framebuffer: FramebufferInfo, font: Font, scrollback_lines: usize) -> Writer {
+        let scale = if framebuffer.width >= 1024 {
+            (TARGET_CELL_HEIGHT / font.height).max(1)
+        } else {
+            1
+        };
+        let foreground = ColorSpec::from_color(Color::LightGray);
+        let background = ColorSpec::from_color(Color::Black);
+        // At least one cell, so cursor arithmetic never underflows even if
+        // the font is larger than the screen.
+        let columns = (framebuffer.width / (font.width * scale)).max(1);
+        let rows = (framebuffer.height / (font.height * scale)).max(1);
+        let blank = Cell {
+            c: ' ',
+            foreground: 0,
+            background: 0,
+        };
+        Writer {
+            framebuffer,
+            parser: Parser::new(),
+            column_position: 0,
+            row_position: 0,
+            saved_position: (0, 0),
+            columns,
+            rows,
+            font,
+            scale,
+            screen: vec![blank; columns * rows],
+            scrollback: VecDeque::new(),
+            scrollback_capacity: scrollback_lines,
+            view_offset: 0,
+            foreground,
+            background,
+            default_foreground: foreground,
+            default_background: background,
+            bold: false,
+            reverse: false,
+        }
+    }
+
+    fn cell_width(&self) -> usize {
+        self.font.width * self.scale
+    }
+
+    fn cell_height(&self) -> usize {
+        self.font.height * self.scale
+    }
+
+    /// The encoded (foreground, background) pixel values for new text.
+    fn pixel_colors(&self) -> (u32, u32) {
+        let (fr, fg, fb) = self.foreground.rgb(self.bold);
+        let (br, bg, bb) = self.background.rgb(false);
+        let layout = &self.framebuffer.layout;
+        let pair = (layout.encode(fr, fg, fb), layout.encode(br, bg, bb));
+        if self.reverse {
+            (pair.1, pair.0)
+        } else {
+            pair
+        }
+    }
+
+    /// Feeds one character through the escape sequence parser.
+    pub fn write_char(&mut self, c: char) {
+        match self.parser.advance(c) {
+            Some(Action::Print(c)) => self.put_glyph(c),
+            Some(Action::Control(c)) => self.control(c),
+            Some(Action::Csi(csi)) => self.csi(&csi),
+            Some(Action::Escape(c)) => self.escape(c),
+            None => {}
+        }
+    }
+
+    fn put_glyph(&mut self, c: char) {
+        if self.column_position >= self.columns {
+            self.new_line();
+        }
+        self.draw_glyph(self.column_position, self.row_position, c);
+        self.column_position += 1;
+    }
+
+    fn control(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column_position = 0,
+            '\t' => {
+                let next = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.column_position = next.min(self.columns - 1);
+            }
+            '\x08' => self.column_position = self.column_position.saturating_sub(1),
+            // Other control characters have no glyph and are dropped
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, c: char) {
+        match c {
+            '7' => self.saved_position = (self.column_position, self.row_position),
+            '8' => (self.column_position, self.row_position) = self.saved_position,
+            'c' => {
+                self.reset_attributes();
+                self.clear();
+            }
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, csi: &Csi) {
+        if csi.private {
+            // Private modes (cursor visibility, etc.) have no effect here.
+            return;
+        }
+        let n = csi.param_or(0, 1) as usize;
+        let last_column = self.columns - 1;
+        let last_row = self.rows - 1;
+        match csi.final_char {
+            'A' => self.row_position = self.row_position.saturating_sub(n),
+            'B' => self.row_position = (self.row_position + n).min(last_row),
+            'C' => self.column_position = (self.column_position + n).min(last_column),
+            'D' => self.column_position = self.column_position.saturating_sub(n),
+            'E' => {
+                self.row_position = (self.row_position + n).min(last_row);
+                self.column_position = 0;
+            }
+            'F' => {
+                self.row_position = self.row_position.saturating_sub(n);
+                self.column_position = 0;
+            }
+            'G' => self.column_position = (n - 1).min(last_column),
+            'H' | 'f' => {
+                self.row_position = (csi.param_or(0, 1) as usize - 1).min(last_row);
+                self.column_position = (csi.param_or(1, 1) as usize - 1).min(last_column);
+            }
+            'J' => self.erase_display(csi.params().first().copied().unwrap_or(0)),
+            'K' => self.erase_line(csi.params().first().copied().unwrap_or(0)),
+            'm' => self.select_graphic_rendition(csi.params()),
+            's' => self.saved_position = (self.column_position, self.row_position),
+            'u' => (self.column_position, self.row_position) = self.saved_position,
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.reset_attributes();
+            return;
+        }
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                0 => self.reset_attributes(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                code @ 30..=37 => self.foreground = ColorSpec::Ansi((code - 30) as u8),
+                code @ 90..=97 => self.foreground = ColorSpec::Ansi((code - 90 + 8) as u8),
+                code @ 40..=47 => self.background = ColorSpec::Ansi((code - 40) as u8),
+                code @ 100..=107 => self.background = ColorSpec::Ansi((code - 100 + 8) as u8),
+                39 => self.foreground = self.default_foreground,
+                49 => self.background = self.default_background,
+                code @ (38 | 48) => {
+                    let (spec, used) = match params.get(i + 1) {
+                        Some(5) => (params.get(i + 2).map(|&n| ColorSpec::from_256(n)), 2),
+                        Some(2) if params.len() > i + 4 => {
+                            let channel = |j: usize| params[i + j].min(255) as u8;
+                            (Some(ColorSpec::Rgb(channel(2), channel(3), channel(4))), 4)
+                        }
+                        _ => (None, params.len()),
+                    };
+                    if let Some(spec) = spec {
+                        if code == 38 {
+                            self.foreground = spec;
+                        } else {
+                            self.background = spec;
+                        }
+                    }
+                    i += used;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    fn reset_attributes(&mut self) {
+        self.foreground = self.default_foreground;
+        self.background = self.default_background;
+        self.bold = false;
+        self.reverse = false;
+    }
+
+    /// ED: 0 erases to the end of the screen, 1 to the start, 2 and 3 all of it.
+    fn erase_display(&mut self, mode: u16) {
+        let (column, row) = (self.column_position, self.row_position);
+        match mode {
+            0 => {
+                self.clear_cells(row, column, self.columns);
+                for r in row + 1..self.rows {
+                    self.clear_row(r);
+                }
+            }
+            1 => {
+                for r in 0..row {
+                    self.clear_row(r);
+                }
+                self.clear_cells(row, 0, column + 1);
+            }
+            _ => {
+                for r in 0..self.rows {
+                    self.clear_row(r);
+                }
+            }
+        }
+    }
+
+    /// EL: 0 erases to the end of the line, 1 to the start, 2 the whole line.
+    fn erase_line(&mut self, mode: u16) {
+        let (column, row) = (self.column_position, self.row_position);
+        match mode {
+            0 => self.clear_cells(row, column, self.columns),
+            1 => self.clear_cells(row, 0, column + 1),
+            _ => self.clear_row(row),
+        }
+    }
+
+    fn following(&self) -> bool {
+        self.view_offset == 0
+    }
+
+    fn draw_glyph(&mut self, column: usize, row: usize, c: char) {
+        let (foreground, background) = self.pixel_colors();
+        let cell = Cell {
+            c,
+            foreground,
+            background,
+        };
+        self.screen[row * self.columns + column] = cell;
+        if self.following() {
+            self.render_cell(column, row, cell);
+        }
+    }
+
+    fn render_cell(&self, column: usize, row: usize, cell: Cell) {
+        let origin_x = column * self.cell_width();
+        let origin_y = row * self.cell_height();
+        let glyph = self.font.glyph(cell.c);
+
+        for gy in 0..self.font.height {
+            for gx in 0..self.font.width {
+                let value = if self.font.pixel(glyph, gx, gy) {
+                    cell.foreground
+                } else {
+                    cell.background
+                };
+                for sy in 0..self.scale {
+                    for sx in 0..self.scale {
+                        let x = origin_x + gx * self.scale + sx;
+                        let y = origin_y + gy * self.scale + sy;
+                        unsafe { self.framebuffer.write_pixel(x, y, value) };
+                    }
+                }
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position + 1 < self.rows {
+            self.row_position += 1;
+            return;
+        }
+        self.scroll_up();
+        self.clear_row(self.rows - 1);
+    }
+
+    /// Moves every text row up by one, keeping the top row in the scrollback.
+    fn scroll_up(&mut self) {
+        let top: Vec<Cell> = self.screen.drain(..self.columns).collect();
+        self.screen.extend_from_slice(&top);
+        if self.scrollback_capacity > 0 {
+            if self.scrollback.len() == self.scrollback_capacity {
+                self.scrollback.pop_front();
+            } else if !self.following() {
+                // Keep the lines being inspected in place.
+                self.view_offset += 1;
+            }
+            self.scrollback.push_back(top);
+        }
+
+        if self.following() {
+            let fb = &self.framebuffer;
+            let row_bytes = fb.stride * fb.layout.bytes_per_pixel;
+            let line_bytes = row_bytes * self.cell_height();
+            let text_bytes = line_bytes * self.rows;
+            unsafe {
+                core::ptr::copy(fb.base.add(line_bytes), fb.base, text_bytes - line_bytes);
+            }
+        }
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        let columns = self.columns;
+        self.clear_cells(row, 0, columns);
+        if !self.following() {
+            return;
+        }
+        // Also clear the strip right of the last full column.
+        let (_, background) = self.pixel_colors();
+        let top = row * self.cell_height();
+        for y in top..top + self.cell_height() {
+            for x in columns * self.cell_width()..self.framebuffer.width {
+                unsafe { self.framebuffer.write_pixel(x, y, background) };
+            }
+        }
+    }
+
+    /// Fills the cells `[start, end)` of a row with the background color.
+    fn clear_cells(&mut self, row: usize, start: usize, end: usize) {
+        let (foreground, background) = self.pixel_colors();
+        let blank = Cell {
+            c: ' ',
+            foreground,
+            background,
+        };
+        for column in start..end.min(self.columns) {
+            self.screen[row * self.columns + column] = blank;
+            if self.following() {
+                self.render_cell(column, row, blank);
+            }
+        }
+    }
+
+    // --- Scrollback ---
+
+    /// Moves the view through the scrollback buffer and redraws the screen.
+    pub fn scroll(&mut self, command: ScrollCommand) {
+        let page = self.rows.saturating_sub(1).max(1);
+        let max_offset = self.scrollback.len();
+        let offset = match command {
+            ScrollCommand::LineUp => self.view_offset + 1,
+            ScrollCommand::LineDown => self.view_offset.saturating_sub(1),
+            ScrollCommand::PageUp => self.view_offset + page,
+            ScrollCommand::PageDown => self.view_offset.saturating_sub(page),
+            ScrollCommand::Top => max_offset,
+            ScrollCommand::FollowTail => 0,
+        }
+        .min(max_offset);
+
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.render_view();
+        }
+    }
+
+    /// Redraws the whole screen from the scrollback and live screen contents.
+    fn render_view(&self) {
+        let first = self.scrollback.len() - self.view_offset;
+        for row in 0..self.rows {
+            let line = first + row;
+            for column in 0..self.columns {
+                let cell = match self.scrollback.get(line) {
+                    Some(history) => history[column],
+                    None => self.screen[(line - self.scrollback.len()) * self.columns + column],
+                };
+                self.render_cell(column, row, cell);
+            }
+        }
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        // Missing code points are drawn with the font's replacement glyph.
+        for c in s.chars() {
+            self.write_char(c);
+        }
+    }
+
+    /// Sets the current and default colors for subsequent prints.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.default_foreground = ColorSpec::from_color(foreground);
+        self.default_background = ColorSpec::from_color(background);
+        self.foreground = self.default_foreground;
+        self.background = self.default_background;
+    }
+
+    pub fn clear(&mut self) {
+        self.view_offset = 0;
+        let (r, g, b) = self.background.rgb(false);
+        self.framebuffer.fill(r, g, b);
+        for row in 0..self.rows {
+            let columns = self.columns;
+            self.clear_cells(row, 0, columns);
+        }
+        self.column_position = 0;
+        self.row_position = 0;
+    }
+}
+
+// --- Formatting Macro Support ---
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+// --- Global Writer Instance ---
+// Empty until `init` is given a framebuffer; printing before that is a no-op.
+pub static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+/// Runs `f` on the console, if it is up. Interrupts stay off so an IRQ or a
+/// preempted process never finds the console locked.
+fn with_writer(f: impl FnOnce(&mut Writer)) {
+    interrupts::without_interrupts(|| {
+        if let Some(writer) = WRITER.lock().as_mut() {
+            f(writer);
+        }
+    });
+}
+
+/// Starts the console on the given framebuffer and clears it.
+pub fn init(framebuffer: FramebufferInfo, font: Font, scrollback_lines: usize) {
+    let mut writer = Writer::new(framebuffer, font, scrollback_lines);
+    writer.clear();
+    interrupts::without_interrupts(|| *WRITER.lock() = Some(writer));
+}
+
+// Helper function called by the print!/println! macros in main.rs
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    with_writer(|writer| writer.write_fmt(args).unwrap());
+}
+
+/// Sets the foreground and background colors for subsequent prints.
+pub fn set_text_color(foreground: Color, background: Color) {
+    with_writer(|writer| writer.set_color(foreground, background));
+}
+
+/// Clears the screen with the current background color.
+pub fn clear_screen() {
+    with_writer(|writer| writer.clear());
+}
+
+/// Moves the scrollback view; called by the keyboard driver.
+pub fn scroll(command: ScrollCommand) {
+    with_writer(|writer| writer.scroll(command));
+}
@@ -1 +1,577 @@
-// Placeholder for event loop and handler execution logic 
\ No newline at end of file
+// Module for the kernel event queue and handler dispatcher
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::audit::{self, Outcome};
+use crate::hardware::Instant;
+use crate::keyboard::KeyEvent;
+use crate::manifest::Manifest;
+use crate::rtc::DateTime;
+
+// --- Event Definitions ---
+
+/// The kinds of events a handler can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// Periodic background schedule (every 15 minutes by default).
+    BackgroundSchedule,
+    /// Emitted when one or more subscription queues had to discard events.
+    EventsDropped,
+    /// A byte received on the serial console.
+    SerialInput,
+    /// A key pressed or released on the PS/2 keyboard.
+    KeyInput,
+    /// The RTC alarm set with `rtc::set_alarm` went off.
+    RtcAlarm,
+    /// The RTC finished its once-a-second update, when enabled.
+    RtcUpdate,
+    /// The system clock was set; timestamps on the wall clock jumped.
+    ClockChanged,
+    /// The machine is about to power off or reboot; handlers should flush
+    /// their snapshots within the grace period.
+    SystemShutdown,
+    /// Fired by hand from the debug shell.
+    Test,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 9] = [
+        EventKind::BackgroundSchedule,
+        EventKind::EventsDropped,
+        EventKind::SerialInput,
+        EventKind::KeyInput,
+        EventKind::RtcAlarm,
+        EventKind::RtcUpdate,
+        EventKind::ClockChanged,
+        EventKind::SystemShutdown,
+        EventKind::Test,
+    ];
+
+    /// Looks up an event kind by its manifest name.
+    pub fn from_name(name: &str) -> Option<EventKind> {
+        EventKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// The name of the event as used in manifests and logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::BackgroundSchedule => "background-schedule",
+            EventKind::EventsDropped => "events-dropped",
+            EventKind::SerialInput => "serial-input",
+            EventKind::KeyInput => "key-input",
+            EventKind::RtcAlarm => "rtc-alarm",
+            EventKind::RtcUpdate => "rtc-update",
+            EventKind::ClockChanged => "clock-changed",
+            EventKind::SystemShutdown => "system-shutdown",
+            EventKind::Test => "test",
+        }
+    }
+
+    /// Priority used when an event is emitted without an explicit one.
+    pub fn default_priority(&self) -> Priority {
+        match self {
+            EventKind::BackgroundSchedule => Priority::Low,
+            EventKind::EventsDropped => Priority::High,
+            EventKind::SerialInput => Priority::High,
+            EventKind::KeyInput => Priority::High,
+            EventKind::RtcAlarm => Priority::High,
+            EventKind::RtcUpdate => Priority::Low,
+            EventKind::ClockChanged => Priority::Normal,
+            EventKind::SystemShutdown => Priority::Critical,
+            EventKind::Test => Priority::Normal,
+        }
+    }
+
+    fn bit(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+/// The set of event kinds a program is allowed to subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EventSet(u32);
+
+impl EventSet {
+    pub const fn empty() -> EventSet {
+        EventSet(0)
+    }
+
+    /// Returns the set with `kind` added.
+    pub const fn with(self, kind: EventKind) -> EventSet {
+        EventSet(self.0 | 1 << kind as u32)
+    }
+
+    pub fn contains(&self, kind: EventKind) -> bool {
+        self.0 & kind.bit() != 0
+    }
+}
+
+/// Priority classes for events and handlers, lowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+    Critical,
+}
+
+impl Priority {
+    /// High and Critical work is never throttled for the foreground.
+    pub fn is_urgent(&self) -> bool {
+        *self >= Priority::High
+    }
+}
+
+/// Data carried by an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventPayload {
+    None,
+    /// Number of events discarded by a subscription since the last report.
+    Dropped { subscription: SubscriptionId, count: u64 },
+    /// A byte received on the serial port at `port`.
+    SerialInput { port: u16, byte: u8 },
+    /// A key press or release with the modifiers held at the time.
+    Key(KeyEvent),
+    /// The calendar time an RTC event is for.
+    DateTime(DateTime),
+    /// The new system time and how far it moved, in seconds.
+    ClockChanged { current: DateTime, adjustment_secs: i64 },
+    /// Sent with `system-shutdown`; true if the machine will restart.
+    Shutdown { reboot: bool },
+}
+
+/// A timestamped event as delivered to handlers.
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub kind: EventKind,
+    pub priority: Priority,
+    pub timestamp: Instant,
+    pub payload: EventPayload,
+}
+
+// --- Subscriptions ---
+
+pub type SubscriptionId = u32;
+
+/// A handler entry point. Handlers run to completion.
+pub type Handler = fn(&Event);
+
+/// What a subscription does when its queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Discard the oldest pending event to make room for the new one.
+    DropOldest,
+    /// Discard the incoming event.
+    DropNewest,
+    /// Keep at most one pending event of each kind, replacing it with the latest.
+    CoalesceLatest,
+    /// Refuse the event so the emitter has to wait for the handler to catch up.
+    BlockEmitter,
+}
+
+/// Default queue depth, small enough for IoT-class devices.
+pub const DEFAULT_QUEUE_DEPTH: usize = 16;
+
+struct Subscription {
+    id: SubscriptionId,
+    kind: EventKind,
+    manifest: &'static Manifest,
+    handler: Handler,
+    priority: Priority,
+    policy: QueuePolicy,
+    depth: usize,
+    queue: VecDeque<Event>,
+    dropped: u64,
+}
+
+impl Subscription {
+    /// The priority the next pending event would run at: the higher of the
+    /// event's and the handler's class.
+    fn next_priority(&self) -> Option<Priority> {
+        self.queue.front().map(|e| e.priority.max(self.priority))
+    }
+
+    /// Queues an event according to the subscription policy.
+    /// Returns false only when the emitter has to block.
+    fn push(&mut self, event: Event) -> bool {
+        if self.policy == QueuePolicy::CoalesceLatest {
+            if let Some(pending) = self.queue.iter_mut().find(|e| e.kind == event.kind) {
+                *pending = event;
+                return true;
+            }
+        }
+
+        if self.queue.len() < self.depth {
+            self.queue.push_back(event);
+            return true;
+        }
+
+        match self.policy {
+            QueuePolicy::DropOldest | QueuePolicy::CoalesceLatest => {
+                self.queue.pop_front();
+                self.queue.push_back(event);
+                self.dropped += 1;
+                true
+            }
+            QueuePolicy::DropNewest => {
+                self.dropped += 1;
+                true
+            }
+            QueuePolicy::BlockEmitter => false,
+        }
+    }
+}
+
+/// Errors returned by the event loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventError {
+    /// A subscription using `QueuePolicy::BlockEmitter` is full.
+    WouldBlock(SubscriptionId),
+    /// Queue depth must be at least one.
+    InvalidDepth,
+    /// The program has not been granted access to this kind of event.
+    NotPermitted(EventKind),
+    /// The program's manifest does not allow it to run in the background.
+    BackgroundNotAllowed,
+}
+
+// --- Event Loop ---
+
+pub struct EventLoop {
+    subscriptions: Vec<Subscription>,
+    next_id: SubscriptionId,
+}
+
+impl EventLoop {
+    pub const fn new() -> EventLoop {
+        EventLoop {
+            subscriptions: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Registers a handler for `kind`, which must be among the manifest's
+    /// allowed events, for a program allowed to run in the background. The
+    /// decision is recorded in the audit log.
+    pub fn subscribe(
+        &mut self,
+        kind: EventKind,
+        manifest: &'static Manifest,
+        handler: Handler,
+        priority: Priority,
+        policy: QueuePolicy,
+        depth: usize,
+    ) -> Result<SubscriptionId, EventError> {
+        let background = manifest.contexts.allows_background();
+        let permitted = manifest.allowed_events.contains(kind);
+        audit::record(
+            manifest.name,
+            format!("subscribe {}", kind.name()),
+            if background && permitted { Outcome::Allowed } else { Outcome::Denied },
+        );
+        if !background {
+            return Err(EventError::BackgroundNotAllowed);
+        }
+        if !permitted {
+            return Err(EventError::NotPermitted(kind));
+        }
+        if depth == 0 {
+            return Err(EventError::InvalidDepth);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscriptions.push(Subscription {
+            id,
+            kind,
+            manifest,
+            handler,
+            priority,
+            policy,
+            depth,
+            queue: VecDeque::with_capacity(depth),
+            dropped: 0,
+        });
+        Ok(id)
+    }
+
+    /// Removes a subscription along with its queued events.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) {
+        self.subscriptions.retain(|s| s.id != id);
+    }
+
+    /// Queues an event for every matching subscription.
+    ///
+    /// Blocking subscriptions are checked first so that a refused event is
+    /// not delivered to anyone, keeping delivery all-or-nothing.
+    pub fn emit(&mut self, event: Event) -> Result<(), EventError> {
+        if let Some(full) = self.subscriptions.iter().find(|s| {
+            s.kind == event.kind
+                && s.policy == QueuePolicy::BlockEmitter
+                && s.queue.len() >= s.depth
+        }) {
+            return Err(EventError::WouldBlock(full.id));
+        }
+
+        for subscription in self.subscriptions.iter_mut().filter(|s| s.kind == event.kind) {
+            subscription.push(event);
+        }
+        Ok(())
+    }
+
+    /// Takes the next pending event across all subscriptions, highest
+    /// priority first and oldest first within a priority class.
+    /// With `urgent_only` set, Low and Normal work is left queued.
+    fn next_pending(&mut self, urgent_only: bool) -> Option<(Handler, Event, Priority)> {
+        let subscription = self
+            .subscriptions
+            .iter_mut()
+            .filter(|s| match s.next_priority() {
+                Some(priority) => !urgent_only || priority.is_urgent(),
+                None => false,
+            })
+            .max_by_key(|s| (s.next_priority(), Reverse(s.queue.front().map(|e| e.timestamp))))?;
+        let priority = subscription.next_priority()?;
+        let event = subscription.queue.pop_front()?;
+        Some((subscription.handler, event, priority))
+    }
+
+    /// Turns accumulated drop counts into `events-dropped` events.
+    ///
+    /// Drops from `events-dropped` subscriptions are only counted, never
+    /// reported, so that overload cannot feed itself.
+    fn report_drops(&mut self, timestamp: Instant) {
+        let reports: Vec<(usize, SubscriptionId, u64)> = self
+            .subscriptions
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.dropped > 0 && s.kind != EventKind::EventsDropped)
+            .map(|(index, s)| (index, s.id, s.dropped))
+            .collect();
+        for (index, subscription, count) in reports {
+            let report = Event {
+                kind: EventKind::EventsDropped,
+                priority: EventKind::EventsDropped.default_priority(),
+                timestamp,
+                payload: EventPayload::Dropped { subscription, count },
+            };
+            // A refused report keeps its count and is retried on the next pass.
+            if self.emit(report).is_ok() {
+                self.subscriptions[index].dropped -= count;
+            }
+        }
+    }
+
+    /// Total number of events discarded and not yet reported.
+    pub fn pending_drops(&self) -> u64 {
+        self.subscriptions.iter().map(|s| s.dropped).sum()
+    }
+
+    pub fn pending_events(&self) -> usize {
+        self.subscriptions.iter().map(|s| s.queue.len()).sum()
+    }
+
+    /// Every handler as (subscription, program name), without allocating.
+    pub fn handlers(&self) -> impl Iterator<Item = (SubscriptionId, &'static str)> + '_ {
+        self.subscriptions.iter().map(|s| (s.id, s.manifest.name))
+    }
+
+    /// A summary of every subscription, in registration order.
+    pub fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        self.subscriptions
+            .iter()
+            .map(|s| SubscriptionInfo {
+                id: s.id,
+                kind: s.kind,
+                manifest: s.manifest,
+                priority: s.priority,
+                policy: s.policy,
+                depth: s.depth,
+                queued: s.queue.len(),
+                dropped: s.dropped,
+            })
+            .collect()
+    }
+
+    /// Every queued event with the subscription it is waiting in.
+    pub fn queued_events(&self) -> Vec<(SubscriptionId, Event)> {
+        self.subscriptions
+            .iter()
+            .flat_map(|s| s.queue.iter().map(move |event| (s.id, *event)))
+            .collect()
+    }
+}
+
+/// A snapshot of a subscription's state, for diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct SubscriptionInfo {
+    pub id: SubscriptionId,
+    pub kind: EventKind,
+    pub manifest: &'static Manifest,
+    pub priority: Priority,
+    pub policy: QueuePolicy,
+    pub depth: usize,
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+// --- Global Event Loop Instance ---
+pub static EVENT_LOOP: Mutex<EventLoop> = Mutex::new(EventLoop::new());
+
+/// Runs `f` on the global event loop with interrupts disabled, so IRQ
+/// handlers can emit events without deadlocking on the lock.
+pub fn with_event_loop<R>(f: impl FnOnce(&mut EventLoop) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut EVENT_LOOP.lock()))
+}
+
+/// Limits applied to background work, from the kernel policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventPolicy {
+    /// Longest a background handler may run, in seconds.
+    pub background_timeout_secs: u64,
+    /// Interval between `background-schedule` events, in seconds.
+    pub schedule_interval_secs: u64,
+}
+
+static POLICY: Mutex<EventPolicy> = Mutex::new(EventPolicy {
+    background_timeout_secs: 5 * 60,
+    schedule_interval_secs: 15 * 60,
+});
+
+pub fn set_policy(policy: EventPolicy) {
+    interrupts::without_interrupts(|| *POLICY.lock() = policy);
+}
+
+pub fn policy() -> EventPolicy {
+    interrupts::without_interrupts(|| *POLICY.lock())
+}
+
+/// Set while a foreground program is interactive.
+static FOREGROUND_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Gap kept between Low and Normal handlers while the foreground is
+/// interactive; the rest stay queued until it has passed.
+pub const INTERACTIVE_BACKGROUND_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Earliest time the next Low or Normal handler may run while the
+/// foreground is interactive.
+static NEXT_BACKGROUND: Mutex<Instant> = Mutex::new(Instant::ZERO);
+
+/// Marks whether a foreground program is currently interactive, throttling
+/// background handlers to protect UI latency.
+pub fn set_foreground_interactive(interactive: bool) {
+    FOREGROUND_INTERACTIVE.store(interactive, Ordering::Relaxed);
+}
+
+pub fn foreground_interactive() -> bool {
+    FOREGROUND_INTERACTIVE.load(Ordering::Relaxed)
+}
+
+/// Set while a handler runs, so `emit_blocking` knows it cannot wait.
+static IN_HANDLER: AtomicBool = AtomicBool::new(false);
+
+/// Current time on the monotonic clock.
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/// Emits an event of the given kind stamped with the current time.
+pub fn emit(kind: EventKind, payload: EventPayload) -> Result<(), EventError> {
+    emit_with_priority(kind, kind.default_priority(), payload)
+}
+
+/// Emits an event with an explicit priority class.
+pub fn emit_with_priority(
+    kind: EventKind,
+    priority: Priority,
+    payload: EventPayload,
+) -> Result<(), EventError> {
+    with_event_loop(|event_loop| {
+        event_loop.emit(Event {
+            kind,
+            priority,
+            timestamp: now(),
+            payload,
+        })
+    })
+}
+
+/// Emits an event, running handlers until blocking subscriptions have room.
+///
+/// From inside a handler this cannot wait, since the subscription it would
+/// wait on may be the one running, so a refusal is returned instead.
+pub fn emit_blocking(kind: EventKind, payload: EventPayload) -> Result<(), EventError> {
+    loop {
+        match emit(kind, payload) {
+            Ok(()) => return Ok(()),
+            Err(e @ EventError::WouldBlock(_)) => {
+                if IN_HANDLER.load(Ordering::SeqCst) {
+                    return Err(e);
+                }
+                if !dispatch_one() {
+                    core::hint::spin_loop();
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Runs the handler for the next pending event.
+/// The lock is released before the handler runs so handlers may emit events.
+/// Returns false when there was nothing to run.
+pub fn dispatch_one() -> bool {
+    run_next(false).is_some()
+}
+
+/// Runs one handler, timing it against the background budget. Handlers run
+/// to completion, so an overrun is reported rather than cut short.
+fn run_next(urgent_only: bool) -> Option<Priority> {
+    let (handler, event, priority) = with_event_loop(|event_loop| event_loop.next_pending(urgent_only))?;
+    let started = now();
+    let nested = IN_HANDLER.swap(true, Ordering::SeqCst);
+    handler(&event);
+    IN_HANDLER.store(nested, Ordering::SeqCst);
+    let elapsed = started.elapsed();
+    let budget = Duration::from_secs(policy().background_timeout_secs);
+    if !priority.is_urgent() && elapsed > budget {
+        log::warn!(
+            "Handler for {} ran {} ms, over the {} s background budget",
+            event.kind.name(),
+            elapsed.as_millis(),
+            budget.as_secs()
+        );
+    }
+    Some(priority)
+}
+
+/// Runs handlers until every queue is empty, reporting drops as they happen.
+///
+/// While the foreground is interactive, Low/Normal handlers run at most once
+/// per `INTERACTIVE_BACKGROUND_INTERVAL`; urgent handlers always run. Returns
+/// when held-back handlers may run, or None if nothing was held back.
+pub fn dispatch_pending() -> Option<Instant> {
+    loop {
+        with_event_loop(|event_loop| event_loop.report_drops(now()));
+        let interactive = foreground_interactive();
+        let next_background = interrupts::without_interrupts(|| *NEXT_BACKGROUND.lock());
+        let throttled = interactive && now() < next_background;
+        match run_next(throttled) {
+            Some(priority) if interactive && !priority.is_urgent() => {
+                let next = now() + INTERACTIVE_BACKGROUND_INTERVAL;
+                interrupts::without_interrupts(|| *NEXT_BACKGROUND.lock() = next);
+            }
+            Some(_) => {}
+            None if throttled && with_event_loop(|event_loop| event_loop.pending_events()) > 0 => {
+                return Some(next_background);
+            }
+            None => return None,
+        }
+    }
+}
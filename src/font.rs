@@ -0,0 +1,235 @@
+// Module for console bitmap fonts: PSF1/PSF2 parsing and the built-in default
+//
+// Glyph rows are stored PSF-style: each row is `bytes_per_row` bytes with the
+// leftmost pixel in the most significant bit of the first byte.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use font8x8::unicode::{BASIC_UNICODE, BLOCK_UNICODE, BOX_UNICODE, GREEK_UNICODE, LATIN_UNICODE, MISC_UNICODE};
+use font8x8::FontUnicode;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TAB: u8 = 0x02;
+const PSF1_MODE_SEQ: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQ: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQ: u8 = 0xFE;
+
+/// Code point drawn for characters the font cannot represent.
+const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
+
+/// Replacement glyph for the built-in font: a hollow box.
+const BUILTIN_REPLACEMENT: [u8; 8] = [0x00, 0x7E, 0x42, 0x42, 0x42, 0x42, 0x7E, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// The data does not start with a PSF1 or PSF2 magic number.
+    UnknownFormat,
+    /// The header or glyph data is shorter than the header claims.
+    Truncated,
+    /// The header describes glyphs of zero size.
+    InvalidHeader,
+}
+
+pub struct Font {
+    pub width: usize,
+    pub height: usize,
+    bytes_per_row: usize,
+    glyphs: Vec<u8>,
+    glyph_count: usize,
+    /// Maps code points to glyph indices. Fonts without a Unicode table map
+    /// the first 256 code points directly.
+    unicode: BTreeMap<char, usize>,
+}
+
+impl Font {
+    fn bytes_per_glyph(&self) -> usize {
+        self.bytes_per_row * self.height
+    }
+
+    /// Parses a PSF1 or PSF2 font, including its Unicode table if present.
+    pub fn parse_psf(data: &[u8]) -> Result<Font, FontError> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Font::parse_psf2(data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Font::parse_psf1(data)
+        } else {
+            Err(FontError::UnknownFormat)
+        }
+    }
+
+    fn parse_psf1(data: &[u8]) -> Result<Font, FontError> {
+        let mode = *data.get(2).ok_or(FontError::Truncated)?;
+        let height = *data.get(3).ok_or(FontError::Truncated)? as usize;
+        if height == 0 {
+            return Err(FontError::InvalidHeader);
+        }
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyph_end = 4 + glyph_count * height;
+        let glyphs = data.get(4..glyph_end).ok_or(FontError::Truncated)?;
+
+        let mut unicode = BTreeMap::new();
+        if mode & (PSF1_MODE_HAS_TAB | PSF1_MODE_SEQ) != 0 {
+            let table = &data[glyph_end..];
+            let mut glyph = 0;
+            let mut in_sequence = false;
+            for entry in table.chunks_exact(2) {
+                let value = u16::from_le_bytes([entry[0], entry[1]]);
+                match value {
+                    PSF1_SEPARATOR => {
+                        glyph += 1;
+                        in_sequence = false;
+                    }
+                    // Multi-codepoint sequences are not rendered; skip them.
+                    PSF1_START_SEQ => in_sequence = true,
+                    _ if !in_sequence => {
+                        if let Some(c) = char::from_u32(value as u32) {
+                            unicode.entry(c).or_insert(glyph);
+                        }
+                    }
+                    _ => {}
+                }
+                if glyph >= glyph_count {
+                    break;
+                }
+            }
+        } else {
+            map_identity(&mut unicode, glyph_count);
+        }
+
+        Ok(Font {
+            width: 8,
+            height,
+            bytes_per_row: 1,
+            glyphs: glyphs.to_vec(),
+            glyph_count,
+            unicode,
+        })
+    }
+
+    fn parse_psf2(data: &[u8]) -> Result<Font, FontError> {
+        let field = |index: usize| -> Result<u32, FontError> {
+            let start = index * 4;
+            let bytes = data.get(start..start + 4).ok_or(FontError::Truncated)?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        let header_size = field(2)? as usize;
+        let flags = field(3)?;
+        let glyph_count = field(4)? as usize;
+        let bytes_per_glyph = field(5)? as usize;
+        let height = field(6)? as usize;
+        let width = field(7)? as usize;
+        if width == 0 || height == 0 || glyph_count == 0 {
+            return Err(FontError::InvalidHeader);
+        }
+        let bytes_per_row = width.div_ceil(8);
+        if bytes_per_glyph != bytes_per_row * height {
+            return Err(FontError::InvalidHeader);
+        }
+
+        let glyph_end = header_size + glyph_count * bytes_per_glyph;
+        let glyphs = data.get(header_size..glyph_end).ok_or(FontError::Truncated)?;
+
+        let mut unicode = BTreeMap::new();
+        if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            let table = &data[glyph_end..];
+            for (glyph, entry) in table.split(|&b| b == PSF2_SEPARATOR).enumerate() {
+                if glyph >= glyph_count {
+                    break;
+                }
+                // Anything after a sequence marker describes multi-codepoint
+                // sequences, which are not rendered.
+                let singles = entry.split(|&b| b == PSF2_START_SEQ).next().unwrap_or(&[]);
+                if let Ok(text) = core::str::from_utf8(singles) {
+                    for c in text.chars() {
+                        unicode.entry(c).or_insert(glyph);
+                    }
+                }
+            }
+        } else {
+            map_identity(&mut unicode, glyph_count);
+        }
+
+        Ok(Font {
+            width,
+            height,
+            bytes_per_row,
+            glyphs: glyphs.to_vec(),
+            glyph_count,
+            unicode,
+        })
+    }
+
+    /// The 8x8 font compiled into the kernel, covering ASCII, Latin-1,
+    /// Greek, box-drawing and block elements.
+    pub fn builtin() -> Font {
+        let mut font = Font {
+            width: 8,
+            height: 8,
+            bytes_per_row: 1,
+            glyphs: Vec::new(),
+            glyph_count: 0,
+            unicode: BTreeMap::new(),
+        };
+
+        let sets: [&[FontUnicode]; 6] = [
+            &BASIC_UNICODE,
+            &LATIN_UNICODE,
+            &GREEK_UNICODE,
+            &BOX_UNICODE,
+            &BLOCK_UNICODE,
+            &MISC_UNICODE,
+        ];
+        for set in sets {
+            for glyph in set.iter().filter(|g| !g.char().is_control()) {
+                // font8x8 keeps the leftmost pixel in the lowest bit.
+                let rows = glyph.byte_array().map(u8::reverse_bits);
+                font.push_glyph(glyph.char(), &rows);
+            }
+        }
+        font.push_glyph(REPLACEMENT_CHARACTER, &BUILTIN_REPLACEMENT.map(u8::reverse_bits));
+        font
+    }
+
+    fn push_glyph(&mut self, c: char, rows: &[u8]) {
+        self.glyphs.extend_from_slice(rows);
+        self.unicode.entry(c).or_insert(self.glyph_count);
+        self.glyph_count += 1;
+    }
+
+    /// Looks up the glyph for a code point without any fallback.
+    pub fn lookup(&self, c: char) -> Option<&[u8]> {
+        let index = *self.unicode.get(&c)?;
+        let size = self.bytes_per_glyph();
+        self.glyphs.get(index * size..(index + 1) * size)
+    }
+
+    /// The glyph for a code point, falling back to U+FFFD, then `?`, only
+    /// when the font has no glyph for it.
+    pub fn glyph(&self, c: char) -> &[u8] {
+        self.lookup(c)
+            .or_else(|| self.lookup(REPLACEMENT_CHARACTER))
+            .or_else(|| self.lookup('?'))
+            .unwrap_or(&self.glyphs[..self.bytes_per_glyph()])
+    }
+
+    /// Whether the pixel at (x, y) of a glyph is set.
+    pub fn pixel(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        let byte = glyph[y * self.bytes_per_row + x / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+/// For fonts without a Unicode table the glyph index is the code point.
+fn map_identity(unicode: &mut BTreeMap<char, usize>, glyph_count: usize) {
+    for index in 0..glyph_count.min(256) {
+        if let Some(c) = char::from_u32(index as u32) {
+            unicode.insert(c, index);
+        }
+    }
+}
@@ -0,0 +1,839 @@
+// Module for the GDB remote serial protocol stub
+//
+// The stub owns a second UART (COM1 carries the kernel console) and takes
+// over the debug (#DB) and breakpoint (#BP) exceptions. When either fires it
+// talks to GDB with interrupts disabled until told to continue, so it works
+// on real hardware where no hypervisor gdbstub is available.
+//
+// GDB threads select the address space memory accesses go through: thread 1
+// is the kernel, and each registered handler appears as its own thread.
+//
+// A session never allocates: the trap may have stopped the heap allocator
+// with its lock held. Packets go through fixed buffers and the breakpoint
+// list is reserved when the stub starts.
+
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::VirtAddr;
+
+use crate::event_loop::{self, SubscriptionId};
+use crate::interrupts;
+use crate::serial::{SerialError, SerialPort};
+
+/// Baud rate of the debug UART.
+pub const GDB_BAUD_RATE: u32 = 115200;
+
+/// Largest packet we accept, advertised to GDB in `qSupported`.
+const PACKET_SIZE: usize = 0x1000;
+
+/// Breakpoints GDB may set at once.
+const MAX_BREAKPOINTS: usize = 64;
+
+const DEBUG_VECTOR: u64 = 1;
+const BREAKPOINT_VECTOR: u64 = 3;
+const INT3: u8 = 0xCC;
+const RFLAGS_TRAP: u64 = 1 << 8;
+const SIGTRAP: u8 = 5;
+
+/// GDB thread id of the kernel itself; handlers follow it.
+const KERNEL_THREAD: u64 = 1;
+
+// Page table entry flags used when translating addresses
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_HUGE: u64 = 1 << 7;
+const PTE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// --- Trap Entry ---
+
+/// Registers saved by the trap entry stubs, lowest address first, followed
+/// by the frame the CPU pushed.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// The x86-interrupt ABI does not expose general purpose registers, so the
+// debug exceptions enter through these stubs. Neither pushes an error code.
+// After the CPU frame, the vector and 15 registers the stack is 8 bytes off
+// 16-byte alignment, which the `sub rsp, 8` corrects for the call.
+global_asm!(
+    ".global optios_debug_entry",
+    "optios_debug_entry:",
+    "push 1",
+    "jmp optios_trap_common",
+    ".global optios_breakpoint_entry",
+    "optios_breakpoint_entry:",
+    "push 3",
+    "jmp optios_trap_common",
+    "optios_trap_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "sub rsp, 8",
+    "call optios_debug_trap",
+    "add rsp, 8",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 8",
+    "iretq",
+);
+
+extern "C" {
+    fn optios_debug_entry();
+    fn optios_breakpoint_entry();
+}
+
+/// Entry point for the #DB vector.
+pub fn debug_entry() -> VirtAddr {
+    VirtAddr::new(optios_debug_entry as *const () as u64)
+}
+
+/// Entry point for the #BP vector.
+pub fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::new(optios_breakpoint_entry as *const () as u64)
+}
+
+#[no_mangle]
+extern "C" fn optios_debug_trap(frame: &mut TrapFrame) {
+    let mut guard = STUB.lock();
+    let Some(stub) = guard.as_mut() else {
+        frame.rflags &= !RFLAGS_TRAP;
+        if frame.vector == BREAKPOINT_VECTOR {
+            log::warn!("Breakpoint at {:#x}", frame.rip - 1);
+        }
+        return;
+    };
+
+    if frame.vector == DEBUG_VECTOR {
+        frame.rflags &= !RFLAGS_TRAP;
+        // Finished stepping off a breakpoint on the way to `continue`.
+        if stub.stepping_over_breakpoint {
+            stub.stepping_over_breakpoint = false;
+            stub.insert_breakpoints();
+            return;
+        }
+    } else if stub.breakpoint_at(frame.rip - 1) {
+        // int3 leaves rip after the instruction; report the breakpoint address.
+        frame.rip -= 1;
+    }
+
+    stub.remove_breakpoints();
+    stub.session(frame);
+}
+
+// --- Memory Access ---
+
+/// Which address space memory reads and writes go through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Kernel,
+    Handler(SubscriptionId),
+}
+
+impl Target {
+    fn thread_id(&self) -> u64 {
+        match self {
+            Target::Kernel => KERNEL_THREAD,
+            Target::Handler(id) => KERNEL_THREAD + 1 + *id as u64,
+        }
+    }
+
+    fn from_thread_id(thread: u64) -> Option<Target> {
+        match thread {
+            // 0 means "any thread" and -1 "all threads".
+            0 | u64::MAX | KERNEL_THREAD => Some(Target::Kernel),
+            _ => {
+                let id = SubscriptionId::try_from(thread - KERNEL_THREAD - 1).ok()?;
+                handler_name(id).map(|_| Target::Handler(id))
+            }
+        }
+    }
+
+    /// Physical address of the top-level page table for the target.
+    ///
+    /// Handlers currently run in the kernel address space, so every target
+    /// resolves to the active root until snapshots get page tables of their own.
+    fn page_table_root(&self) -> u64 {
+        Cr3::read().0.start_address().as_u64()
+    }
+}
+
+/// The program name of a registered handler. None if there is no such
+/// handler, or the event loop was interrupted while locked, since waiting
+/// for it would deadlock.
+fn handler_name(id: SubscriptionId) -> Option<&'static str> {
+    let event_loop = event_loop::EVENT_LOOP.try_lock()?;
+    let name = event_loop.handlers().find(|(handler, _)| *handler == id);
+    name.map(|(_, name)| name)
+}
+
+/// Walks the 4-level page tables at `root` to find the physical address of
+/// `addr`. Firmware identity-maps physical memory, so tables are read directly.
+fn translate(root: u64, addr: u64) -> Option<u64> {
+    let mut table = root;
+    for level in (1..=4).rev() {
+        let index = (addr >> (12 + 9 * (level - 1))) & 0x1FF;
+        let entry = unsafe { ((table + index * 8) as *const u64).read_volatile() };
+        if entry & PTE_PRESENT == 0 {
+            return None;
+        }
+        let frame = entry & PTE_ADDRESS_MASK;
+        // 1 GiB and 2 MiB pages end the walk early.
+        if level > 1 && level < 4 && entry & PTE_HUGE != 0 {
+            let offset_mask = (1u64 << (12 + 9 * (level - 1))) - 1;
+            return Some((frame & !offset_mask) | (addr & offset_mask));
+        }
+        table = frame;
+    }
+    Some(table | (addr & 0xFFF))
+}
+
+fn read_memory(target: Target, addr: u64) -> Option<u8> {
+    let phys = translate(target.page_table_root(), addr)?;
+    Some(unsafe { (phys as *const u8).read_volatile() })
+}
+
+/// Writes memory, ignoring write protection so breakpoints can be placed in
+/// read-only code pages. Nothing is written unless every byte is mapped.
+fn write_memory(target: Target, addr: u64, data: &[u8]) -> Option<()> {
+    let root = target.page_table_root();
+    let physical = |i: usize| translate(root, addr.checked_add(i as u64)?);
+    if (0..data.len()).any(|i| physical(i).is_none()) {
+        return None;
+    }
+
+    let cr0 = Cr0::read();
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        for (i, byte) in data.iter().enumerate() {
+            if let Some(phys) = physical(i) {
+                (phys as *mut u8).write_volatile(*byte);
+            }
+        }
+        Cr0::write(cr0);
+    }
+    Some(())
+}
+
+// --- Registers ---
+
+/// Number of registers in GDB's amd64 general register set that we report:
+/// 16 GPRs, rip, eflags and the six segment selectors.
+const REGISTER_COUNT: usize = 24;
+
+/// Reads GDB register `index` as (value, size in bytes).
+fn read_register(frame: &TrapFrame, index: usize) -> Option<(u64, usize)> {
+    let value = match index {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => return Some((frame.rflags, 4)),
+        18 => return Some((frame.cs, 4)),
+        19 => return Some((frame.ss, 4)),
+        20 => return Some((DS::get_reg().0 as u64, 4)),
+        21 => return Some((ES::get_reg().0 as u64, 4)),
+        22 => return Some((FS::get_reg().0 as u64, 4)),
+        23 => return Some((GS::get_reg().0 as u64, 4)),
+        _ => return None,
+    };
+    Some((value, 8))
+}
+
+/// Writes GDB register `index`. Segment selectors are read-only.
+fn write_register(frame: &mut TrapFrame, index: usize, value: u64) -> Option<()> {
+    let slot = match index {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18..=23 => return Some(()),
+        _ => return None,
+    };
+    *slot = value;
+    Some(())
+}
+
+// --- Hex Encoding ---
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+/// Parses a thread id, where `-1` means all threads.
+fn parse_thread(text: &str) -> Option<u64> {
+    if text == "-1" {
+        return Some(u64::MAX);
+    }
+    parse_hex(text)
+}
+
+/// Decodes hex text into `out`, returning the bytes written. None if the
+/// text is malformed or does not fit.
+fn decode_hex<'a>(text: &str, out: &'a mut [u8]) -> Option<&'a [u8]> {
+    let bytes = text.as_bytes();
+    if !bytes.len().is_multiple_of(2) || bytes.len() / 2 > out.len() {
+        return None;
+    }
+    for (slot, pair) in out.iter_mut().zip(bytes.chunks_exact(2)) {
+        *slot = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(&out[..bytes.len() / 2])
+}
+
+fn encode_hex(out: &mut impl Write, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = write!(out, "{:02x}", byte);
+    }
+}
+
+/// Decodes a little-endian register value of up to 8 bytes.
+fn decode_register(text: &str) -> Option<u64> {
+    let mut buffer = [0u8; 8];
+    let bytes = decode_hex(text, &mut buffer)?;
+    Some(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64))
+}
+
+// --- Protocol ---
+
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+/// Packet text in a fixed buffer.
+struct PacketBuffer {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl PacketBuffer {
+    const fn new() -> PacketBuffer {
+        PacketBuffer {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Appends a byte. Returns false if the buffer is full.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == PACKET_SIZE {
+            return false;
+        }
+        self.data[self.len] = byte;
+        self.len += 1;
+        true
+    }
+}
+
+impl Write for PacketBuffer {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let end = self.len + text.len();
+        if end > PACKET_SIZE {
+            return Err(fmt::Error);
+        }
+        self.data[self.len..end].copy_from_slice(text.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Packets received from GDB. Only used under the `STUB` lock.
+static INPUT: Mutex<PacketBuffer> = Mutex::new(PacketBuffer::new());
+
+/// Bytes decoded from an `M` packet. Only used under the `STUB` lock.
+static MEMORY: Mutex<[u8; PACKET_SIZE / 2]> = Mutex::new([0; PACKET_SIZE / 2]);
+
+/// What the session does after answering a packet.
+enum Reply {
+    /// Send the packet in `Stub::output`.
+    Packet,
+    /// Leave the stub and resume execution.
+    Resume,
+}
+
+struct Stub {
+    port: SerialPort,
+    breakpoints: Vec<Breakpoint>,
+    target: Target,
+    /// Set while single-stepping over a breakpoint at the resume address.
+    stepping_over_breakpoint: bool,
+    /// The last packet sent, resent when GDB answers `-`.
+    output: PacketBuffer,
+}
+
+static STUB: Mutex<Option<Stub>> = Mutex::new(None);
+
+impl Stub {
+    fn breakpoint_at(&self, addr: u64) -> bool {
+        self.breakpoints.iter().any(|b| b.addr == addr)
+    }
+
+    /// Writes `int3` over every breakpoint. They are only in place while the
+    /// target runs, so memory reads from GDB see the original code.
+    fn insert_breakpoints(&mut self) {
+        for breakpoint in &self.breakpoints {
+            let _ = write_memory(Target::Kernel, breakpoint.addr, &[INT3]);
+        }
+    }
+
+    fn remove_breakpoints(&mut self) {
+        for breakpoint in &self.breakpoints {
+            let _ = write_memory(Target::Kernel, breakpoint.addr, &[breakpoint.original]);
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.port.try_read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Sets the next packet to send. One too long for a packet becomes an
+    /// error reply.
+    fn reply(&mut self, args: fmt::Arguments) -> Reply {
+        self.output.clear();
+        if self.output.write_fmt(args).is_err() {
+            self.output.clear();
+            let _ = self.output.write_str("E0c");
+        }
+        Reply::Packet
+    }
+
+    fn ok(&mut self) -> Reply {
+        self.reply(format_args!("OK"))
+    }
+
+    fn error(&mut self, code: u8) -> Reply {
+        self.reply(format_args!("E{:02x}", code))
+    }
+
+    fn unsupported(&mut self) -> Reply {
+        self.reply(format_args!(""))
+    }
+
+    /// Sends the packet in `output`, framed with its checksum.
+    fn send_packet(&mut self) {
+        let checksum = self.output.as_bytes().iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        self.port.write_byte(b'$');
+        for i in 0..self.output.len {
+            let byte = self.output.data[i];
+            self.port.write_byte(byte);
+        }
+        self.port.write_byte(b'#');
+        for digit in [checksum >> 4, checksum & 0xF] {
+            let digit = char::from_digit(digit as u32, 16).unwrap_or('0');
+            self.port.write_byte(digit as u8);
+        }
+    }
+
+    /// Waits for the next well-formed packet into `input`, acknowledging it.
+    /// Acks for our own packets are skipped; a `-` resends the last one.
+    fn receive_packet(&mut self, input: &mut PacketBuffer) {
+        loop {
+            match self.read_byte() {
+                b'$' => {}
+                b'-' => {
+                    self.send_packet();
+                    continue;
+                }
+                _ => continue,
+            }
+
+            input.clear();
+            let mut checksum = 0u8;
+            let mut oversized = false;
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    // A new packet start means the last one was cut short.
+                    b'$' => {
+                        input.clear();
+                        checksum = 0;
+                        oversized = false;
+                    }
+                    byte => {
+                        // Discard the rest of an oversized packet up to its `#`.
+                        if input.push(byte) {
+                            checksum = checksum.wrapping_add(byte);
+                        } else {
+                            oversized = true;
+                        }
+                    }
+                }
+            }
+            let high = hex_value(self.read_byte());
+            let low = hex_value(self.read_byte());
+            match (high, low) {
+                (Some(high), Some(low)) if !oversized && high << 4 | low == checksum => {
+                    self.port.write_byte(b'+');
+                    return;
+                }
+                _ => self.port.write_byte(b'-'),
+            }
+        }
+    }
+
+    fn stop_reply(&mut self) -> Reply {
+        let thread = self.target.thread_id();
+        self.reply(format_args!("T{:02x}thread:{:x};", SIGTRAP, thread))
+    }
+
+    /// Talks to GDB until it resumes the target.
+    fn session(&mut self, frame: &mut TrapFrame) {
+        let mut input = INPUT.lock();
+        self.stop_reply();
+        self.send_packet();
+        loop {
+            self.receive_packet(&mut input);
+            let reply = match core::str::from_utf8(input.as_bytes()) {
+                Ok(text) => self.handle(text, frame),
+                Err(_) => self.unsupported(),
+            };
+            match reply {
+                Reply::Packet => self.send_packet(),
+                Reply::Resume => return,
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str, frame: &mut TrapFrame) -> Reply {
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        match command {
+            "?" => self.stop_reply(),
+            "g" => {
+                self.output.clear();
+                for index in 0..REGISTER_COUNT {
+                    if let Some((value, size)) = read_register(frame, index) {
+                        encode_hex(&mut self.output, &value.to_le_bytes()[..size]);
+                    }
+                }
+                Reply::Packet
+            }
+            "G" => self.write_registers(args, frame),
+            "p" => match parse_hex(args).and_then(|index| read_register(frame, index as usize)) {
+                Some((value, size)) => {
+                    self.output.clear();
+                    encode_hex(&mut self.output, &value.to_le_bytes()[..size]);
+                    Reply::Packet
+                }
+                None => self.error(0x01),
+            },
+            "P" => {
+                let parsed = args
+                    .split_once('=')
+                    .and_then(|(index, value)| Some((parse_hex(index)?, decode_register(value)?)));
+                match parsed.and_then(|(index, value)| write_register(frame, index as usize, value)) {
+                    Some(()) => self.ok(),
+                    None => self.error(0x01),
+                }
+            }
+            "m" => {
+                let parsed = args
+                    .split_once(',')
+                    .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+                match parsed {
+                    Some((_, len)) if len > PACKET_SIZE as u64 / 2 => self.error(0x01),
+                    Some((addr, len)) => {
+                        self.output.clear();
+                        for i in 0..len {
+                            let Some(byte) = addr.checked_add(i).and_then(|addr| read_memory(self.target, addr)) else {
+                                return self.error(0x0e);
+                            };
+                            encode_hex(&mut self.output, &[byte]);
+                        }
+                        Reply::Packet
+                    }
+                    None => self.error(0x01),
+                }
+            }
+            "M" => {
+                let mut memory = MEMORY.lock();
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = range.split_once(',')?;
+                    let data = decode_hex(data, &mut memory[..])?;
+                    (parse_hex(len)? as usize == data.len()).then_some((parse_hex(addr)?, data))
+                });
+                match parsed {
+                    Some((addr, data)) => match write_memory(self.target, addr, data) {
+                        Some(()) => self.ok(),
+                        None => self.error(0x0e),
+                    },
+                    None => self.error(0x01),
+                }
+            }
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    frame.rip = addr;
+                }
+                if command == "s" {
+                    frame.rflags |= RFLAGS_TRAP;
+                } else if self.breakpoint_at(frame.rip) {
+                    // Step off the breakpoint first, then put it back.
+                    frame.rflags |= RFLAGS_TRAP;
+                    self.stepping_over_breakpoint = true;
+                } else {
+                    self.insert_breakpoints();
+                }
+                Reply::Resume
+            }
+            "Z" | "z" => self.update_breakpoint(command == "Z", args),
+            "H" => {
+                // `Hg` selects the thread for memory and registers, `Hc` for
+                // stepping; both map onto the address space target.
+                match args.get(1..).and_then(parse_thread).and_then(Target::from_thread_id) {
+                    Some(target) => {
+                        self.target = target;
+                        self.ok()
+                    }
+                    None => self.error(0x01),
+                }
+            }
+            "T" => match parse_thread(args).and_then(Target::from_thread_id) {
+                Some(_) => self.ok(),
+                None => self.error(0x01),
+            },
+            "D" => {
+                self.breakpoints.clear();
+                self.ok();
+                self.send_packet();
+                Reply::Resume
+            }
+            "k" => {
+                self.breakpoints.clear();
+                Reply::Resume
+            }
+            "q" => self.query(args),
+            _ => self.unsupported(),
+        }
+    }
+
+    fn write_registers(&mut self, hex: &str, frame: &mut TrapFrame) -> Reply {
+        let mut offset = 0;
+        for index in 0..REGISTER_COUNT {
+            let Some((_, size)) = read_register(frame, index) else {
+                break;
+            };
+            let Some(text) = hex.get(offset..offset + size * 2) else {
+                break;
+            };
+            match decode_register(text) {
+                Some(value) => {
+                    let _ = write_register(frame, index, value);
+                }
+                None => return self.error(0x01),
+            }
+            offset += size * 2;
+        }
+        self.ok()
+    }
+
+    fn update_breakpoint(&mut self, insert: bool, args: &str) -> Reply {
+        let mut fields = args.split(',');
+        // Only software breakpoints (type 0) are supported.
+        if fields.next() != Some("0") {
+            return self.unsupported();
+        }
+        let Some(addr) = fields.next().and_then(parse_hex) else {
+            return self.error(0x01);
+        };
+
+        if !insert {
+            self.breakpoints.retain(|b| b.addr != addr);
+            return self.ok();
+        }
+        if self.breakpoint_at(addr) {
+            return self.ok();
+        }
+        // The list was reserved up front; growing it would allocate.
+        if self.breakpoints.len() == self.breakpoints.capacity() {
+            return self.error(0x0c);
+        }
+        match read_memory(Target::Kernel, addr) {
+            Some(original) => {
+                self.breakpoints.push(Breakpoint { addr, original });
+                self.ok()
+            }
+            None => self.error(0x0e),
+        }
+    }
+
+    fn query(&mut self, args: &str) -> Reply {
+        if args.starts_with("Supported") {
+            return self.reply(format_args!("PacketSize={:x}", PACKET_SIZE));
+        }
+        if args == "Attached" {
+            return self.reply(format_args!("1"));
+        }
+        if args == "C" {
+            let thread = self.target.thread_id();
+            return self.reply(format_args!("QC{:x}", thread));
+        }
+        if args == "fThreadInfo" {
+            self.output.clear();
+            let _ = write!(self.output, "m{:x}", KERNEL_THREAD);
+            // Handlers are left out if the event loop was interrupted while
+            // locked, since waiting for it would deadlock.
+            let mut written = Ok(());
+            if let Some(event_loop) = event_loop::EVENT_LOOP.try_lock() {
+                for (id, _) in event_loop.handlers() {
+                    written = written.and_then(|()| write!(self.output, ",{:x}", Target::Handler(id).thread_id()));
+                }
+            }
+            return match written {
+                Ok(()) => Reply::Packet,
+                Err(_) => self.error(0x0c),
+            };
+        }
+        if args == "sThreadInfo" {
+            return self.reply(format_args!("l"));
+        }
+        if let Some(thread) = args.strip_prefix("ThreadExtraInfo,") {
+            self.output.clear();
+            let _ = match parse_thread(thread).and_then(Target::from_thread_id) {
+                Some(Target::Kernel) => write!(HexWriter(&mut self.output), "kernel"),
+                Some(Target::Handler(id)) => {
+                    let name = handler_name(id).unwrap_or("?");
+                    write!(HexWriter(&mut self.output), "handler #{} {}", id, name)
+                }
+                None => return self.error(0x01),
+            };
+            return Reply::Packet;
+        }
+        self.unsupported()
+    }
+}
+
+/// Writes text hex-encoded, as `qThreadExtraInfo` replies need.
+struct HexWriter<'a>(&'a mut PacketBuffer);
+
+impl Write for HexWriter<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for byte in text.bytes() {
+            write!(self.0, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// --- Public API ---
+
+/// Claims the UART at `base` for the stub and stops in the debugger so GDB
+/// can attach. Requires the kernel IDT, see `interrupts::init`.
+pub fn init(base: u16) -> Result<(), SerialError> {
+    if !interrupts::is_active() {
+        return Err(SerialError::InterruptsInactive);
+    }
+    let mut port = SerialPort::new(base, GDB_BAUD_RATE);
+    port.init()?;
+    *STUB.lock() = Some(Stub {
+        port,
+        breakpoints: Vec::with_capacity(MAX_BREAKPOINTS),
+        target: Target::Kernel,
+        stepping_over_breakpoint: false,
+        output: PacketBuffer::new(),
+    });
+    log::info!("GDB stub waiting on serial port {:#x}", base);
+    breakpoint();
+    Ok(())
+}
+
+/// Stops in the debugger, or logs a warning when no stub is attached.
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
@@ -1 +1,1416 @@
-// Placeholder for hardware interaction logic (ports, devices) 
\ No newline at end of file
+// Module for platform hardware: ACPI tables, the APIC interrupt controllers
+// and the monotonic clock
+//
+// Firmware identity-maps physical memory, so ACPI tables and the APIC MMIO
+// registers are accessed at their physical addresses. Tables are validated
+// and parsed into typed structures once, by `init_acpi`. The monotonic clock
+// is calibrated from them by `init_clock`.
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::time::Duration;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+
+use crate::interrupts::{self, IRQ_COUNT, PIC_1_OFFSET};
+
+// --- ACPI Tables ---
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Bytes covered by the ACPI 1.0 RSDP checksum.
+const RSDP_V1_LENGTH: usize = 20;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+
+/// Errors returned while discovering the ACPI tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The RSDP signature is wrong.
+    InvalidRsdp,
+    /// A table's bytes do not sum to zero.
+    BadChecksum([u8; 4]),
+    /// A table is shorter than its fixed fields.
+    Truncated([u8; 4]),
+}
+
+/// The Root System Description Pointer, as found in the UEFI configuration table.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+ fields
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header shared by every system description table.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const SDT_HEADER_SIZE: usize = core::mem::size_of::<SdtHeader>();
+
+unsafe fn read_header(address: u64) -> SdtHeader {
+    ptr::read_unaligned(address as *const SdtHeader)
+}
+
+/// Whether `length` bytes at `address` sum to zero, as ACPI requires.
+fn checksum_valid(address: u64, length: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Bounds-checked little-endian reads from a table. Fields past the end of
+/// the table, as in older table revisions, read as zero.
+#[derive(Debug, Clone, Copy)]
+struct TableBytes {
+    address: u64,
+    length: usize,
+}
+
+impl TableBytes {
+    fn read<T: Copy + Default>(&self, offset: usize) -> T {
+        if offset + core::mem::size_of::<T>() > self.length {
+            return T::default();
+        }
+        unsafe { ptr::read_unaligned((self.address + offset as u64) as *const T) }
+    }
+
+    fn u8(&self, offset: usize) -> u8 {
+        self.read(offset)
+    }
+
+    fn u16(&self, offset: usize) -> u16 {
+        self.read(offset)
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        self.read(offset)
+    }
+
+    fn u64(&self, offset: usize) -> u64 {
+        self.read(offset)
+    }
+
+    fn has(&self, offset: usize, size: usize) -> bool {
+        offset + size <= self.length
+    }
+}
+
+/// Reads the header of the table at `address` and validates its checksum.
+fn open_table(address: u64) -> Result<(SdtHeader, TableBytes), AcpiError> {
+    let header = unsafe { read_header(address) };
+    let length = header.length as usize;
+    if length < SDT_HEADER_SIZE {
+        return Err(AcpiError::Truncated(header.signature));
+    }
+    if !checksum_valid(address, length) {
+        return Err(AcpiError::BadChecksum(header.signature));
+    }
+    Ok((header, TableBytes { address, length }))
+}
+
+// Generic Address Structure address spaces
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+/// An ACPI Generic Address Structure: a register in memory or I/O space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    const SIZE: usize = 12;
+
+    fn parse(table: &TableBytes, offset: usize) -> GenericAddress {
+        GenericAddress {
+            address_space: table.u8(offset),
+            bit_width: table.u8(offset + 1),
+            bit_offset: table.u8(offset + 2),
+            access_size: table.u8(offset + 3),
+            address: table.u64(offset + 4),
+        }
+    }
+
+    /// A legacy 32-bit I/O port block from the ACPI 1.0 fields.
+    fn io_port(port: u32, length: u8) -> GenericAddress {
+        GenericAddress {
+            address_space: ADDRESS_SPACE_IO,
+            bit_width: length * 8,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+
+    /// Access width in bytes, from the access size or else the bit width.
+    fn width(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 1 << (self.access_size - 1),
+            _ => match self.bit_width {
+                0..=8 => 1,
+                9..=16 => 2,
+                17..=32 => 4,
+                _ => 8,
+            },
+        }
+    }
+
+    /// Reads the register. Unsupported address spaces read as zero.
+    ///
+    /// # Safety
+    /// The register must be safe to read, with no side effects the caller
+    /// is not prepared for.
+    pub unsafe fn read(&self) -> u64 {
+        match (self.address_space, self.width()) {
+            (ADDRESS_SPACE_IO, 1) => Port::<u8>::new(self.address as u16).read() as u64,
+            (ADDRESS_SPACE_IO, 2) => Port::<u16>::new(self.address as u16).read() as u64,
+            (ADDRESS_SPACE_IO, _) => Port::<u32>::new(self.address as u16).read() as u64,
+            (ADDRESS_SPACE_MEMORY, 1) => ptr::read_volatile(self.address as *const u8) as u64,
+            (ADDRESS_SPACE_MEMORY, 2) => ptr::read_volatile(self.address as *const u16) as u64,
+            (ADDRESS_SPACE_MEMORY, 4) => ptr::read_volatile(self.address as *const u32) as u64,
+            (ADDRESS_SPACE_MEMORY, _) => ptr::read_volatile(self.address as *const u64),
+            _ => 0,
+        }
+    }
+
+    /// Writes the register, truncating `value` to its width. Returns false
+    /// for address spaces that are not supported.
+    ///
+    /// # Safety
+    /// Writing the register must not break memory safety, e.g. by resetting
+    /// a device the kernel is using.
+    pub unsafe fn write(&self, value: u64) -> bool {
+        match (self.address_space, self.width()) {
+            (ADDRESS_SPACE_IO, 1) => Port::<u8>::new(self.address as u16).write(value as u8),
+            (ADDRESS_SPACE_IO, 2) => Port::<u16>::new(self.address as u16).write(value as u16),
+            (ADDRESS_SPACE_IO, _) => Port::<u32>::new(self.address as u16).write(value as u32),
+            (ADDRESS_SPACE_MEMORY, 1) => ptr::write_volatile(self.address as *mut u8, value as u8),
+            (ADDRESS_SPACE_MEMORY, 2) => ptr::write_volatile(self.address as *mut u16, value as u16),
+            (ADDRESS_SPACE_MEMORY, 4) => ptr::write_volatile(self.address as *mut u32, value as u32),
+            (ADDRESS_SPACE_MEMORY, _) => ptr::write_volatile(self.address as *mut u64, value),
+            _ => return false,
+        }
+        true
+    }
+}
+
+// --- FADT ---
+
+/// FADT flag: the reset register is supported.
+const FADT_RESET_REG_SUPPORTED: u32 = 1 << 10;
+/// FADT boot flag: an 8042 keyboard controller is present.
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+/// FADT boot flag: the CMOS RTC is absent.
+pub const BOOT_ARCH_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+/// The Fixed ACPI Description Table: power management registers and
+/// platform flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub revision: u8,
+    /// Physical address of the DSDT.
+    pub dsdt: u64,
+    pub sci_interrupt: u16,
+    /// Port written with `acpi_enable` to hand power management to the OS.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event: GenericAddress,
+    pub pm1b_event: GenericAddress,
+    pub pm1a_control: GenericAddress,
+    pub pm1b_control: GenericAddress,
+    pub pm_timer: GenericAddress,
+    /// CMOS index of the RTC century register, or 0 if there is none.
+    pub century: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    /// IA-PC boot architecture flags (`BOOT_ARCH_*`).
+    pub boot_architecture: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    fn parse(table: &TableBytes) -> Result<Fadt, AcpiError> {
+        // Everything up to the PM timer length exists since ACPI 1.0.
+        if !table.has(0, 92) {
+            return Err(AcpiError::Truncated(*FADT_SIGNATURE));
+        }
+
+        // The 64-bit X_ fields take precedence when present.
+        let extended = |offset: usize, legacy: GenericAddress| {
+            let address = GenericAddress::parse(table, offset);
+            if table.has(offset, GenericAddress::SIZE) && address.is_present() {
+                address
+            } else {
+                legacy
+            }
+        };
+        let x_dsdt = table.u64(140);
+        let flags = table.u32(112);
+        let reset_register = GenericAddress::parse(table, 116);
+
+        Ok(Fadt {
+            revision: table.u8(8),
+            dsdt: if x_dsdt != 0 { x_dsdt } else { table.u32(40) as u64 },
+            sci_interrupt: table.u16(46),
+            smi_command: table.u32(48),
+            acpi_enable: table.u8(52),
+            acpi_disable: table.u8(53),
+            pm1a_event: extended(148, GenericAddress::io_port(table.u32(56), table.u8(88))),
+            pm1b_event: extended(160, GenericAddress::io_port(table.u32(60), table.u8(88))),
+            pm1a_control: extended(172, GenericAddress::io_port(table.u32(64), table.u8(89))),
+            pm1b_control: extended(184, GenericAddress::io_port(table.u32(68), table.u8(89))),
+            pm_timer: extended(208, GenericAddress::io_port(table.u32(76), table.u8(91))),
+            century: table.u8(108),
+            day_alarm: table.u8(106),
+            month_alarm: table.u8(107),
+            boot_architecture: table.u16(109),
+            flags,
+            reset_register: (flags & FADT_RESET_REG_SUPPORTED != 0 && reset_register.is_present())
+                .then_some(reset_register),
+            reset_value: table.u8(128),
+        })
+    }
+}
+
+// --- HPET ---
+
+/// The HPET description table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetInfo {
+    pub hardware_revision: u8,
+    pub comparator_count: u8,
+    pub counter_is_64bit: bool,
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub base_address: GenericAddress,
+    pub number: u8,
+    /// Minimum clock ticks for periodic mode without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+impl HpetInfo {
+    fn parse(table: &TableBytes) -> Result<HpetInfo, AcpiError> {
+        if !table.has(0, 56) {
+            return Err(AcpiError::Truncated(*HPET_SIGNATURE));
+        }
+        let block_id = table.u32(36);
+        Ok(HpetInfo {
+            hardware_revision: block_id as u8,
+            comparator_count: ((block_id >> 8) & 0x1F) as u8 + 1,
+            counter_is_64bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            pci_vendor_id: (block_id >> 16) as u16,
+            base_address: GenericAddress::parse(table, 40),
+            number: table.u8(52),
+            minimum_tick: table.u16(53),
+        })
+    }
+}
+
+// --- MCFG ---
+
+/// A PCI Express enhanced configuration space region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciConfigRegion {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// The PCI Express memory-mapped configuration table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mcfg {
+    pub regions: Vec<PciConfigRegion>,
+}
+
+impl Mcfg {
+    const ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+    const ENTRY_SIZE: usize = 16;
+
+    fn parse(table: &TableBytes) -> Mcfg {
+        let count = table.length.saturating_sub(Mcfg::ENTRIES_OFFSET) / Mcfg::ENTRY_SIZE;
+        let regions = (0..count)
+            .map(|i| {
+                let offset = Mcfg::ENTRIES_OFFSET + i * Mcfg::ENTRY_SIZE;
+                PciConfigRegion {
+                    base_address: table.u64(offset),
+                    segment: table.u16(offset + 8),
+                    start_bus: table.u8(offset + 10),
+                    end_bus: table.u8(offset + 11),
+                }
+            })
+            .collect();
+        Mcfg { regions }
+    }
+}
+
+// --- MADT ---
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// MADT flag: the system also has 8259 PICs that must be disabled.
+const MADT_PCAT_COMPAT: u32 = 0x01;
+/// Local APIC flag: the processor can be used.
+const LOCAL_APIC_ENABLED: u32 = 0x01;
+
+/// Interrupt polarity and trigger mode from MPS INTI flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptFlags {
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl InterruptFlags {
+    /// ISA interrupts default to active high, edge triggered.
+    const ISA: InterruptFlags = InterruptFlags {
+        active_low: false,
+        level_triggered: false,
+    };
+
+    /// Decodes MPS INTI flags, where 0 means "conforms to the bus" (ISA).
+    fn from_mps(flags: u16) -> InterruptFlags {
+        InterruptFlags {
+            active_low: flags & 0x3 == 0x3,
+            level_triggered: (flags >> 2) & 0x3 == 0x3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicInfo {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// Remaps an ISA IRQ to a different global system interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: InterruptFlags,
+}
+
+/// Which local APIC LINT pin carries NMIs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// 0xFF means every processor.
+    pub processor_id: u8,
+    pub lint: u8,
+    pub flags: InterruptFlags,
+}
+
+/// The Multiple APIC Description Table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub has_legacy_pics: bool,
+    pub local_apics: Vec<LocalApicInfo>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    fn parse(table: &TableBytes) -> Result<Madt, AcpiError> {
+        let body = SDT_HEADER_SIZE;
+        if !table.has(body, 8) {
+            return Err(AcpiError::Truncated(*MADT_SIGNATURE));
+        }
+        let mut madt = Madt {
+            local_apic_address: table.u32(body) as u64,
+            has_legacy_pics: table.u32(body + 4) & MADT_PCAT_COMPAT != 0,
+            ..Madt::default()
+        };
+
+        let mut offset = body + 8;
+        while table.has(offset, 2) {
+            let entry_type = table.u8(offset);
+            let length = table.u8(offset + 1) as usize;
+            if length < 2 || !table.has(offset, length) {
+                break;
+            }
+            match entry_type {
+                MADT_LOCAL_APIC => madt.local_apics.push(LocalApicInfo {
+                    processor_id: table.u8(offset + 2),
+                    apic_id: table.u8(offset + 3),
+                    enabled: table.u32(offset + 4) & LOCAL_APIC_ENABLED != 0,
+                }),
+                MADT_IO_APIC => madt.io_apics.push(IoApicInfo {
+                    id: table.u8(offset + 2),
+                    address: table.u32(offset + 4),
+                    gsi_base: table.u32(offset + 8),
+                }),
+                MADT_INTERRUPT_OVERRIDE => madt.overrides.push(InterruptOverride {
+                    irq: table.u8(offset + 3),
+                    gsi: table.u32(offset + 4),
+                    flags: InterruptFlags::from_mps(table.u16(offset + 8)),
+                }),
+                MADT_LOCAL_APIC_NMI => madt.nmis.push(LocalApicNmi {
+                    processor_id: table.u8(offset + 2),
+                    flags: InterruptFlags::from_mps(table.u16(offset + 3)),
+                    lint: table.u8(offset + 5),
+                }),
+                MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    madt.local_apic_address = table.u64(offset + 4);
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        Ok(madt)
+    }
+
+    /// The global system interrupt and flags an ISA IRQ is wired to, or
+    /// None if it has no input: its identity GSI is taken by another IRQ's
+    /// override, as GSI 2 usually is by the PIT.
+    pub fn isa_route(&self, irq: u8) -> Option<(u32, InterruptFlags)> {
+        if let Some(o) = self.overrides.iter().find(|o| o.irq == irq) {
+            return Some((o.gsi, o.flags));
+        }
+        if self.overrides.iter().any(|o| o.gsi == irq as u32) {
+            return None;
+        }
+        Some((irq as u32, InterruptFlags::ISA))
+    }
+}
+
+// --- Table Discovery ---
+
+/// A table listed in the RSDT/XSDT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableEntry {
+    pub signature: [u8; 4],
+    pub address: u64,
+    /// False if the checksum failed; such tables are not parsed.
+    pub valid: bool,
+}
+
+/// Every ACPI table the kernel uses, parsed once at boot.
+#[derive(Debug, Clone)]
+pub struct AcpiTables {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub tables: Vec<TableEntry>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<HpetInfo>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl AcpiTables {
+    /// Validates the RSDP and root table, then parses the tables we know.
+    /// A table that fails validation is logged and left out.
+    pub fn parse(rsdp_address: u64) -> Result<AcpiTables, AcpiError> {
+        let rsdp = unsafe { ptr::read_unaligned(rsdp_address as *const Rsdp) };
+        if rsdp.signature != *RSDP_SIGNATURE {
+            return Err(AcpiError::InvalidRsdp);
+        }
+        if !checksum_valid(rsdp_address, RSDP_V1_LENGTH) {
+            return Err(AcpiError::BadChecksum(*b"RSDP"));
+        }
+        let use_xsdt = rsdp.revision >= 2 && rsdp.xsdt_address != 0;
+        if use_xsdt && !checksum_valid(rsdp_address, rsdp.length as usize) {
+            return Err(AcpiError::BadChecksum(*b"RSDP"));
+        }
+
+        let (root, entry_size) = if use_xsdt {
+            (rsdp.xsdt_address, 8)
+        } else {
+            (rsdp.rsdt_address as u64, 4)
+        };
+        let (_, root_table) = open_table(root)?;
+
+        let count = (root_table.length - SDT_HEADER_SIZE) / entry_size;
+        let mut acpi = AcpiTables {
+            revision: rsdp.revision,
+            oem_id: rsdp.oem_id,
+            tables: Vec::with_capacity(count),
+            madt: None,
+            fadt: None,
+            hpet: None,
+            mcfg: None,
+        };
+
+        for i in 0..count {
+            let offset = SDT_HEADER_SIZE + i * entry_size;
+            let address = if entry_size == 8 {
+                root_table.u64(offset)
+            } else {
+                root_table.u32(offset) as u64
+            };
+            if address == 0 {
+                continue;
+            }
+            match open_table(address) {
+                Ok((header, table)) => {
+                    acpi.tables.push(TableEntry {
+                        signature: header.signature,
+                        address,
+                        valid: true,
+                    });
+                    if let Err(e) = acpi.parse_table(&header, &table) {
+                        log::warn!("ACPI: ignoring {}: {:?}", signature_str(&header.signature), e);
+                    }
+                }
+                Err(e) => {
+                    let signature = unsafe { read_header(address) }.signature;
+                    log::warn!("ACPI: ignoring {}: {:?}", signature_str(&signature), e);
+                    acpi.tables.push(TableEntry {
+                        signature,
+                        address,
+                        valid: false,
+                    });
+                }
+            }
+        }
+        Ok(acpi)
+    }
+
+    fn parse_table(&mut self, header: &SdtHeader, table: &TableBytes) -> Result<(), AcpiError> {
+        match &header.signature {
+            MADT_SIGNATURE if self.madt.is_none() => self.madt = Some(Madt::parse(table)?),
+            FADT_SIGNATURE if self.fadt.is_none() => self.fadt = Some(Fadt::parse(table)?),
+            HPET_SIGNATURE if self.hpet.is_none() => self.hpet = Some(HpetInfo::parse(table)?),
+            MCFG_SIGNATURE if self.mcfg.is_none() => self.mcfg = Some(Mcfg::parse(table)),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A table signature for display; signatures are ASCII.
+pub fn signature_str(signature: &[u8; 4]) -> &str {
+    core::str::from_utf8(signature).unwrap_or("????")
+}
+
+static ACPI_TABLES: Once<AcpiTables> = Once::new();
+
+/// Parses the ACPI tables from the RSDP and keeps them for the drivers
+/// that need them. Only reads memory, so it is safe during boot services.
+pub fn init_acpi(rsdp_address: u64) -> Result<(), AcpiError> {
+    let tables = AcpiTables::parse(rsdp_address)?;
+    let signatures: Vec<&str> = tables.tables.iter().map(|t| signature_str(&t.signature)).collect();
+    log::info!(
+        "ACPI revision {} ({}): {}",
+        tables.revision,
+        core::str::from_utf8(&tables.oem_id).unwrap_or("?").trim_end(),
+        signatures.join(" ")
+    );
+    ACPI_TABLES.call_once(|| tables);
+    Ok(())
+}
+
+/// The tables parsed by `init_acpi`, if it succeeded.
+pub fn acpi_tables() -> Option<&'static AcpiTables> {
+    ACPI_TABLES.r#try()
+}
+
+// --- Sleep States ---
+
+// AML opcodes used to find the `\_Sx` packages
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_WORD_PREFIX: u8 = 0x0B;
+const AML_DWORD_PREFIX: u8 = 0x0C;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+/// ACPI sleep states. S0 is the working state and S5 is soft-off. Only S5
+/// is entered so far; the others can already be looked up in the DSDT.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepState {
+    S1 = 1,
+    S2,
+    S3,
+    S4,
+    S5,
+}
+
+/// The `SLP_TYPa`/`SLP_TYPb` values for a sleep state, written to the PM1a
+/// and PM1b control registers to enter it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Reads one integer package element, returning it and its encoded length.
+fn aml_integer(bytes: &[u8]) -> Option<(u8, usize)> {
+    match *bytes.first()? {
+        AML_ZERO_OP => Some((0, 1)),
+        AML_ONE_OP => Some((1, 1)),
+        AML_BYTE_PREFIX => Some((*bytes.get(1)?, 2)),
+        AML_WORD_PREFIX => Some((*bytes.get(1)?, 3)),
+        AML_DWORD_PREFIX => Some((*bytes.get(1)?, 5)),
+        _ => None,
+    }
+}
+
+/// Finds the `Name(\_Sx, Package() {a, b, ...})` object for a sleep state in
+/// the DSDT. This is a byte scan rather than an AML interpreter, which is
+/// enough for the static packages firmware uses for sleep types.
+pub fn sleep_type(state: SleepState) -> Option<SleepType> {
+    let dsdt = acpi_tables()?.fadt?.dsdt;
+    let (_, table) = open_table(dsdt).ok()?;
+    let aml = unsafe { core::slice::from_raw_parts(dsdt as *const u8, table.length) };
+    let name = [b'_', b'S', b'0' + state as u8, b'_'];
+
+    let body = &aml[SDT_HEADER_SIZE..];
+    let mut start = 0;
+    while let Some(found) = body[start..].windows(4).position(|w| w == name) {
+        let index = start + found;
+        start = index + 1;
+
+        // Must be a NameOp, optionally through the root prefix.
+        let named = match index {
+            0 => false,
+            1 => body[0] == AML_NAME_OP,
+            _ => body[index - 1] == AML_NAME_OP || (body[index - 1] == AML_ROOT_CHAR && body[index - 2] == AML_NAME_OP),
+        };
+        let rest = &body[index + 4..];
+        if !named || rest.first() != Some(&AML_PACKAGE_OP) {
+            continue;
+        }
+        // PkgLength encodes its own extra byte count in the top two bits,
+        // and is followed by the element count.
+        let lead = *rest.get(1)?;
+        let mut elements = &rest[(2 + (lead >> 6) as usize + 1).min(rest.len())..];
+        let (a, length) = aml_integer(elements)?;
+        elements = &elements[length..];
+        let b = aml_integer(elements).map_or(0, |(b, _)| b);
+        return Some(SleepType { a, b });
+    }
+    None
+}
+
+// --- Monotonic Clock ---
+
+/// How long the TSC is measured against the reference timer at boot.
+const CALIBRATION_MS: u64 = 50;
+
+// HPET registers
+const HPET_CAPABILITIES: u64 = 0x000;
+const HPET_CONFIG: u64 = 0x010;
+const HPET_MAIN_COUNTER: u64 = 0x0F0;
+const HPET_CONFIG_ENABLE: u64 = 1 << 0;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+// 8254 PIT channel 2, gated through the keyboard controller's port B
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CHANNEL_2_ONE_SHOT: u8 = 0xB0; // channel 2, lobyte/hibyte, mode 0
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE_2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT_2: u8 = 1 << 5;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// What the clock counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The invariant TSC, which ticks at a constant rate in every power state.
+    Tsc,
+    /// The HPET main counter, used when the TSC is not invariant.
+    Hpet,
+}
+
+/// What the TSC rate was measured against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Calibration {
+    /// Read from CPUID leaf 0x15, no measurement needed.
+    Cpuid,
+    Hpet,
+    Pit,
+}
+
+/// A point on the monotonic clock, in nanoseconds since the CPU came out of
+/// reset. Reads as zero until `init_clock` has run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const ZERO: Instant = Instant(0);
+
+    pub fn now() -> Instant {
+        match CLOCK.r#try() {
+            Some(clock) => Instant(clock.nanos(clock.read())),
+            None => Instant::ZERO,
+        }
+    }
+
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to this instant, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos()).ok().and_then(|nanos| self.0.checked_add(nanos)).map(Instant)
+    }
+
+    /// Adds `duration`, clamping at the end of time.
+    pub fn saturating_add(&self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Instant(u64::MAX))
+    }
+}
+
+impl core::ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.saturating_add(duration)
+    }
+}
+
+impl core::ops::Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Seconds with microseconds, as shown in logs.
+impl core::fmt::Display for Instant {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let micros = self.0 / 1000;
+        f.pad(&alloc::format!("{}.{:06}", micros / 1_000_000, micros % 1_000_000))
+    }
+}
+
+/// The calibrated clock. Counter ticks are converted to nanoseconds as
+/// `ticks * mult >> 32`, which avoids a division on every read.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    pub source: ClockSource,
+    pub calibration: Calibration,
+    /// TSC rate, even when the HPET is the clock source.
+    pub tsc_hz: u64,
+    /// Counter value at `Instant::ZERO`.
+    base: u64,
+    mult: u64,
+    hpet_base: u64,
+}
+
+impl Clock {
+    fn read(&self) -> u64 {
+        match self.source {
+            ClockSource::Tsc => read_tsc(),
+            ClockSource::Hpet => unsafe { hpet_read(self.hpet_base, HPET_MAIN_COUNTER) },
+        }
+    }
+
+    fn nanos(&self, counter: u64) -> u64 {
+        ((counter.wrapping_sub(self.base) as u128 * self.mult as u128) >> 32) as u64
+    }
+
+    /// The TSC value at which `instant` is reached, for TSC-deadline timers.
+    pub fn tsc_at(&self, instant: Instant) -> u64 {
+        let tsc_base = match self.source {
+            ClockSource::Tsc => self.base,
+            // Map through the current reading of both counters.
+            ClockSource::Hpet => read_tsc().wrapping_sub(ticks(Instant::now().as_nanos(), self.tsc_hz)),
+        };
+        tsc_base.wrapping_add(ticks(instant.as_nanos(), self.tsc_hz))
+    }
+}
+
+/// Converts nanoseconds to ticks of a counter running at `hz`.
+fn ticks(nanos: u64, hz: u64) -> u64 {
+    (nanos as u128 * hz as u128 / NANOS_PER_SECOND as u128) as u64
+}
+
+/// `mult` such that `ticks * mult >> 32` is nanoseconds.
+fn nanos_mult(hz: u64) -> u64 {
+    ((NANOS_PER_SECOND as u128) << 32).div_ceil(hz as u128) as u64
+}
+
+static CLOCK: Once<Clock> = Once::new();
+
+fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+fn invariant_tsc() -> bool {
+    const CPUID_EDX_INVARIANT_TSC: u32 = 1 << 8;
+    let max_extended = core::arch::x86_64::__cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0007 && core::arch::x86_64::__cpuid(0x8000_0007).edx & CPUID_EDX_INVARIANT_TSC != 0
+}
+
+/// The TSC rate from CPUID leaf 0x15, when the crystal frequency is given.
+fn cpuid_tsc_hz() -> Option<u64> {
+    if core::arch::x86_64::__cpuid(0).eax < 0x15 {
+        return None;
+    }
+    let leaf = core::arch::x86_64::__cpuid(0x15);
+    let (denominator, numerator, crystal_hz) = (leaf.eax as u64, leaf.ebx as u64, leaf.ecx as u64);
+    (denominator != 0 && numerator != 0 && crystal_hz != 0).then(|| crystal_hz * numerator / denominator)
+}
+
+unsafe fn hpet_read(base: u64, register: u64) -> u64 {
+    ptr::read_volatile((base + register) as *const u64)
+}
+
+unsafe fn hpet_write(base: u64, register: u64, value: u64) {
+    ptr::write_volatile((base + register) as *mut u64, value)
+}
+
+/// Starts the HPET main counter and returns its rate in Hz.
+fn hpet_enable(base: u64) -> u64 {
+    unsafe {
+        let period_fs = hpet_read(base, HPET_CAPABILITIES) >> 32;
+        let config = hpet_read(base, HPET_CONFIG);
+        hpet_write(base, HPET_CONFIG, config | HPET_CONFIG_ENABLE);
+        FEMTOSECONDS_PER_SECOND / period_fs.max(1)
+    }
+}
+
+/// Counts TSC ticks while the HPET advances by `CALIBRATION_MS`.
+fn calibrate_against_hpet(base: u64, hpet_hz: u64) -> u64 {
+    let wait = hpet_hz * CALIBRATION_MS / 1000;
+    unsafe {
+        let start = hpet_read(base, HPET_MAIN_COUNTER);
+        let tsc_start = read_tsc();
+        // Mask to 32 bits so a 32-bit counter wrapping does not end the wait early.
+        while hpet_read(base, HPET_MAIN_COUNTER).wrapping_sub(start) & 0xFFFF_FFFF < wait {
+            core::hint::spin_loop();
+        }
+        let elapsed = hpet_read(base, HPET_MAIN_COUNTER).wrapping_sub(start) & 0xFFFF_FFFF;
+        (read_tsc() - tsc_start) * hpet_hz / elapsed
+    }
+}
+
+/// Counts TSC ticks during a `CALIBRATION_MS` one-shot on PIT channel 2.
+fn calibrate_against_pit() -> u64 {
+    let count = PIT_FREQUENCY_HZ * CALIBRATION_MS / 1000;
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+    unsafe {
+        // Gate channel 2 on with the speaker disconnected.
+        let saved = port_b.read();
+        port_b.write((saved & !PORT_B_SPEAKER) & !PORT_B_GATE_2);
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL_2_ONE_SHOT);
+        Port::<u8>::new(PIT_CHANNEL_2).write(count as u8);
+        Port::<u8>::new(PIT_CHANNEL_2).write((count >> 8) as u8);
+        port_b.write((saved & !PORT_B_SPEAKER) | PORT_B_GATE_2);
+
+        let tsc_start = read_tsc();
+        while port_b.read() & PORT_B_OUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+        let tsc_end = read_tsc();
+        port_b.write(saved);
+        (tsc_end - tsc_start) * 1000 / CALIBRATION_MS
+    }
+}
+
+/// Calibrates the monotonic clock. The TSC rate comes from CPUID when the
+/// CPU reports it, otherwise it is measured against the HPET if ACPI lists
+/// one, or the PIT. Call after `init_acpi`.
+pub fn init_clock() -> &'static Clock {
+    CLOCK.call_once(|| {
+        let hpet = acpi_tables()
+            .and_then(|tables| tables.hpet)
+            .filter(|hpet| hpet.base_address.address_space == ADDRESS_SPACE_MEMORY)
+            .map(|hpet| (hpet, hpet_enable(hpet.base_address.address)));
+
+        let (tsc_hz, calibration) = cpu_interrupts::without_interrupts(|| match (cpuid_tsc_hz(), hpet) {
+            (Some(hz), _) => (hz, Calibration::Cpuid),
+            (None, Some((info, hpet_hz))) => {
+                (calibrate_against_hpet(info.base_address.address, hpet_hz), Calibration::Hpet)
+            }
+            (None, None) => (calibrate_against_pit(), Calibration::Pit),
+        });
+
+        // A 32-bit HPET counter wraps within minutes, so only a 64-bit one
+        // can stand in for a TSC that drifts with power states.
+        let clock = match hpet {
+            Some((info, hpet_hz)) if !invariant_tsc() && info.counter_is_64bit => {
+                let base = info.base_address.address;
+                Clock {
+                    source: ClockSource::Hpet,
+                    calibration,
+                    tsc_hz,
+                    base: unsafe { hpet_read(base, HPET_MAIN_COUNTER) },
+                    mult: nanos_mult(hpet_hz),
+                    hpet_base: base,
+                }
+            }
+            _ => {
+                if !invariant_tsc() {
+                    log::warn!("TSC is not invariant and no 64-bit HPET; timestamps may drift");
+                }
+                Clock {
+                    source: ClockSource::Tsc,
+                    calibration,
+                    tsc_hz,
+                    base: 0,
+                    mult: nanos_mult(tsc_hz),
+                    hpet_base: 0,
+                }
+            }
+        };
+        log::info!(
+            "Clock: {:?}, TSC {}.{:03} MHz ({:?} calibration)",
+            clock.source,
+            tsc_hz / 1_000_000,
+            tsc_hz / 1000 % 1000,
+            clock.calibration
+        );
+        clock
+    })
+}
+
+/// The calibrated clock, once `init_clock` has run.
+pub fn clock() -> Option<&'static Clock> {
+    CLOCK.r#try()
+}
+
+// --- Local APIC ---
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC register offsets
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TASK_PRIORITY: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xB0;
+const LAPIC_SPURIOUS: u64 = 0xF0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_LVT_LINT0: u64 = 0x350;
+const LAPIC_LVT_LINT1: u64 = 0x360;
+const LAPIC_LVT_ERROR: u64 = 0x370;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 0x100;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+/// Vector for spurious local APIC interrupts, which need no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Local APIC MMIO base, kept outside the lock so EOIs can be sent from
+/// any interrupt handler. Zero until the APIC is initialized.
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn lapic_read(base: u64, register: u64) -> u32 {
+    ptr::read_volatile((base + register) as *const u32)
+}
+
+unsafe fn lapic_write(base: u64, register: u64, value: u32) {
+    ptr::write_volatile((base + register) as *mut u32, value);
+}
+
+fn apic_supported() -> bool {
+    const CPUID_EDX_APIC: u32 = 1 << 9;
+    core::arch::x86_64::__cpuid(1).edx & CPUID_EDX_APIC != 0
+}
+
+/// Whether the firmware handed over the local APIC in x2APIC mode, where its
+/// registers are MSRs and the MMIO window is gone.
+fn x2apic_enabled() -> bool {
+    let value = unsafe { Msr::new(IA32_APIC_BASE_MSR).read() };
+    value & APIC_BASE_X2APIC != 0
+}
+
+fn init_local_apic(madt: &Madt) -> u8 {
+    let base = madt.local_apic_address;
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE_MSR);
+        let value = msr.read();
+        msr.write((value & 0xFFF) | base | APIC_BASE_ENABLE);
+
+        lapic_write(base, LAPIC_TASK_PRIORITY, 0);
+        lapic_write(base, LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic_write(base, LAPIC_LVT_LINT0, LVT_MASKED);
+        lapic_write(base, LAPIC_LVT_LINT1, LVT_MASKED);
+        lapic_write(base, LAPIC_LVT_ERROR, LVT_MASKED);
+        lapic_write(base, LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    let apic_id = (unsafe { lapic_read(base, LAPIC_ID) } >> 24) as u8;
+    let processor_id = madt
+        .local_apics
+        .iter()
+        .find(|l| l.apic_id == apic_id)
+        .map(|l| l.processor_id);
+
+    for nmi in madt
+        .nmis
+        .iter()
+        .filter(|n| n.processor_id == 0xFF || Some(n.processor_id) == processor_id)
+    {
+        let mut lvt = LVT_DELIVERY_NMI;
+        if nmi.flags.active_low {
+            lvt |= LVT_ACTIVE_LOW;
+        }
+        if nmi.flags.level_triggered {
+            lvt |= LVT_LEVEL_TRIGGERED;
+        }
+        let register = if nmi.lint == 0 { LAPIC_LVT_LINT0 } else { LAPIC_LVT_LINT1 };
+        unsafe { lapic_write(base, register, lvt) };
+    }
+
+    LOCAL_APIC_BASE.store(base, Ordering::SeqCst);
+    apic_id
+}
+
+/// Signals end of interrupt to the local APIC.
+pub fn local_apic_eoi() {
+    let base = LOCAL_APIC_BASE.load(Ordering::Relaxed);
+    if base != 0 {
+        unsafe { lapic_write(base, LAPIC_EOI, 0) };
+    }
+}
+
+// --- Local APIC Timer ---
+
+const IA32_TSC_DEADLINE_MSR: u32 = 0x6E0;
+
+const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3E0;
+
+const LVT_TIMER_ONE_SHOT: u32 = 0b00 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+/// Divide configuration value for dividing the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long the one-shot timer is measured against the clock.
+const APIC_TIMER_CALIBRATION_MS: u64 = 10;
+
+/// Vector the local APIC timer interrupts on. Its only job is to wake the
+/// CPU from `hlt`; expired timers run in the kernel loop.
+pub const TIMER_VECTOR: u8 = 0xF0;
+
+/// How the local APIC timer is programmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicTimerMode {
+    /// The timer fires when the TSC reaches a value written to an MSR.
+    TscDeadline,
+    /// A one-shot countdown at a calibrated rate, for CPUs without
+    /// TSC-deadline mode.
+    OneShot { ticks_per_second: u64 },
+}
+
+static APIC_TIMER: Once<ApicTimerMode> = Once::new();
+
+fn tsc_deadline_supported() -> bool {
+    const CPUID_ECX_TSC_DEADLINE: u32 = 1 << 24;
+    core::arch::x86_64::__cpuid(1).ecx & CPUID_ECX_TSC_DEADLINE != 0
+}
+
+/// Counts one-shot timer ticks over `APIC_TIMER_CALIBRATION_MS`.
+fn calibrate_apic_timer(base: u64) -> u64 {
+    let wait = Duration::from_millis(APIC_TIMER_CALIBRATION_MS);
+    unsafe {
+        lapic_write(base, LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(base, LAPIC_LVT_TIMER, LVT_MASKED | LVT_TIMER_ONE_SHOT);
+        let start = Instant::now();
+        lapic_write(base, LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+        while start.elapsed() < wait {
+            core::hint::spin_loop();
+        }
+        let remaining = lapic_read(base, LAPIC_TIMER_CURRENT_COUNT);
+        lapic_write(base, LAPIC_TIMER_INITIAL_COUNT, 0);
+        (u32::MAX - remaining) as u64 * 1000 / APIC_TIMER_CALIBRATION_MS
+    }
+}
+
+/// Sets up the local APIC timer for one-shot deadlines, in TSC-deadline
+/// mode when the CPU supports it. Call after `init_apic` and `init_clock`.
+pub fn init_apic_timer() -> Result<ApicTimerMode, HardwareError> {
+    let base = LOCAL_APIC_BASE.load(Ordering::SeqCst);
+    if base == 0 {
+        return Err(HardwareError::ApicInactive);
+    }
+    let clock = clock().ok_or(HardwareError::ClockUncalibrated)?;
+    let mode = *APIC_TIMER.call_once(|| {
+        cpu_interrupts::without_interrupts(|| {
+            if tsc_deadline_supported() && clock.source == ClockSource::Tsc {
+                unsafe { lapic_write(base, LAPIC_LVT_TIMER, TIMER_VECTOR as u32 | LVT_TIMER_TSC_DEADLINE) };
+                ApicTimerMode::TscDeadline
+            } else {
+                let ticks_per_second = calibrate_apic_timer(base);
+                unsafe { lapic_write(base, LAPIC_LVT_TIMER, TIMER_VECTOR as u32 | LVT_TIMER_ONE_SHOT) };
+                ApicTimerMode::OneShot { ticks_per_second }
+            }
+        })
+    });
+    log::info!("Local APIC timer: {:?}", mode);
+    Ok(mode)
+}
+
+/// Whether `init_apic_timer` has run, so deadlines will raise an interrupt.
+pub fn apic_timer_active() -> bool {
+    APIC_TIMER.r#try().is_some()
+}
+
+/// Arms the local APIC timer to interrupt at `deadline`, replacing any
+/// earlier setting, or disarms it with `None`. A deadline that has passed
+/// fires immediately. Does nothing before `init_apic_timer`.
+pub fn set_timer_deadline(deadline: Option<Instant>) {
+    let (Some(mode), Some(clock)) = (APIC_TIMER.r#try(), clock()) else {
+        return;
+    };
+    let base = LOCAL_APIC_BASE.load(Ordering::Relaxed);
+    match (*mode, deadline) {
+        (ApicTimerMode::TscDeadline, deadline) => unsafe {
+            // Writing zero disarms; a past TSC value fires at once.
+            let tsc = deadline.map_or(0, |deadline| clock.tsc_at(deadline).max(1));
+            Msr::new(IA32_TSC_DEADLINE_MSR).write(tsc);
+        },
+        (ApicTimerMode::OneShot { .. }, None) => unsafe {
+            lapic_write(base, LAPIC_TIMER_INITIAL_COUNT, 0);
+        },
+        (ApicTimerMode::OneShot { ticks_per_second }, Some(deadline)) => {
+            // Deadlines past the counter's range wake early and are re-armed.
+            let remaining = deadline.duration_since(Instant::now());
+            let count = ticks(remaining.as_nanos().min(u64::MAX as u128) as u64, ticks_per_second);
+            unsafe { lapic_write(base, LAPIC_TIMER_INITIAL_COUNT, count.clamp(1, u32::MAX as u64) as u32) };
+        }
+    }
+}
+
+// --- I/O APIC ---
+
+const IOAPIC_REGISTER_SELECT: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECT_MASKED: u32 = 1 << 16;
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    fn new(info: &IoApicInfo) -> IoApic {
+        let mut io_apic = IoApic {
+            base: info.address as u64,
+            gsi_base: info.gsi_base,
+            redirection_entries: 0,
+        };
+        io_apic.redirection_entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        io_apic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_REGISTER_SELECT) as *mut u32, register);
+            ptr::read_volatile((self.base + IOAPIC_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_REGISTER_SELECT) as *mut u32, register);
+            ptr::write_volatile((self.base + IOAPIC_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    fn entry_register(&self, gsi: u32) -> u32 {
+        IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base)
+    }
+
+    /// Routes `gsi` to `vector` on the local APIC `destination`, masked.
+    fn route(&self, gsi: u32, vector: u8, flags: InterruptFlags, destination: u8) {
+        let mut low = vector as u32 | REDIRECT_MASKED;
+        if flags.active_low {
+            low |= REDIRECT_ACTIVE_LOW;
+        }
+        if flags.level_triggered {
+            low |= REDIRECT_LEVEL_TRIGGERED;
+        }
+        let register = self.entry_register(gsi);
+        self.write(register + 1, (destination as u32) << 24);
+        self.write(register, low);
+    }
+
+    fn set_masked(&self, gsi: u32, masked: bool) {
+        let register = self.entry_register(gsi);
+        let low = self.read(register);
+        self.write(register, if masked { low | REDIRECT_MASKED } else { low & !REDIRECT_MASKED });
+    }
+
+    fn mask_all(&self) {
+        for entry in 0..self.redirection_entries {
+            self.set_masked(self.gsi_base + entry, true);
+        }
+    }
+}
+
+// --- Interrupt Routing ---
+
+/// Errors returned while bringing up platform hardware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HardwareError {
+    /// The CPU has no local APIC.
+    ApicUnsupported,
+    /// The local APIC is in x2APIC mode, which is not supported.
+    X2ApicEnabled,
+    /// A required ACPI table is missing, or `init_acpi` has not run.
+    TableNotFound(&'static str),
+    /// The MADT lists no I/O APIC.
+    NoIoApic,
+    /// The local APIC has not been set up by `init_apic`.
+    ApicInactive,
+    /// The monotonic clock has not been set up by `init_clock`.
+    ClockUncalibrated,
+}
+
+struct ApicState {
+    io_apics: Vec<IoApic>,
+    /// ISA IRQ to global system interrupt, after overrides. None for an IRQ
+    /// whose input was claimed by another.
+    isa_gsi: [Option<u32>; IRQ_COUNT],
+}
+
+static APIC: Mutex<Option<ApicState>> = Mutex::new(None);
+
+/// Replaces the 8259 PICs with the local APIC and I/O APICs described by
+/// the MADT. ISA IRQs keep their vectors (`PIC_1_OFFSET + irq`) and are
+/// routed to this CPU masked; `interrupts::register_irq` unmasks them.
+/// Call after `init_acpi` and `interrupts::init`.
+pub fn init_apic() -> Result<(), HardwareError> {
+    if !apic_supported() {
+        return Err(HardwareError::ApicUnsupported);
+    }
+    // Leaving x2APIC mode means disabling the APIC first, which firmware may
+    // have locked; stay on the PICs instead.
+    if x2apic_enabled() {
+        return Err(HardwareError::X2ApicEnabled);
+    }
+    let madt = acpi_tables()
+        .and_then(|tables| tables.madt.as_ref())
+        .ok_or(HardwareError::TableNotFound("APIC"))?;
+    if madt.io_apics.is_empty() {
+        return Err(HardwareError::NoIoApic);
+    }
+
+    cpu_interrupts::without_interrupts(|| {
+        if madt.has_legacy_pics {
+            interrupts::disable_pics();
+        }
+        let bsp_id = init_local_apic(madt);
+
+        let io_apics: Vec<IoApic> = madt.io_apics.iter().map(IoApic::new).collect();
+        for io_apic in &io_apics {
+            io_apic.mask_all();
+        }
+
+        let mut isa_gsi = [None; IRQ_COUNT];
+        for irq in 0..IRQ_COUNT as u8 {
+            let Some((gsi, flags)) = madt.isa_route(irq) else {
+                continue;
+            };
+            isa_gsi[irq as usize] = Some(gsi);
+            if let Some(io_apic) = io_apics.iter().find(|a| a.handles(gsi)) {
+                io_apic.route(gsi, PIC_1_OFFSET + irq, flags, bsp_id);
+            }
+        }
+
+        log::info!(
+            "APIC: local APIC {} at {:#x}, {} I/O APIC(s), {} override(s)",
+            bsp_id,
+            madt.local_apic_address,
+            io_apics.len(),
+            madt.overrides.len()
+        );
+        *APIC.lock() = Some(ApicState { io_apics, isa_gsi });
+    });
+    interrupts::use_apic();
+    Ok(())
+}
+
+/// Masks or unmasks an ISA IRQ at its I/O APIC. Returns false if the IRQ
+/// has no I/O APIC input, e.g. because an override took it, or the APIC is
+/// not in use.
+pub fn set_isa_irq_masked(irq: u8, masked: bool) -> bool {
+    let apic = APIC.lock();
+    let Some(state) = apic.as_ref() else {
+        return false;
+    };
+    let Some(&Some(gsi)) = state.isa_gsi.get(irq as usize) else {
+        return false;
+    };
+    match state.io_apics.iter().find(|a| a.handles(gsi)) {
+        Some(io_apic) => {
+            io_apic.set_masked(gsi, masked);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,160 @@
+// Module for the kernel heap
+//
+// The heap is a block of pages taken from the firmware before boot services
+// exit, so it stays ours afterwards. Free space is kept in a list of blocks
+// ordered by address; allocation takes the first block that fits and freed
+// blocks are merged with their neighbors.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr;
+use spin::Mutex;
+use uefi::table::boot::{AllocateType, BootServices, MemoryType, PAGE_SIZE};
+use x86_64::instructions::interrupts;
+
+/// Heap size asked of the firmware; halved until an allocation succeeds.
+const HEAP_SIZE: usize = 16 * 1024 * 1024;
+const MIN_HEAP_SIZE: usize = 1024 * 1024;
+
+/// Every block is a multiple of this, so a free one can hold its header.
+const BLOCK_ALIGN: usize = 16;
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    /// First free block, lowest address first.
+    head: *mut FreeBlock,
+    size: usize,
+    used: usize,
+}
+
+// Safety: the free list is only reached through the `HEAP` lock.
+unsafe impl Send for Heap {}
+
+impl Heap {
+    const fn empty() -> Heap {
+        Heap {
+            head: ptr::null_mut(),
+            size: 0,
+            used: 0,
+        }
+    }
+
+    fn block_size(layout: &Layout) -> usize {
+        layout.size().max(size_of::<FreeBlock>()).next_multiple_of(BLOCK_ALIGN)
+    }
+
+    /// Adds `[start, start + size)` to the free list, merging it with the
+    /// blocks either side.
+    unsafe fn free(&mut self, start: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = Heap::block_size(&layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut block = self.head;
+        while !block.is_null() {
+            let start = block as usize;
+            let end = start + (*block).size;
+            let aligned = start.next_multiple_of(align);
+            if aligned + size <= end {
+                let next = (*block).next;
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                // Return the unused space either side to the list.
+                if aligned > start {
+                    self.free(start, aligned - start);
+                }
+                if aligned + size < end {
+                    self.free(aligned + size, end - aligned - size);
+                }
+                self.used += size;
+                return aligned as *mut u8;
+            }
+            prev = block;
+            block = (*block).next;
+        }
+        ptr::null_mut()
+    }
+}
+
+pub struct KernelAllocator;
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap::empty());
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| HEAP.lock().allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = Heap::block_size(&layout);
+        interrupts::without_interrupts(|| {
+            let mut heap = HEAP.lock();
+            heap.used -= size;
+            heap.free(ptr as usize, size);
+        });
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+/// Takes the heap's pages from the firmware. Must be called first thing,
+/// before anything allocates.
+pub fn init(bt: &BootServices) -> uefi::Result<()> {
+    let mut size = HEAP_SIZE;
+    loop {
+        let pages = size / PAGE_SIZE;
+        match bt.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages) {
+            Ok(start) => {
+                interrupts::without_interrupts(|| {
+                    let mut heap = HEAP.lock();
+                    heap.size = size;
+                    // Safety: the pages are ours and unused.
+                    unsafe { heap.free(start as usize, size) };
+                });
+                return Ok(());
+            }
+            Err(e) if size <= MIN_HEAP_SIZE => return Err(e),
+            Err(_) => size /= 2,
+        }
+    }
+}
+
+/// Heap size and bytes in use.
+pub fn usage() -> (usize, usize) {
+    interrupts::without_interrupts(|| {
+        let heap = HEAP.lock();
+        (heap.size, heap.used)
+    })
+}
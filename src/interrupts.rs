@@ -0,0 +1,268 @@
+// Module for the interrupt descriptor table and hardware IRQ dispatch
+//
+// ISA IRQs 0-15 use vectors 32-47. They are delivered through the 8259 PICs
+// until `hardware::init_apic` switches to the I/O APIC, after which routing
+// and EOIs go through the APIC. Drivers register a handler per IRQ line; the
+// line is masked until a handler is registered.
+//
+// The firmware owns the IDT while boot services are active, so `init` must
+// only be called once the kernel has taken over the machine.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::{gdb, hardware, loader};
+
+/// First vector used for ISA IRQs, just past the CPU exceptions.
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// Number of ISA IRQ lines.
+pub const IRQ_COUNT: usize = 16;
+
+// ISA IRQ lines used by the kernel
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_COM2: u8 = 3;
+pub const IRQ_COM1: u8 = 4;
+pub const IRQ_RTC: u8 = 8;
+
+// 8259 PIC ports and commands
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_2_DATA: u16 = 0xA1;
+const PIC_INIT: u8 = 0x11; // ICW1: initialize, ICW4 follows
+const PIC_MODE_8086: u8 = 0x01;
+const PIC_EOI: u8 = 0x20;
+/// IRQ line on the primary PIC the secondary PIC cascades through.
+const PIC_CASCADE_IRQ: u8 = 2;
+
+/// An IRQ handler. Runs with interrupts disabled and must not block.
+pub type IrqHandler = fn();
+
+/// Errors returned when registering IRQ handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ number is not an ISA IRQ line.
+    InvalidIrq(u8),
+    /// Another driver already owns the line.
+    AlreadyRegistered(u8),
+}
+
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// Set once the kernel IDT is loaded and the PICs are remapped.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Set once IRQs are routed through the I/O APIC instead of the PICs.
+static APIC_MODE: AtomicBool = AtomicBool::new(false);
+
+// --- IDT ---
+
+macro_rules! irq_stubs {
+    ($($name:ident => $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+
+        fn set_irq_entries(idt: &mut InterruptDescriptorTable) {
+            $( idt[(PIC_1_OFFSET + $irq) as usize].set_handler_fn($name); )*
+        }
+    };
+}
+
+irq_stubs! {
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3,
+    irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11,
+    irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15,
+}
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        // Debug exceptions go to the GDB stub, which needs every register.
+        unsafe {
+            idt.debug.set_handler_addr(gdb::debug_entry());
+            idt.breakpoint.set_handler_addr(gdb::breakpoint_entry());
+        }
+        idt.double_fault.set_handler_fn(double_fault_handler);
+        idt[hardware::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_handler);
+        idt[hardware::TIMER_VECTOR as usize].set_handler_fn(timer_handler);
+        set_irq_entries(&mut idt);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, _code: u64) -> ! {
+    panic!("Double fault at {:#x}", frame.instruction_pointer.as_u64());
+}
+
+/// Spurious local APIC interrupts must not be acknowledged.
+extern "x86-interrupt" fn spurious_handler(_frame: InterruptStackFrame) {}
+
+/// The local APIC timer wakes the CPU, where `timer::run_expired` does the
+/// work, and counts down the running process's time slice. The EOI comes
+/// first, since the tick may switch to another context.
+extern "x86-interrupt" fn timer_handler(_frame: InterruptStackFrame) {
+    hardware::local_apic_eoi();
+    loader::on_timer_tick();
+}
+
+fn dispatch_irq(irq: u8) {
+    if APIC_MODE.load(Ordering::Relaxed) {
+        let handler = IRQ_HANDLERS.lock()[irq as usize];
+        if let Some(handler) = handler {
+            handler();
+        }
+        hardware::local_apic_eoi();
+        return;
+    }
+
+    // Spurious IRQ 7/15: the PIC raised the line but has nothing in service.
+    if (irq == 7 || irq == 15) && !irq_in_service(irq) {
+        if irq == 15 {
+            send_eoi(PIC_CASCADE_IRQ);
+        }
+        return;
+    }
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+    send_eoi(irq);
+}
+
+// --- 8259 PIC ---
+
+fn remap_pics() {
+    let mut command_1: Port<u8> = Port::new(PIC_1_COMMAND);
+    let mut data_1: Port<u8> = Port::new(PIC_1_DATA);
+    let mut command_2: Port<u8> = Port::new(PIC_2_COMMAND);
+    let mut data_2: Port<u8> = Port::new(PIC_2_DATA);
+
+    unsafe {
+        command_1.write(PIC_INIT);
+        command_2.write(PIC_INIT);
+        data_1.write(PIC_1_OFFSET);
+        data_2.write(PIC_2_OFFSET);
+        data_1.write(1 << PIC_CASCADE_IRQ); // Secondary PIC on IRQ2
+        data_2.write(PIC_CASCADE_IRQ); // Cascade identity
+        data_1.write(PIC_MODE_8086);
+        data_2.write(PIC_MODE_8086);
+
+        // Mask everything except the cascade until drivers register
+        data_1.write(!(1 << PIC_CASCADE_IRQ));
+        data_2.write(0xFF);
+    }
+}
+
+/// Masks every PIC line, leaving them remapped clear of the CPU exceptions
+/// so a stray interrupt cannot be mistaken for one.
+pub fn disable_pics() {
+    remap_pics();
+    unsafe {
+        Port::<u8>::new(PIC_1_DATA).write(0xFF);
+        Port::<u8>::new(PIC_2_DATA).write(0xFF);
+    }
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    if APIC_MODE.load(Ordering::Relaxed) {
+        hardware::set_isa_irq_masked(irq, masked);
+        return;
+    }
+    let (mut data, line) = if irq < 8 {
+        (Port::<u8>::new(PIC_1_DATA), irq)
+    } else {
+        (Port::<u8>::new(PIC_2_DATA), irq - 8)
+    };
+    unsafe {
+        let mask = data.read();
+        data.write(if masked { mask | (1 << line) } else { mask & !(1 << line) });
+    }
+}
+
+fn irq_in_service(irq: u8) -> bool {
+    const READ_ISR: u8 = 0x0B;
+    let (mut command, line) = if irq < 8 {
+        (Port::<u8>::new(PIC_1_COMMAND), irq)
+    } else {
+        (Port::<u8>::new(PIC_2_COMMAND), irq - 8)
+    };
+    unsafe {
+        command.write(READ_ISR);
+        command.read() & (1 << line) != 0
+    }
+}
+
+fn send_eoi(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            Port::<u8>::new(PIC_2_COMMAND).write(PIC_EOI);
+        }
+        Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI);
+    }
+}
+
+// --- Public API ---
+
+/// Loads the kernel IDT and remaps the PICs, unmasking only lines that
+/// already have a handler. Interrupts stay disabled until `enable` is called.
+pub fn init() {
+    interrupts::disable();
+    IDT.load();
+    remap_pics();
+    for (irq, handler) in IRQ_HANDLERS.lock().iter().enumerate() {
+        if handler.is_some() {
+            set_masked(irq as u8, false);
+        }
+    }
+    ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// Whether the kernel IDT is in place, so drivers can rely on IRQs.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+pub fn enable() {
+    interrupts::enable();
+}
+
+/// Switches IRQ masking and EOIs to the APIC and unmasks every line that
+/// has a handler. Called by `hardware::init_apic` once routing is set up.
+pub fn use_apic() {
+    interrupts::without_interrupts(|| {
+        APIC_MODE.store(true, Ordering::SeqCst);
+        for (irq, handler) in IRQ_HANDLERS.lock().iter().enumerate() {
+            if handler.is_some() {
+                set_masked(irq as u8, false);
+            }
+        }
+    });
+}
+
+/// Installs the handler for an ISA IRQ line and unmasks it.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT || irq == PIC_CASCADE_IRQ {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        if handlers[irq as usize].is_some() {
+            return Err(IrqError::AlreadyRegistered(irq));
+        }
+        handlers[irq as usize] = Some(handler);
+        if is_active() {
+            set_masked(irq, false);
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,972 @@
+// Module for the PS/2 keyboard on the 8042 controller
+//
+// Scan codes are decoded into layout-independent key codes, named after the
+// keys of a US keyboard, and then into characters through the configured
+// layout. The controller usually translates the keyboard's set 2 codes into
+// set 1; both are decoded, depending on whether translation is enabled.
+//
+// Each key event goes to the focused foreground program, is emitted as
+// `key-input` to handlers whose manifest allows it, and is typed into the
+// debug shell when no program has focus. Shift with the navigation keys
+// scrolls the console instead.
+//
+// The keyboard is only set up once boot services have exited, so the
+// firmware's own keyboard driver is no longer reading the controller. Bytes
+// are then taken from IRQ 1 and decoded in the interrupt handler.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::console::{self, ScrollCommand};
+use crate::event_loop::{self, EventKind, EventPayload};
+use crate::hardware::{self, BOOT_ARCH_8042};
+use crate::interrupts::{self, IrqError, IRQ_KEYBOARD};
+use crate::loader;
+use crate::serial::ByteRing;
+
+// 8042 controller ports
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+// Status register bits
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+const STATUS_AUX_DATA: u8 = 0x20;
+
+// Controller commands and configuration byte bits
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const CONFIG_KEYBOARD_INTERRUPT: u8 = 0x01;
+const CONFIG_TRANSLATION: u8 = 0x40;
+
+// Keyboard commands and responses
+const KEYBOARD_SET_LEDS: u8 = 0xED;
+const RESPONSE_ACK: u8 = 0xFA;
+const RESPONSE_RESEND: u8 = 0xFE;
+const RESPONSE_ECHO: u8 = 0xEE;
+const RESPONSE_SELF_TEST_PASSED: u8 = 0xAA;
+const RESPONSE_ERROR: u8 = 0xFF;
+
+// LED bits sent after KEYBOARD_SET_LEDS
+const LED_SCROLL_LOCK: u8 = 0x01;
+const LED_NUM_LOCK: u8 = 0x02;
+const LED_CAPS_LOCK: u8 = 0x04;
+
+// Scan code prefixes
+const PREFIX_EXTENDED: u8 = 0xE0;
+const PREFIX_PAUSE: u8 = 0xE1;
+const SET2_PREFIX_RELEASE: u8 = 0xF0;
+const SET1_RELEASE_BIT: u8 = 0x80;
+
+/// Bytes following E1 in the set 2 Pause sequence.
+const SET2_PAUSE_LENGTH: u8 = 7;
+
+/// Status register polls before giving up on the controller.
+const CONTROLLER_TIMEOUT_POLLS: usize = 100_000;
+
+/// Scan code bytes buffered between the IRQ and the kernel loop.
+const RX_BUFFER_SIZE: usize = 64;
+
+/// Errors returned when setting up the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardError {
+    /// The FADT reports no 8042, or nothing answers at its ports.
+    NoController,
+    /// The controller did not respond to a command.
+    Timeout,
+    /// `init` has not succeeded.
+    NotInitialized,
+    /// Interrupt mode needs the kernel IDT, see `interrupts::init`.
+    InterruptsInactive,
+    /// IRQ 1 could not be claimed.
+    Irq(IrqError),
+}
+
+// --- Key Codes ---
+
+/// A physical key, named after its legend on a US keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Backquote,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Digit0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    /// Above Enter on US keyboards, left of it on ISO ones.
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    /// The extra key right of left Shift on ISO keyboards.
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftSuper,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightSuper,
+    Menu,
+    RightCtrl,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    ArrowUp,
+    ArrowLeft,
+    ArrowDown,
+    ArrowRight,
+    NumLock,
+    NumpadSlash,
+    NumpadStar,
+    NumpadMinus,
+    NumpadPlus,
+    NumpadEnter,
+    NumpadDot,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+}
+
+/// Modifier and lock key state at the time of a key event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    /// Right Alt, which selects the third level on non-US layouts.
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// A key press or release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    pub modifiers: Modifiers,
+    /// The character the key types in the current layout, if any. Ctrl with
+    /// a letter gives the matching control character.
+    pub character: Option<char>,
+}
+
+// --- Scan Code Sets ---
+
+/// The scan code set the keyboard's bytes arrive in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+fn set1_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Digit1,
+        0x03 => Digit2,
+        0x04 => Digit3,
+        0x05 => Digit4,
+        0x06 => Digit5,
+        0x07 => Digit6,
+        0x08 => Digit7,
+        0x09 => Digit8,
+        0x0A => Digit9,
+        0x0B => Digit0,
+        0x0C => Minus,
+        0x0D => Equals,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1A => LeftBracket,
+        0x1B => RightBracket,
+        0x1C => Enter,
+        0x1D => LeftCtrl,
+        0x1E => A,
+        0x1F => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Backquote,
+        0x2A => LeftShift,
+        0x2B => Backslash,
+        0x2C => Z,
+        0x2D => X,
+        0x2E => C,
+        0x2F => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => NumpadStar,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1,
+        0x3C => F2,
+        0x3D => F3,
+        0x3E => F4,
+        0x3F => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Numpad7,
+        0x48 => Numpad8,
+        0x49 => Numpad9,
+        0x4A => NumpadMinus,
+        0x4B => Numpad4,
+        0x4C => Numpad5,
+        0x4D => Numpad6,
+        0x4E => NumpadPlus,
+        0x4F => Numpad1,
+        0x50 => Numpad2,
+        0x51 => Numpad3,
+        0x52 => Numpad0,
+        0x53 => NumpadDot,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+/// Keys sent with the E0 prefix in set 1. The fake shifts sent around
+/// PrintScreen and the navigation keys (E0 2A, E0 36) are ignored.
+fn set1_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x1C => NumpadEnter,
+        0x1D => RightCtrl,
+        0x35 => NumpadSlash,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => ArrowUp,
+        0x49 => PageUp,
+        0x4B => ArrowLeft,
+        0x4D => ArrowRight,
+        0x4F => End,
+        0x50 => ArrowDown,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5B => LeftSuper,
+        0x5C => RightSuper,
+        0x5D => Menu,
+        _ => return None,
+    })
+}
+
+fn set2_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Backquote,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Digit1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Digit2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Digit4,
+        0x26 => Digit3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Digit5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Digit6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Digit7,
+        0x3E => Digit8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Digit0,
+        0x46 => Digit9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Numpad1,
+        0x6B => Numpad4,
+        0x6C => Numpad7,
+        0x70 => Numpad0,
+        0x71 => NumpadDot,
+        0x72 => Numpad2,
+        0x73 => Numpad5,
+        0x74 => Numpad6,
+        0x75 => Numpad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => NumpadPlus,
+        0x7A => Numpad3,
+        0x7B => NumpadMinus,
+        0x7C => NumpadStar,
+        0x7D => Numpad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+/// Keys sent with the E0 prefix in set 2; the fake shifts are ignored.
+fn set2_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1F => LeftSuper,
+        0x27 => RightSuper,
+        0x2F => Menu,
+        0x4A => NumpadSlash,
+        0x5A => NumpadEnter,
+        0x69 => End,
+        0x6B => ArrowLeft,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => ArrowDown,
+        0x74 => ArrowRight,
+        0x75 => ArrowUp,
+        0x7A => PageDown,
+        0x7C => PrintScreen,
+        0x7D => PageUp,
+        _ => return None,
+    })
+}
+
+/// Turns scan code bytes into key presses and releases.
+#[derive(Debug)]
+struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    /// Set 2 only: the next code is a release.
+    release: bool,
+    /// Bytes of a Pause sequence still to come.
+    pause_bytes: u8,
+}
+
+impl Decoder {
+    const fn new(set: ScancodeSet) -> Decoder {
+        Decoder {
+            set,
+            extended: false,
+            release: false,
+            pause_bytes: 0,
+        }
+    }
+
+    /// Feeds one byte, returning `(key, pressed)` once a sequence completes.
+    fn feed(&mut self, byte: u8) -> Option<(KeyCode, bool)> {
+        if self.pause_bytes > 0 {
+            self.pause_bytes -= 1;
+            return self.finish_pause(byte);
+        }
+        match byte {
+            0x00 | RESPONSE_ACK | RESPONSE_RESEND | RESPONSE_ECHO | RESPONSE_ERROR => return None,
+            // In set 1 this is also the release of left Shift.
+            RESPONSE_SELF_TEST_PASSED if self.set == ScancodeSet::Set2 => return None,
+            PREFIX_EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+            PREFIX_PAUSE => {
+                self.pause_bytes = match self.set {
+                    ScancodeSet::Set1 => 2,
+                    ScancodeSet::Set2 => SET2_PAUSE_LENGTH,
+                };
+                return None;
+            }
+            SET2_PREFIX_RELEASE if self.set == ScancodeSet::Set2 => {
+                self.release = true;
+                return None;
+            }
+            _ => {}
+        }
+
+        let extended = core::mem::take(&mut self.extended);
+        match self.set {
+            ScancodeSet::Set1 => {
+                let code = byte & !SET1_RELEASE_BIT;
+                let key = if extended { set1_extended_key(code) } else { set1_key(code) };
+                Some((key?, byte & SET1_RELEASE_BIT == 0))
+            }
+            ScancodeSet::Set2 => {
+                let pressed = !core::mem::take(&mut self.release);
+                let key = if extended { set2_extended_key(byte) } else { set2_key(byte) };
+                Some((key?, pressed))
+            }
+        }
+    }
+
+    /// Pause sends E1 1D 45 E1 9D C5 in set 1, a press and a release, but
+    /// only a press (E1 14 77 E1 F0 14 F0 77) in set 2.
+    fn finish_pause(&mut self, byte: u8) -> Option<(KeyCode, bool)> {
+        if self.pause_bytes > 0 {
+            return None;
+        }
+        match (self.set, byte) {
+            (ScancodeSet::Set1, 0x45) => Some((KeyCode::Pause, true)),
+            (ScancodeSet::Set1, 0xC5) => Some((KeyCode::Pause, false)),
+            (ScancodeSet::Set2, _) => Some((KeyCode::Pause, true)),
+            _ => None,
+        }
+    }
+}
+
+// --- Layouts ---
+
+/// Keyboard layouts for turning keys into characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 2] = [Layout::Us, Layout::Uk];
+
+    /// Looks up a layout by its configuration name.
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+        }
+    }
+
+    /// The character `key` types with `modifiers` held, if any.
+    pub fn character(&self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        if let Some(c) = letter(key) {
+            let upper = modifiers.shift != modifiers.caps_lock;
+            if modifiers.ctrl {
+                // Ctrl-A is 0x01 through Ctrl-Z at 0x1A.
+                return char::from_u32(c as u32 - 'a' as u32 + 1);
+            }
+            return Some(if upper { c.to_ascii_uppercase() } else { c });
+        }
+        if let Some(c) = numpad(key, modifiers) {
+            return Some(c);
+        }
+        if modifiers.alt_gr {
+            return self.third_level(key);
+        }
+        let (normal, shifted) = self.symbol(key)?;
+        Some(if modifiers.shift { shifted } else { normal })
+    }
+
+    /// Unshifted and shifted characters of the non-letter keys.
+    fn symbol(&self, key: KeyCode) -> Option<(char, char)> {
+        use KeyCode::*;
+        let uk = *self == Layout::Uk;
+        Some(match key {
+            Backquote if uk => ('`', '¬'),
+            Backquote => ('`', '~'),
+            Digit1 => ('1', '!'),
+            Digit2 if uk => ('2', '"'),
+            Digit2 => ('2', '@'),
+            Digit3 if uk => ('3', '£'),
+            Digit3 => ('3', '#'),
+            Digit4 => ('4', '$'),
+            Digit5 => ('5', '%'),
+            Digit6 => ('6', '^'),
+            Digit7 => ('7', '&'),
+            Digit8 => ('8', '*'),
+            Digit9 => ('9', '('),
+            Digit0 => ('0', ')'),
+            Minus => ('-', '_'),
+            Equals => ('=', '+'),
+            LeftBracket => ('[', '{'),
+            RightBracket => (']', '}'),
+            Backslash if uk => ('#', '~'),
+            Backslash => ('\\', '|'),
+            Semicolon => (';', ':'),
+            Quote if uk => ('\'', '@'),
+            Quote => ('\'', '"'),
+            NonUsBackslash => ('\\', '|'),
+            Comma => (',', '<'),
+            Period => ('.', '>'),
+            Slash => ('/', '?'),
+            Space => (' ', ' '),
+            Tab => ('\t', '\t'),
+            Enter | NumpadEnter => ('\n', '\n'),
+            Backspace => ('\x08', '\x08'),
+            Escape => ('\x1b', '\x1b'),
+            _ => return None,
+        })
+    }
+
+    /// Characters typed with AltGr held.
+    fn third_level(&self, key: KeyCode) -> Option<char> {
+        match (self, key) {
+            (Layout::Uk, KeyCode::Backquote) => Some('¦'),
+            (Layout::Uk, KeyCode::Digit4) => Some('€'),
+            _ => None,
+        }
+    }
+}
+
+fn letter(key: KeyCode) -> Option<char> {
+    use KeyCode::*;
+    Some(match key {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    })
+}
+
+/// Numpad characters; the digits and dot only type with Num Lock on.
+fn numpad(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    use KeyCode::*;
+    let digits = modifiers.num_lock && !modifiers.shift;
+    match key {
+        NumpadSlash => Some('/'),
+        NumpadStar => Some('*'),
+        NumpadMinus => Some('-'),
+        NumpadPlus => Some('+'),
+        Numpad0 if digits => Some('0'),
+        Numpad1 if digits => Some('1'),
+        Numpad2 if digits => Some('2'),
+        Numpad3 if digits => Some('3'),
+        Numpad4 if digits => Some('4'),
+        Numpad5 if digits => Some('5'),
+        Numpad6 if digits => Some('6'),
+        Numpad7 if digits => Some('7'),
+        Numpad8 if digits => Some('8'),
+        Numpad9 if digits => Some('9'),
+        NumpadDot if digits => Some('.'),
+        _ => None,
+    }
+}
+
+// --- Keyboard State ---
+
+/// Modifier keys currently held, tracked per side.
+#[derive(Debug, Clone, Copy, Default)]
+struct HeldModifiers {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    left_alt: bool,
+    right_alt: bool,
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    layout: Layout,
+    held: HeldModifiers,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+    /// LED state to send once the keyboard acknowledges KEYBOARD_SET_LEDS.
+    leds_pending: Option<u8>,
+    rx: ByteRing<RX_BUFFER_SIZE>,
+}
+
+impl Keyboard {
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.held.left_shift || self.held.right_shift,
+            ctrl: self.held.left_ctrl || self.held.right_ctrl,
+            alt: self.held.left_alt,
+            alt_gr: self.held.right_alt,
+            caps_lock: self.caps_lock,
+            num_lock: self.num_lock,
+            scroll_lock: self.scroll_lock,
+        }
+    }
+
+    /// Updates modifier and lock state for a key, then describes it.
+    fn key_event(&mut self, key: KeyCode, pressed: bool) -> KeyEvent {
+        let held = &mut self.held;
+        match key {
+            KeyCode::LeftShift => held.left_shift = pressed,
+            KeyCode::RightShift => held.right_shift = pressed,
+            KeyCode::LeftCtrl => held.left_ctrl = pressed,
+            KeyCode::RightCtrl => held.right_ctrl = pressed,
+            KeyCode::LeftAlt => held.left_alt = pressed,
+            KeyCode::RightAlt => held.right_alt = pressed,
+            KeyCode::CapsLock if pressed => self.toggle_lock(LED_CAPS_LOCK),
+            KeyCode::NumLock if pressed => self.toggle_lock(LED_NUM_LOCK),
+            KeyCode::ScrollLock if pressed => self.toggle_lock(LED_SCROLL_LOCK),
+            _ => {}
+        }
+        let modifiers = self.modifiers();
+        KeyEvent {
+            key,
+            pressed,
+            modifiers,
+            character: if pressed { self.layout.character(key, modifiers) } else { None },
+        }
+    }
+
+    /// Toggles a lock key and asks the keyboard to update its LEDs. Typematic
+    /// repeats of a held lock key toggle it again, as on other systems.
+    fn toggle_lock(&mut self, led: u8) {
+        match led {
+            LED_CAPS_LOCK => self.caps_lock = !self.caps_lock,
+            LED_NUM_LOCK => self.num_lock = !self.num_lock,
+            _ => self.scroll_lock = !self.scroll_lock,
+        }
+        // A command already waiting for its ACK sends the latest state.
+        let leds = self.leds();
+        if self.leds_pending.is_some() || write_data(KEYBOARD_SET_LEDS) {
+            self.leds_pending = Some(leds);
+        }
+    }
+
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        if self.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        leds
+    }
+
+    /// Handles one byte from the keyboard.
+    fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if byte == RESPONSE_ACK {
+            if let Some(leds) = self.leds_pending.take() {
+                write_data(leds);
+            }
+        }
+        let (key, pressed) = self.decoder.feed(byte)?;
+        Some(self.key_event(key, pressed))
+    }
+
+    /// Moves any bytes waiting in the controller into the receive buffer.
+    fn poll_controller(&mut self) {
+        let mut status: Port<u8> = Port::new(STATUS_PORT);
+        let mut data: Port<u8> = Port::new(DATA_PORT);
+        while !self.rx.is_full() {
+            let value = unsafe { status.read() };
+            // Mouse bytes are left for whoever owns the auxiliary port.
+            if value & STATUS_OUTPUT_FULL == 0 || value & STATUS_AUX_DATA != 0 {
+                break;
+            }
+            self.rx.push(unsafe { data.read() });
+        }
+    }
+}
+
+static KEYBOARD: Mutex<Option<Keyboard>> = Mutex::new(None);
+
+// --- Controller I/O ---
+
+fn wait_for_status(mask: u8, set: bool) -> bool {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    (0..CONTROLLER_TIMEOUT_POLLS).any(|_| (unsafe { status.read() } & mask != 0) == set)
+}
+
+/// Sends a byte to the keyboard. Returns false if the controller is busy.
+fn write_data(byte: u8) -> bool {
+    if !wait_for_status(STATUS_INPUT_FULL, false) {
+        return false;
+    }
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+    true
+}
+
+/// Reads the controller configuration byte.
+fn read_config() -> Result<u8, KeyboardError> {
+    if !wait_for_status(STATUS_INPUT_FULL, false) {
+        return Err(KeyboardError::Timeout);
+    }
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(COMMAND_READ_CONFIG) };
+    if !wait_for_status(STATUS_OUTPUT_FULL, true) {
+        return Err(KeyboardError::Timeout);
+    }
+    Ok(unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+/// Writes the controller configuration byte.
+fn write_config(config: u8) -> Result<(), KeyboardError> {
+    if !wait_for_status(STATUS_INPUT_FULL, false) {
+        return Err(KeyboardError::Timeout);
+    }
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(COMMAND_WRITE_CONFIG) };
+    if !write_data(config) {
+        return Err(KeyboardError::Timeout);
+    }
+    Ok(())
+}
+
+// --- Public API ---
+
+/// Finds the keyboard controller and starts decoding with `layout`. The
+/// controller is left configured as the firmware set it up.
+pub fn init(layout: Layout) -> Result<(), KeyboardError> {
+    let fadt = hardware::acpi_tables().and_then(|tables| tables.fadt);
+    if !fadt.is_none_or(|fadt| fadt.boot_architecture & BOOT_ARCH_8042 != 0) {
+        return Err(KeyboardError::NoController);
+    }
+    // A floating bus reads as all ones.
+    if unsafe { Port::<u8>::new(STATUS_PORT).read() } == 0xFF {
+        return Err(KeyboardError::NoController);
+    }
+    let set = cpu_interrupts::without_interrupts(read_config).map(|config| {
+        if config & CONFIG_TRANSLATION != 0 {
+            ScancodeSet::Set1
+        } else {
+            ScancodeSet::Set2
+        }
+    })?;
+
+    *KEYBOARD.lock() = Some(Keyboard {
+        decoder: Decoder::new(set),
+        layout,
+        held: HeldModifiers::default(),
+        caps_lock: false,
+        num_lock: false,
+        scroll_lock: false,
+        leds_pending: None,
+        rx: ByteRing::new(),
+    });
+    log::info!("PS/2 keyboard: scan code {:?}, {} layout", set, layout.name());
+    Ok(())
+}
+
+/// Delivers key events from IRQ 1. Requires `interrupts::init`.
+pub fn enable_interrupts() -> Result<(), KeyboardError> {
+    if !interrupts::is_active() {
+        return Err(KeyboardError::InterruptsInactive);
+    }
+    cpu_interrupts::without_interrupts(|| {
+        let mut guard = KEYBOARD.lock();
+        let keyboard = guard.as_mut().ok_or(KeyboardError::NotInitialized)?;
+        let config = read_config()?;
+        if config & CONFIG_KEYBOARD_INTERRUPT == 0 {
+            write_config(config | CONFIG_KEYBOARD_INTERRUPT)?;
+        }
+        interrupts::register_irq(IRQ_KEYBOARD, keyboard_irq).map_err(KeyboardError::Irq)?;
+        // A byte already waiting raised its edge before we were listening.
+        keyboard.poll_controller();
+        Ok(())
+    })?;
+    deliver_received();
+    Ok(())
+}
+
+fn keyboard_irq() {
+    if let Some(keyboard) = KEYBOARD.lock().as_mut() {
+        keyboard.poll_controller();
+    }
+    deliver_received();
+}
+
+/// Decodes the received scan codes and delivers the key events.
+fn deliver_received() {
+    loop {
+        let event = cpu_interrupts::without_interrupts(|| {
+            let mut guard = KEYBOARD.lock();
+            let keyboard = guard.as_mut()?;
+            // Bytes that complete no key are consumed without an event.
+            loop {
+                let byte = keyboard.rx.pop()?;
+                if let Some(event) = keyboard.feed(byte) {
+                    return Some(event);
+                }
+            }
+        });
+        match event {
+            Some(event) => deliver(event),
+            None => return,
+        }
+    }
+}
+
+/// Shift with the navigation keys scrolls the console.
+fn scroll_binding(event: &KeyEvent) -> Option<ScrollCommand> {
+    if !event.pressed || !event.modifiers.shift {
+        return None;
+    }
+    match event.key {
+        KeyCode::ArrowUp => Some(ScrollCommand::LineUp),
+        KeyCode::ArrowDown => Some(ScrollCommand::LineDown),
+        KeyCode::PageUp => Some(ScrollCommand::PageUp),
+        KeyCode::PageDown => Some(ScrollCommand::PageDown),
+        KeyCode::Home => Some(ScrollCommand::Top),
+        KeyCode::End => Some(ScrollCommand::FollowTail),
+        _ => None,
+    }
+}
+
+fn deliver(event: KeyEvent) {
+    if let Some(command) = scroll_binding(&event) {
+        console::scroll(command);
+        return;
+    }
+    loader::deliver_key(event);
+    if let Err(e) = event_loop::emit(EventKind::KeyInput, EventPayload::Key(event)) {
+        log::warn!("Dropped key event: {:?}", e);
+    }
+}
@@ -1 +1,467 @@
-// Placeholder for loading handler programs 
\ No newline at end of file
+// Module for loading foreground programs and scheduling between them
+//
+// The kernel loop hands the CPU to the ready processes in turn through
+// `run_foreground`, each for up to one time slice, and gets it back after the
+// last one. A slice is counted in local APIC timer ticks: the timer is armed
+// one tick ahead while a process runs, and `on_timer_tick` switches away
+// once the slice is used up. A process waiting for input is blocked until a
+// key arrives.
+//
+// Kernel programs such as the shell are launched with `launch_kernel` and do
+// not count against the policy's `max_foreground`.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::audit::{self, Outcome};
+use crate::event_loop;
+use crate::hardware::{self, Instant};
+use crate::keyboard::KeyEvent;
+use crate::manifest::Manifest;
+
+/// Foreground processes allowed by default, per the kernel policy.
+pub const DEFAULT_MAX_FOREGROUND: usize = 1;
+
+/// Stack size for each foreground process.
+const PROCESS_STACK_SIZE: usize = 64 * 1024;
+
+/// Timer ticks a process may run before it is preempted.
+const TIME_SLICE_TICKS: u32 = 4;
+
+/// Length of a scheduler tick.
+const TICK: Duration = Duration::from_millis(10);
+
+/// Key events queued for a process before the oldest are discarded.
+const KEY_QUEUE_DEPTH: usize = 64;
+
+pub type ProcessId = u32;
+
+/// Entry point of a foreground program. Returning terminates the process.
+pub type ProcessEntry = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Running,
+    /// Waiting in `wait_key` for a key event.
+    Blocked,
+    Terminated,
+}
+
+/// Errors returned by the loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoaderError {
+    /// Launching another program would exceed `max_foreground`.
+    ForegroundLimitReached { limit: usize },
+    /// No live process has the given id.
+    NoSuchProcess(ProcessId),
+    /// The program's manifest does not allow it to run in the foreground.
+    ForegroundNotAllowed,
+}
+
+// --- Context Switching ---
+
+// switch_context(old_rsp: *mut u64, new_rsp: u64)
+// Saves the callee-saved registers on the current stack, stores the stack
+// pointer through `old_rsp`, then restores the same registers from `new_rsp`.
+global_asm!(
+    ".global optios_switch_context",
+    "optios_switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    fn optios_switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// Registers pushed by `optios_switch_context` below the return address.
+const SAVED_REGISTERS: usize = 6;
+
+struct Process {
+    pid: ProcessId,
+    name: &'static str,
+    entry: ProcessEntry,
+    /// Launched by the kernel; not counted against `max_foreground`.
+    kernel: bool,
+    state: ProcessState,
+    rsp: u64,
+    /// Key events received while the process had focus, oldest first.
+    keys: VecDeque<KeyEvent>,
+    // Kept alive for as long as the process exists; only `rsp` points into it.
+    _stack: Box<[u8]>,
+}
+
+impl Process {
+    fn new(pid: ProcessId, name: &'static str, entry: ProcessEntry, kernel: bool) -> Box<Process> {
+        let mut stack = vec![0u8; PROCESS_STACK_SIZE].into_boxed_slice();
+        let top = (stack.as_mut_ptr() as u64 + PROCESS_STACK_SIZE as u64) & !0xF;
+
+        // Lay the stack out as if `optios_switch_context` had been called from
+        // the trampoline: saved registers, then the address to return to.
+        let frame = top - 8 * (SAVED_REGISTERS as u64 + 2);
+        unsafe {
+            let slots = frame as *mut u64;
+            for i in 0..SAVED_REGISTERS {
+                slots.add(i).write(0);
+            }
+            slots.add(SAVED_REGISTERS).write(process_trampoline as *const () as u64);
+            slots.add(SAVED_REGISTERS + 1).write(0); // Fake return address, keeps alignment
+        }
+
+        Box::new(Process {
+            pid,
+            name,
+            entry,
+            kernel,
+            state: ProcessState::Ready,
+            rsp: frame,
+            keys: VecDeque::new(),
+            _stack: stack,
+        })
+    }
+}
+
+/// Information about a foreground process for callers outside the loader.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    pub pid: ProcessId,
+    pub name: &'static str,
+    pub state: ProcessState,
+    pub focused: bool,
+    pub kernel: bool,
+}
+
+// --- Scheduler ---
+
+pub struct Scheduler {
+    // Boxed so saved stack pointer slots stay put while the vector grows.
+    #[allow(clippy::vec_box)]
+    processes: Vec<Box<Process>>,
+    current: Option<usize>,
+    focused: Option<ProcessId>,
+    max_foreground: usize,
+    next_pid: ProcessId,
+    ticks_left: u32,
+    // Stack pointer of the kernel context that started scheduling.
+    kernel_rsp: u64,
+}
+
+impl Scheduler {
+    pub const fn new() -> Scheduler {
+        Scheduler {
+            processes: Vec::new(),
+            current: None,
+            focused: None,
+            max_foreground: DEFAULT_MAX_FOREGROUND,
+            next_pid: 1,
+            ticks_left: TIME_SLICE_TICKS,
+            kernel_rsp: 0,
+        }
+    }
+
+    /// Live programs counted against `max_foreground`.
+    fn live_count(&self) -> usize {
+        self.processes
+            .iter()
+            .filter(|p| p.state != ProcessState::Terminated && !p.kernel)
+            .count()
+    }
+
+    fn index_of(&self, pid: ProcessId) -> Option<usize> {
+        self.processes
+            .iter()
+            .position(|p| p.pid == pid && p.state != ProcessState::Terminated)
+    }
+
+    /// Drops terminated processes other than the one still running on its stack.
+    fn reap(&mut self) {
+        let current_pid = self.current.map(|i| self.processes[i].pid);
+        self.processes
+            .retain(|p| p.state != ProcessState::Terminated || Some(p.pid) == current_pid);
+        self.current = current_pid.and_then(|pid| self.processes.iter().position(|p| p.pid == pid));
+    }
+
+    /// Picks the next ready process after the current one. None means the
+    /// rotation is over and the kernel runs next; from the kernel, the
+    /// rotation starts again at the first process.
+    fn next_ready(&self) -> Option<usize> {
+        let start = self.current.map_or(0, |i| i + 1);
+        (start..self.processes.len()).find(|&i| self.processes[i].state == ProcessState::Ready)
+    }
+
+    fn set_focus(&mut self, focused: Option<ProcessId>) {
+        self.focused = focused;
+        event_loop::set_foreground_interactive(focused.is_some());
+    }
+
+    /// Prepares a switch to the next runnable context and returns the stack
+    /// pointer slots to pass to `optios_switch_context`.
+    fn prepare_switch(&mut self) -> Option<(*mut u64, u64)> {
+        self.ticks_left = TIME_SLICE_TICKS;
+        let next = self.next_ready();
+
+        let old_rsp: *mut u64 = match self.current {
+            Some(i) => {
+                let process = &mut self.processes[i];
+                if process.state == ProcessState::Running {
+                    process.state = ProcessState::Ready;
+                }
+                &mut process.rsp
+            }
+            None => {
+                // Nothing to run; the kernel keeps going.
+                next?;
+                &mut self.kernel_rsp
+            }
+        };
+
+        let new_rsp = match next {
+            Some(i) => {
+                self.processes[i].state = ProcessState::Running;
+                self.current = Some(i);
+                // Preempt the process once its slice is up.
+                hardware::set_timer_deadline(Some(Instant::now() + TICK));
+                self.processes[i].rsp
+            }
+            None => {
+                self.current = None;
+                self.kernel_rsp
+            }
+        };
+        Some((old_rsp, new_rsp))
+    }
+}
+
+// --- Global Scheduler Instance ---
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+/// Sets the foreground process limit from the kernel policy.
+pub fn set_max_foreground(limit: usize) {
+    interrupts::without_interrupts(|| SCHEDULER.lock().max_foreground = limit);
+}
+
+fn spawn(manifest: &'static Manifest, entry: ProcessEntry, kernel: bool) -> Result<ProcessId, LoaderError> {
+    let permitted = manifest.contexts.allows_foreground();
+    audit::record(
+        manifest.name,
+        String::from("launch"),
+        if permitted { Outcome::Allowed } else { Outcome::Denied },
+    );
+    if !permitted {
+        return Err(LoaderError::ForegroundNotAllowed);
+    }
+    let name = manifest.name;
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.reap();
+        if !kernel && scheduler.live_count() >= scheduler.max_foreground {
+            return Err(LoaderError::ForegroundLimitReached {
+                limit: scheduler.max_foreground,
+            });
+        }
+        let pid = scheduler.next_pid;
+        scheduler.next_pid += 1;
+        scheduler.processes.push(Process::new(pid, name, entry, kernel));
+        scheduler.set_focus(Some(pid));
+        log::info!("Launched foreground process {} ({})", pid, name);
+        Ok(pid)
+    })
+}
+
+/// Launches a foreground program and gives it focus. Its manifest must
+/// allow the foreground; the decision is recorded in the audit log.
+pub fn launch(manifest: &'static Manifest, entry: ProcessEntry) -> Result<ProcessId, LoaderError> {
+    spawn(manifest, entry, false)
+}
+
+/// Launches a kernel program, such as the shell, and gives it focus. It does
+/// not count against `max_foreground`.
+pub fn launch_kernel(manifest: &'static Manifest, entry: ProcessEntry) -> Result<ProcessId, LoaderError> {
+    spawn(manifest, entry, true)
+}
+
+/// Gives input focus to a foreground process.
+pub fn focus(pid: ProcessId) -> Result<(), LoaderError> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.index_of(pid).ok_or(LoaderError::NoSuchProcess(pid))?;
+        scheduler.set_focus(Some(pid));
+        Ok(())
+    })
+}
+
+pub fn focused() -> Option<ProcessId> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().focused)
+}
+
+/// Queues a key event for the focused process, waking it if it is blocked
+/// in `wait_key`. Returns false if no process has focus.
+pub fn deliver_key(event: KeyEvent) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(index) = scheduler.focused.and_then(|pid| scheduler.index_of(pid)) else {
+            return false;
+        };
+        let process = &mut scheduler.processes[index];
+        if process.keys.len() >= KEY_QUEUE_DEPTH {
+            process.keys.pop_front();
+        }
+        process.keys.push_back(event);
+        if process.state == ProcessState::Blocked {
+            process.state = ProcessState::Ready;
+        }
+        true
+    })
+}
+
+/// Takes the next key event queued for the running process, blocking until
+/// one arrives. From the kernel context, where nothing can block, this
+/// returns None.
+pub fn wait_key() -> Option<KeyEvent> {
+    loop {
+        let key = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let index = scheduler.current?;
+            let process = &mut scheduler.processes[index];
+            let key = process.keys.pop_front();
+            if key.is_none() {
+                process.state = ProcessState::Blocked;
+            }
+            Some(key)
+        });
+        match key {
+            Some(Some(key)) => return Some(key),
+            Some(None) => yield_now(),
+            None => return None,
+        }
+    }
+}
+
+/// Terminates a foreground process. Terminating the running process
+/// switches away from it immediately.
+pub fn terminate(pid: ProcessId) -> Result<(), LoaderError> {
+    let is_current = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let index = scheduler.index_of(pid).ok_or(LoaderError::NoSuchProcess(pid))?;
+        scheduler.processes[index].state = ProcessState::Terminated;
+        if scheduler.focused == Some(pid) {
+            let next_focus = scheduler
+                .processes
+                .iter()
+                .find(|p| p.state != ProcessState::Terminated)
+                .map(|p| p.pid);
+            scheduler.set_focus(next_focus);
+        }
+        log::info!("Terminated foreground process {}", pid);
+        Ok(scheduler.current == Some(index))
+    })?;
+
+    if is_current {
+        yield_now();
+    }
+    Ok(())
+}
+
+/// Lists the live foreground processes.
+pub fn processes() -> Vec<ProcessInfo> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler
+            .processes
+            .iter()
+            .filter(|p| p.state != ProcessState::Terminated)
+            .map(|p| ProcessInfo {
+                pid: p.pid,
+                name: p.name,
+                state: p.state,
+                focused: scheduler.focused == Some(p.pid),
+                kernel: p.kernel,
+            })
+            .collect()
+    })
+}
+
+/// Switches to the next runnable context, if any.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        let switch = SCHEDULER.lock().prepare_switch();
+        if let Some((old_rsp, new_rsp)) = switch {
+            // The lock is released; the slots stay valid because processes
+            // are boxed and only reaped from another stack.
+            unsafe { optios_switch_context(old_rsp, new_rsp) };
+        }
+    });
+}
+
+/// Timer tick hook, called from the local APIC timer interrupt; preempts
+/// the running process once its slice is used up. The kernel context is
+/// never preempted.
+pub fn on_timer_tick() {
+    let expired = {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.current.is_none() {
+            return;
+        }
+        scheduler.ticks_left = scheduler.ticks_left.saturating_sub(1);
+        scheduler.ticks_left == 0
+    };
+    if expired {
+        yield_now();
+    } else {
+        hardware::set_timer_deadline(Some(Instant::now() + TICK));
+    }
+}
+
+/// Runs each ready foreground process for up to a time slice, returning to
+/// the kernel loop after the last. Returns false if none was ready.
+pub fn run_foreground() -> bool {
+    let ready = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.reap();
+        scheduler.processes.iter().any(|p| p.state == ProcessState::Ready)
+    });
+    if ready {
+        yield_now();
+    }
+    ready
+}
+
+extern "C" fn process_trampoline() -> ! {
+    let entry = {
+        let scheduler = SCHEDULER.lock();
+        let index = scheduler.current.expect("trampoline without a current process");
+        scheduler.processes[index].entry
+    };
+    interrupts::enable();
+    entry();
+
+    let pid = interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.current.map(|i| scheduler.processes[i].pid)
+    });
+    if let Some(pid) = pid {
+        let _ = terminate(pid);
+    }
+    unreachable!("terminated process was scheduled again");
+}
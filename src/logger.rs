@@ -0,0 +1,204 @@
+// Module for the kernel `log` backend
+//
+// Every record is timestamped and written to the framebuffer console (with
+// ANSI level colors), to the serial port, and to an in-memory ring buffer that
+// the shell's `log` command reads back. Unlike the uefi_services logger this keeps working
+// after boot services are gone.
+//
+// The log ring is kept apart from the audit ring in `audit`: the audit ring
+// is the record of permission decisions, and a burst of debug output must
+// not evict those. Refusals are logged as well, so they show up in both.
+//
+// A record logged while another is being written, e.g. by the console code
+// itself, goes to the ring buffer only; writing it out would re-enter the
+// console and serial locks that are already held.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::hardware::Instant;
+use crate::{console, event_loop, serial};
+
+/// Records kept in the ring buffer before the oldest are discarded.
+pub const LOG_RING_CAPACITY: usize = 512;
+
+/// Crate prefix stripped from targets so filters can name modules directly.
+const CRATE_PREFIX: &str = "optios::";
+
+// --- Filters ---
+
+/// Per-module level filters, e.g. `info,loader=debug,uefi=warn`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    pub default: LevelFilter,
+    /// (module path, level) pairs; the longest matching path wins.
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> LogFilter {
+        LogFilter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    /// Parses a comma-separated list of `level` and `module=level` entries.
+    pub fn parse(value: &str) -> Option<LogFilter> {
+        let mut filter = LogFilter::default();
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split_once('=') {
+                Some((module, level)) => {
+                    let level = level.trim().parse().ok()?;
+                    filter.modules.push((String::from(module.trim()), level));
+                }
+                None => filter.default = entry.parse().ok()?,
+            }
+        }
+        Some(filter)
+    }
+
+    /// The level filter that applies to a record target.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let target = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|&(_, level)| level)
+            .unwrap_or(self.default)
+    }
+
+    /// The most verbose level any module may log at.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, core::cmp::max)
+    }
+}
+
+// --- Ring Buffer ---
+
+/// A log record as kept in the ring buffer.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp: Instant,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+static RING: Mutex<VecDeque<LogEntry>> = Mutex::new(VecDeque::new());
+
+/// The most recent `count` log entries, oldest first.
+pub fn recent(count: usize) -> Vec<LogEntry> {
+    interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        let skip = ring.len().saturating_sub(count);
+        ring.iter().skip(skip).cloned().collect()
+    })
+}
+
+// --- Logger ---
+
+struct KernelLogger {
+    /// Locked with interrupts disabled, since preempted processes log too.
+    filter: Mutex<Option<LogFilter>>,
+}
+
+static LOGGER: KernelLogger = KernelLogger {
+    filter: Mutex::new(None),
+};
+
+/// Set while a record is written to the console and serial port.
+static WRITING: AtomicBool = AtomicBool::new(false);
+
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[31m",
+        Level::Warn => "\x1b[33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[36m",
+        Level::Trace => "\x1b[90m",
+    }
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        interrupts::without_interrupts(|| match self.filter.lock().as_ref() {
+            Some(filter) => metadata.level() <= filter.level_for(metadata.target()),
+            None => metadata.level() <= LevelFilter::Info,
+        })
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = event_loop::now();
+        let level = record.level();
+        let message = format!("{}", record.args());
+
+        let mut line = String::new();
+        let _ = writeln!(
+            line,
+            "[{:>12}] {}{:<5}\x1b[0m {}: {}",
+            timestamp,
+            level_color(level),
+            level,
+            record.target(),
+            message
+        );
+
+        interrupts::without_interrupts(|| {
+            if !WRITING.swap(true, Ordering::SeqCst) {
+                console::_print(format_args!("{}", line));
+                serial::_print(format_args!("{}", line));
+                WRITING.store(false, Ordering::SeqCst);
+            }
+
+            let mut ring = RING.lock();
+            if ring.len() == LOG_RING_CAPACITY {
+                ring.pop_front();
+            }
+            ring.push_back(LogEntry {
+                timestamp,
+                level,
+                target: String::from(record.target()),
+                message,
+            });
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the kernel logger. Must be called once, before anything logs.
+pub fn init() {
+    let serial_status = serial::init_console();
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+    if let Err(e) = serial_status {
+        log::warn!("Serial console unavailable: {:?}", e);
+    }
+}
+
+/// Applies the per-module filters from the boot config.
+pub fn set_filter(filter: LogFilter) {
+    log::set_max_level(filter.max_level());
+    interrupts::without_interrupts(|| *LOGGER.filter.lock() = Some(filter));
+}
@@ -1,40 +1,56 @@
 #![no_std]
 #![no_main]
 // #![feature(lang_items)] // Removed as no longer needed
-// #![feature(abi_x86_interrupt)] // Likely not needed for UEFI app stage
+#![feature(abi_x86_interrupt)]
 
 // extern crate rlibc; // Keep for now, might be unneeded.
 
-// use core::panic::PanicInfo; // Removed as it's unused
+use core::panic::PanicInfo;
 use uefi::prelude::*;
-use log; // Import log to use its macros like info!, error!
-use uefi_services; // Add this use statement
-use uefi::proto::console::gop::{GraphicsOutput, PixelFormat, Mode};
+use uefi::table::cfg;
 // use core::fmt::Write; // No longer needed after switching to output_string
 
-use embedded_graphics::{
-    pixelcolor::Rgb888,
-    prelude::*,
-};
-use uefi_graphics::UefiDisplay;
+extern crate alloc;
 
-// mod serial; // Comment out for now
+use alloc::format;
+
+mod ansi;
+mod audit;
+mod boot_info;
+mod config;
+mod console;
+mod event_loop;
+mod font;
+mod gdb;
+mod hardware;
+mod heap;
+mod interrupts;
+mod keyboard;
+mod loader;
+mod logger;
+mod manifest;
+mod memory;
+mod power;
+mod rtc;
+mod runtime;
+mod serial;
+mod shell;
+mod time;
+mod timer;
+mod tz;
+mod video;
 // pub mod vga_text; // Comment out for now
-// mod rtc; // Comment out for now
 
-/*
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => (vga_text::_print(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
 }
 
 #[macro_export]
 macro_rules! println {
-    () => (print!("\n"));
-    ($($arg:tt)*) => (print!("{}
-", format_args!($($arg)*)));
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
-*/
 
 // entry_point!(kernel_entry); // Removed bootloader entry point
 
@@ -62,7 +78,7 @@ macro_rules! println {
     // vga_text::set_text_color(vga_text::Color::LightGray, vga_text::Color::Black);
     // vga_text::clear_screen(); // Clear screen with new background
 
-    // Futuristic Welcome Header (using simpler ASCII for compatibility)
+    // Futuristic Welcome Header
     // vga_text::set_text_color(vga_text::Color::LightBlue, vga_text::Color::Black);
     // println!("+--------------------------------------------------------------+");
     // println!("|                                                              |");
@@ -99,29 +115,136 @@ macro_rules! println {
 //     halt_loop();
 // }
 
-// Target resolution bounds (from tutorial Part 3)
-const MAX_WIDTH: usize = 1920;
-const MAX_HEIGHT: usize = 1080;
-// Let's try a more common one for QEMU default, like 1024x768, as an alternative
-// const MAX_WIDTH: usize = 1024;
-// const MAX_HEIGHT: usize = 768;
-
-fn print_welcome_message(display: &mut UefiDisplay) -> uefi::Result {
-    // This will just clear the screen to blue for now.
-    display.clear(Rgb888::BLUE).map_err(|_| uefi::Status::DEVICE_ERROR)?;
-    Ok(())
+use boot_info::BootInfo;
+
+fn print_welcome_message() {
+    use console::Color;
+
+    console::set_text_color(Color::LightGray, Color::Black);
+    console::clear_screen();
+
+    // Futuristic Welcome Header
+    console::set_text_color(Color::LightBlue, Color::Black);
+    println!("┌──────────────────────────────────────────────────────────────┐");
+    println!("│                                                              │");
+    print!("│");
+    console::set_text_color(Color::White, Color::Black);
+    print!("                Welcome to OptiOS v0.1.0                      ");
+    console::set_text_color(Color::LightBlue, Color::Black);
+    println!("│");
+    println!("│                                                              │");
+    println!("└──────────────────────────────────────────────────────────────┘");
+    println!();
+
+    if let Ok(local) = time::local_now() {
+        let datetime = local.datetime;
+        console::set_text_color(Color::LightGreen, Color::Black);
+        println!(
+            "    System Time: {}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+            datetime.year, datetime.month, datetime.day,
+            datetime.hour, datetime.minute, datetime.second, local.abbreviation
+        );
+        println!();
+    }
+
+    console::set_text_color(Color::Cyan, Color::Black);
+    println!("    Framebuffer Console Initialized.");
+    println!("    Initializing kernel modules...");
+    console::set_text_color(Color::LightGray, Color::Black);
+}
+
+/// Applies the boot-time policy to the kernel subsystems.
+fn kernel_main(boot_info: &BootInfo) {
+    let config = &boot_info.config;
+    loader::set_max_foreground(config.max_foreground);
+    power::set_grace_period(config.shutdown_grace_secs);
+    event_loop::set_policy(event_loop::EventPolicy {
+        background_timeout_secs: config.background_timeout_secs,
+        schedule_interval_secs: config.schedule_interval_secs,
+    });
+    log::info!("Kernel policy applied: max_foreground={}", config.max_foreground);
+    match boot_info.rsdp {
+        Some(rsdp) => {
+            log::info!("ACPI RSDP at {:#x}", rsdp);
+            if let Err(e) = hardware::init_acpi(rsdp) {
+                log::error!("Invalid ACPI tables: {:?}", e);
+            }
+        }
+        None => log::warn!("No ACPI RSDP; APIC interrupt routing unavailable"),
+    }
+    // Boot services have exited, so the IDT is ours. IRQs stay on the PICs
+    // if the APIC cannot be set up.
+    interrupts::init();
+    // Stop in the debugger as early as the IDT allows, so the rest of
+    // start-up can be stepped through.
+    if let Some(port) = config.gdb_port {
+        if let Err(e) = gdb::init(port) {
+            log::warn!("GDB stub unavailable: {:?}", e);
+        }
+    }
+    if let Err(e) = hardware::init_apic() {
+        log::error!("APIC unavailable, using the 8259 PICs: {:?}", e);
+    }
+    if let Err(e) = serial::enable_console_interrupts() {
+        log::error!("Serial console input unavailable: {:?}", e);
+    }
+    if let Err(e) = rtc::init_interrupts() {
+        log::warn!("RTC interrupts unavailable: {:?}", e);
+    }
+    hardware::init_clock();
+    if let Err(e) = hardware::init_apic_timer() {
+        log::error!("Local APIC timer unavailable, timers will be polled: {:?}", e);
+    }
+    timer::init();
+    if let (Some(name), Some(data)) = (&config.timezone, &boot_info.timezone) {
+        match tz::Timezone::parse(name, data) {
+            Ok(timezone) => tz::set_timezone(timezone),
+            Err(e) => log::error!("Invalid timezone {} ({:?}), using UTC.", name, e),
+        }
+    }
+
+    if let Some(fb) = &boot_info.framebuffer {
+        log::info!("Framebuffer: {}x{} stride {} at {:p} ({} bytes)",
+            fb.width, fb.height, fb.stride, fb.base, fb.size
+        );
+        let font = match &boot_info.console_font {
+            Some(data) => match font::Font::parse_psf(data) {
+                Ok(font) if font.width <= fb.width && font.height <= fb.height => font,
+                Ok(font) => {
+                    log::error!("Console font {}x{} does not fit the screen, using built-in font.",
+                        font.width, font.height
+                    );
+                    font::Font::builtin()
+                }
+                Err(e) => {
+                    log::error!("Invalid console font ({:?}), using built-in font.", e);
+                    font::Font::builtin()
+                }
+            },
+            None => font::Font::builtin(),
+        };
+        console::init(*fb, font, config.scrollback_lines);
+        print_welcome_message();
+    }
+    if let Err(e) = keyboard::init(config.keyboard_layout).and_then(|()| keyboard::enable_interrupts()) {
+        log::warn!("PS/2 keyboard unavailable: {:?}", e);
+    }
+    shell::init();
+
+    // Everything is routed; the kernel loop sleeps until an interrupt.
+    interrupts::enable();
 }
 
 #[entry]
 fn efi_main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
-    // Initialize logger first
-    if let Err(_e) = uefi_services::init(&mut system_table) {
+    // Initialize the heap first; nothing may allocate before it exists
+    if let Err(_e) = heap::init(system_table.boot_services()) {
         // Use Output::output_string for CStr16, ignoring result for this emergency print.
-        let _ = system_table.stdout().output_string(cstr16!("Error: Failed to initialize uefi_services (logger).\r\n"));
+        let _ = system_table.stdout().output_string(cstr16!("Error: Failed to allocate the kernel heap.\r\n"));
         // Loop indefinitely as we can't rely on logging or proper panic handling here.
         loop { system_table.boot_services().stall(1_000_000); }
     }
-    log::set_max_level(log::LevelFilter::Info);
+    logger::init();
 
     log::info!("OptiOS UEFI Bootloader Initializing...");
     log::info!("Image Handle: {:?}", _image_handle);
@@ -131,86 +254,65 @@ fn efi_main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Statu
     );
 
     let bt = system_table.boot_services();
+    let config = config::load(bt, _image_handle);
+    logger::set_filter(config.log_filter.clone());
+    let (max_width, max_height) = (config.max_width, config.max_height);
+
     log::info!("Attempting to initialize GOP and set mode...");
+    let framebuffer = video::init(bt, &config.video_mode, max_width, max_height);
 
-    match bt.get_handle_for_protocol::<GraphicsOutput>() {
-        Ok(gop_handle) => {
-            log::info!("GOP Handle acquired: {:?}", gop_handle);
-            match bt.open_protocol_exclusive::<GraphicsOutput>(gop_handle) {
-                Ok(mut gop) => { 
-                    log::info!("GOP Protocol opened exclusively. Iterating modes...");
-                    
-                    let mut best_mode_idx = None::<u32>;
-                    let mut best_width = 0;
-                    let mut best_height = 0;
-
-                    for (i, mode_object) in gop.modes().enumerate() {
-                        // Assuming mode_object is of type uefi::proto::console::gop::Mode directly
-                        let mode_info = mode_object.info();
-                        let (w, h) = mode_info.resolution();
-                        log::info!("Mode {}: {}x{} Format: {:?}", i, w, h, mode_info.pixel_format());
-
-                        if mode_info.pixel_format() == PixelFormat::Rgb || mode_info.pixel_format() == PixelFormat::Bgr {
-                            if (w <= MAX_WIDTH && h <= MAX_HEIGHT) && (w >= best_width && h >= best_height) {
-                                if w > best_width || h > best_height || best_mode_idx.is_none() {
-                                    best_mode_idx = Some(i as u32);
-                                    best_width = w;
-                                    best_height = h;
-                                }
-                            }
-                        }
-                        // Note: If individual mode fetching could fail and gop.modes()
-                        // originally returned Result, this change bypasses that error handling.
-                    }
-
-                    if let Some(selected_idx) = best_mode_idx {
-                        log::info!("Selected Mode {}: {}x{}", selected_idx, best_width, best_height);
-                        match gop.query_mode(selected_idx) {
-                            Ok(selected_mode_object) => { 
-                                if let Err(e) = gop.set_mode(&selected_mode_object) { 
-                                    log::error!("Failed to set graphics mode {}: {:?}", selected_idx, e);
-                                } else {
-                                    let current_mode_info = gop.current_mode_info();
-                                    let mut frame_buffer = gop.frame_buffer();
-                                    let stride = current_mode_info.stride();
-                                    let (width, height) = current_mode_info.resolution();
-                                    
-                                    let mut display = UefiDisplay::new(
-                                        frame_buffer.as_mut_ptr(),
-                                        stride as u32,
-                                        (width as u32, height as u32),
-                                        &gop 
-                                    );
-                                    let _ = print_welcome_message(&mut display);
-                                }
-                            }
-                            Err(e) => {
-                                log::error!("Failed to query selected mode object {}: {:?}", selected_idx, e);
-                            }
-                        }
-                    } else {
-                        log::error!("No suitable RGB/BGR graphics mode found within bounds.");
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to open GOP protocol (exclusive): {:?}", e);
-                }
-            }
+    let console_font = config.console_font.as_deref().and_then(|path| {
+        config::read_esp_path(bt, _image_handle, path)
+            .map_err(|e| log::error!("Failed to read console font {}: {:?}", path, e))
+            .ok()
+    });
+
+    let timezone = config.timezone.as_deref().and_then(|name| {
+        config::read_esp_path(bt, _image_handle, &format!("zoneinfo/{}", name))
+            .map_err(|e| log::error!("Failed to read timezone {}: {:?}", name, e))
+            .ok()
+    });
+
+    // Prefer the ACPI 2.0 RSDP, which also points at the 64-bit XSDT.
+    let rsdp = [cfg::ACPI2_GUID, cfg::ACPI_GUID].iter().find_map(|guid| {
+        system_table
+            .config_table()
+            .iter()
+            .find(|entry| entry.guid == *guid)
+            .map(|entry| entry.address as u64)
+    });
+
+    let boot_info = BootInfo { config, framebuffer, console_font, timezone, rsdp };
+    // From here on the machine is ours; this also stops the firmware watchdog.
+    runtime::exit_boot_services(system_table);
+    kernel_main(&boot_info);
+
+    // Run timers, events and foreground processes until a handler asks for a
+    // shutdown or reboot, sleeping whenever there is nothing to do.
+    let action = loop {
+        timer::run_expired();
+        let held_back = event_loop::dispatch_pending();
+        if let Some(action) = power::requested() {
+            break action;
         }
-        Err(e) => {
-            log::error!("Failed to get GOP handle: {:?}", e);
+        // Foreground processes get the CPU in turn before the kernel sleeps.
+        if loader::run_foreground() {
+            continue;
         }
-    }
-    
-    // This log might not appear if console is redirected after set_mode
-    // log::info!("OptiOS halting after graphics demo."); 
-    system_table.boot_services().stall(20_000_000); // Stall for 20 seconds to see the result
-    Status::SUCCESS
+        // Background work held back for the foreground waits for its turn
+        // rather than keeping the CPU busy.
+        if held_back.is_some() || event_loop::with_event_loop(|event_loop| event_loop.pending_events()) == 0 {
+            timer::idle(held_back);
+        }
+    };
+    power::shutdown(action)
 }
 
-// Custom panic handler and halt_loop were removed as uefi-services provides them.
-// eh_personality lang item also removed, assuming uefi-services or another dep provides it.
-
-// Try removing eh_personality as uefi-services might provide it.
-// If the build fails asking for it, we can re-add it or the lang_items feature.
-// #[lang = "eh_personality"] extern "C" fn eh_personality() {} 
\ No newline at end of file
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    log::error!("{}", info);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
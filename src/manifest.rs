@@ -0,0 +1,110 @@
+// Module for program manifests: the permissions a program declares
+//
+// A manifest is the only source of authority a program has. The event loop
+// refuses subscriptions to events the manifest does not list, and output
+// capabilities are checked before a program may cause the matching effect.
+// Handlers run in the background and processes in the foreground, so each is
+// refused unless the manifest's execution context allows it.
+
+use crate::event_loop::EventSet;
+
+/// Kinds of effects a program may produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Write to the framebuffer console.
+    ConsoleOutput,
+    /// Write to the serial console.
+    SerialOutput,
+    /// Reboot or power off the machine.
+    Power,
+    /// Set the system clock.
+    SetSystemTime,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 4] = [
+        Capability::ConsoleOutput,
+        Capability::SerialOutput,
+        Capability::Power,
+        Capability::SetSystemTime,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::ConsoleOutput => "console-output",
+            Capability::SerialOutput => "serial-output",
+            Capability::Power => "power",
+            Capability::SetSystemTime => "set-system-time",
+        }
+    }
+
+    fn bit(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+/// The set of output capabilities granted to a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CapabilitySet(u32);
+
+impl CapabilitySet {
+    pub const fn empty() -> CapabilitySet {
+        CapabilitySet(0)
+    }
+
+    /// Returns the set with `capability` added.
+    pub const fn with(self, capability: Capability) -> CapabilitySet {
+        CapabilitySet(self.0 | 1 << capability as u32)
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+}
+
+/// Where a program is allowed to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionContext {
+    Foreground,
+    Background,
+    Both,
+}
+
+impl ExecutionContext {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExecutionContext::Foreground => "foreground",
+            ExecutionContext::Background => "background",
+            ExecutionContext::Both => "both",
+        }
+    }
+
+    /// The program may run as a foreground process.
+    pub fn allows_foreground(&self) -> bool {
+        matches!(self, ExecutionContext::Foreground | ExecutionContext::Both)
+    }
+
+    /// The program may handle events in the background.
+    pub fn allows_background(&self) -> bool {
+        matches!(self, ExecutionContext::Background | ExecutionContext::Both)
+    }
+}
+
+/// The permissions and resources a program declares up front.
+#[derive(Debug)]
+pub struct Manifest {
+    pub name: &'static str,
+    /// Changing the version resets the program's snapshot.
+    pub version: u32,
+    pub allowed_events: EventSet,
+    pub capabilities: CapabilitySet,
+    pub contexts: ExecutionContext,
+    /// Bytes of global state kept in the program's memory snapshot.
+    pub snapshot_size: usize,
+}
+
+impl Manifest {
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
@@ -1 +1,69 @@
-// Placeholder for memory management logic 
\ No newline at end of file
+// Module for physical memory accounting
+//
+// The memory map returned when boot services exit is final, so it is
+// summarized once and kept.
+
+use spin::Once;
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Physical memory by use, in 4 KiB pages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub total_pages: u64,
+    /// Free memory available to the kernel.
+    pub free_pages: u64,
+    /// The kernel image and its heap.
+    pub kernel_pages: u64,
+    /// Firmware memory that becomes free after exiting boot services.
+    pub boot_services_pages: u64,
+    /// Firmware memory that must stay mapped for runtime services.
+    pub runtime_pages: u64,
+    /// ACPI tables and non-volatile storage.
+    pub acpi_pages: u64,
+    /// Reserved, unusable or MMIO ranges.
+    pub reserved_pages: u64,
+}
+
+impl MemoryStats {
+    fn add(&mut self, descriptor: &MemoryDescriptor) {
+        let pages = descriptor.page_count;
+        self.total_pages += pages;
+        let bucket = match descriptor.ty {
+            MemoryType::CONVENTIONAL => &mut self.free_pages,
+            MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => &mut self.kernel_pages,
+            MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => {
+                &mut self.boot_services_pages
+            }
+            MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => {
+                &mut self.runtime_pages
+            }
+            MemoryType::ACPI_RECLAIM | MemoryType::ACPI_NON_VOLATILE => &mut self.acpi_pages,
+            _ => &mut self.reserved_pages,
+        };
+        *bucket += pages;
+    }
+}
+
+/// Summary of the memory map handed over when boot services exited.
+static FINAL_STATS: Once<MemoryStats> = Once::new();
+
+fn summarize<'a>(descriptors: impl Iterator<Item = &'a MemoryDescriptor>) -> MemoryStats {
+    let mut stats = MemoryStats::default();
+    for descriptor in descriptors {
+        stats.add(descriptor);
+    }
+    stats
+}
+
+/// Keeps a summary of the memory map returned by ExitBootServices.
+pub fn record_final_map<'a>(descriptors: impl Iterator<Item = &'a MemoryDescriptor>) {
+    FINAL_STATS.call_once(|| summarize(descriptors));
+}
+
+/// Physical memory by use, as handed over by the firmware. None before boot
+/// services have exited.
+pub fn stats() -> Option<MemoryStats> {
+    FINAL_STATS.r#try().copied()
+}
@@ -0,0 +1,241 @@
+// Module for rebooting and powering off the machine
+//
+// A shutdown first emits `system-shutdown` so handlers can flush their
+// snapshots, then gives them a bounded grace period before the machine is
+// switched off. Power-off enters ACPI S5 through the FADT PM1 control
+// registers; reboot uses the FADT reset register and then the keyboard
+// controller. The firmware's ResetSystem service is the last resort for both.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use uefi::table::runtime::ResetType;
+use uefi::Status;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::event_loop::{self, EventKind, EventPayload};
+use crate::hardware::{self, Fadt, SleepState, BOOT_ARCH_8042};
+use crate::runtime;
+
+// 8042 keyboard controller
+const KBC_STATUS_PORT: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 0x02;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+// PM1 control register bits
+const PM1_SCI_EN: u64 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u64 = 10;
+const PM1_SLP_TYP_MASK: u64 = 0x7 << PM1_SLP_TYP_SHIFT;
+const PM1_SLP_EN: u64 = 1 << 13;
+
+/// Polls of SCI_EN after asking the firmware to switch to ACPI mode.
+const ACPI_ENABLE_POLLS: usize = 100_000;
+
+/// Polls of the 8042 input buffer before giving up on a reset pulse. With no
+/// controller the status port reads 0xFF and never drains.
+const KBC_INPUT_POLLS: usize = 100_000;
+
+/// Port 0x80 writes, about a microsecond each, to wait for the chipset to
+/// act on a reset or sleep request before trying the next method.
+const SETTLE_IO_WAITS: usize = 100_000;
+
+/// Default time handlers get to react to `system-shutdown`.
+pub const DEFAULT_GRACE_PERIOD_SECS: u64 = 5;
+
+/// What the machine does once handlers have been notified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownAction {
+    PowerOff,
+    Reboot,
+}
+
+/// Reasons a power transition through ACPI did not happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// No FADT was found by `hardware::init_acpi`.
+    NoFadt,
+    /// The DSDT has no package for the requested sleep state.
+    NoSleepType(SleepState),
+    /// The firmware did not hand power management over to the OS.
+    AcpiModeTimeout,
+    /// A register is in an address space we cannot access.
+    UnsupportedRegister,
+    /// The register was written but the machine kept running.
+    NoEffect,
+}
+
+static GRACE_PERIOD_SECS: AtomicU64 = AtomicU64::new(DEFAULT_GRACE_PERIOD_SECS);
+
+/// Set by `request`, picked up by the kernel's main loop.
+static REQUESTED: Mutex<Option<ShutdownAction>> = Mutex::new(None);
+
+/// Sets how long handlers get to react to `system-shutdown`.
+pub fn set_grace_period(secs: u64) {
+    GRACE_PERIOD_SECS.store(secs, Ordering::Relaxed);
+}
+
+/// Asks the kernel to shut down once the current handler returns. Handlers
+/// use this rather than `shutdown`, which would run handlers re-entrantly.
+pub fn request(action: ShutdownAction) {
+    cpu_interrupts::without_interrupts(|| *REQUESTED.lock() = Some(action));
+}
+
+/// The shutdown asked for with `request`, if any.
+pub fn requested() -> Option<ShutdownAction> {
+    cpu_interrupts::without_interrupts(|| *REQUESTED.lock())
+}
+
+/// Notifies handlers, waits for them up to the grace period, then powers
+/// off or reboots.
+pub fn shutdown(action: ShutdownAction) -> ! {
+    match action {
+        ShutdownAction::PowerOff => log::info!("Shutting down..."),
+        ShutdownAction::Reboot => log::info!("Rebooting..."),
+    }
+    notify_handlers(action);
+    match action {
+        ShutdownAction::PowerOff => power_off(),
+        ShutdownAction::Reboot => reset(),
+    }
+}
+
+/// Emits `system-shutdown` and runs handlers until every queue is empty or
+/// the grace period ends. A handler that is already running is not cut off.
+fn notify_handlers(action: ShutdownAction) {
+    let payload = EventPayload::Shutdown {
+        reboot: action == ShutdownAction::Reboot,
+    };
+    if let Err(e) = event_loop::emit(EventKind::SystemShutdown, payload) {
+        log::warn!("Could not notify handlers of shutdown: {:?}", e);
+    }
+
+    let grace = Duration::from_secs(GRACE_PERIOD_SECS.load(Ordering::Relaxed));
+    let deadline = event_loop::now() + grace;
+    while event_loop::now() < deadline && event_loop::dispatch_one() {}
+
+    let pending = event_loop::with_event_loop(|event_loop| event_loop.pending_events());
+    if pending > 0 {
+        log::warn!("Shutdown grace period expired with {} events undelivered", pending);
+    }
+}
+
+// --- Power Off ---
+
+/// Switches the machine off through ACPI S5, or the firmware if that fails.
+pub fn power_off() -> ! {
+    if let Err(e) = enter_sleep_state(SleepState::S5) {
+        log::warn!("ACPI power-off failed: {:?}", e);
+    }
+    firmware_reset(ResetType::Shutdown)
+}
+
+/// Writes the state's sleep type and SLP_EN to the PM1 control registers.
+/// Only returns if the machine did not enter the state.
+fn enter_sleep_state(state: SleepState) -> Result<(), PowerError> {
+    let fadt = hardware::acpi_tables().and_then(|tables| tables.fadt).ok_or(PowerError::NoFadt)?;
+    let sleep_type = hardware::sleep_type(state).ok_or(PowerError::NoSleepType(state))?;
+    enable_acpi_mode(&fadt)?;
+
+    cpu_interrupts::disable();
+    let registers = [(fadt.pm1a_control, sleep_type.a), (fadt.pm1b_control, sleep_type.b)];
+    for (register, slp_typ) in registers {
+        if !register.is_present() {
+            continue;
+        }
+        unsafe {
+            let value = register.read() & !PM1_SLP_TYP_MASK;
+            let value = value | (slp_typ as u64) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN;
+            if !register.write(value) {
+                return Err(PowerError::UnsupportedRegister);
+            }
+        }
+    }
+    settle();
+    Err(PowerError::NoEffect)
+}
+
+/// Asks the firmware to hand power management to the OS, unless it has.
+fn enable_acpi_mode(fadt: &Fadt) -> Result<(), PowerError> {
+    let sci_enabled = || unsafe { fadt.pm1a_control.read() } & PM1_SCI_EN != 0;
+    // No SMI command port means the platform is always in ACPI mode.
+    if sci_enabled() || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Ok(());
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_POLLS {
+        if sci_enabled() {
+            return Ok(());
+        }
+        io_wait();
+    }
+    Err(PowerError::AcpiModeTimeout)
+}
+
+// --- Reboot ---
+
+/// Resets the machine through the FADT reset register, then the keyboard
+/// controller, then the firmware.
+pub fn reset() -> ! {
+    cpu_interrupts::disable();
+    let fadt = hardware::acpi_tables().and_then(|tables| tables.fadt);
+
+    if let Some(register) = fadt.and_then(|fadt| fadt.reset_register) {
+        if unsafe { register.write(fadt.map_or(0, |fadt| fadt.reset_value) as u64) } {
+            settle();
+        }
+        log::warn!("ACPI reset register had no effect");
+    }
+
+    // Without a FADT, assume a PC-compatible keyboard controller.
+    if fadt.is_none_or(|fadt| fadt.boot_architecture & BOOT_ARCH_8042 != 0) {
+        if pulse_keyboard_controller_reset() {
+            settle();
+            log::warn!("Keyboard controller reset had no effect");
+        } else {
+            log::warn!("Keyboard controller not responding");
+        }
+    }
+    firmware_reset(ResetType::Cold)
+}
+
+/// Pulses the reset line through the 8042. Returns false if its input buffer
+/// never drained, so no command was sent.
+fn pulse_keyboard_controller_reset() -> bool {
+    let mut port: Port<u8> = Port::new(KBC_STATUS_PORT);
+    for _ in 0..KBC_INPUT_POLLS {
+        if unsafe { port.read() } & KBC_STATUS_INPUT_FULL == 0 {
+            unsafe { port.write(KBC_PULSE_RESET) };
+            return true;
+        }
+        io_wait();
+    }
+    false
+}
+
+/// Resets or powers off through the firmware's ResetSystem runtime service,
+/// or halts if runtime services are unavailable.
+fn firmware_reset(reset_type: ResetType) -> ! {
+    cpu_interrupts::disable();
+    if let Some(rt) = runtime::services() {
+        // Safety: the runtime table stays valid for as long as the machine runs.
+        unsafe { rt.as_ref() }.reset(reset_type, Status::SUCCESS, None)
+    }
+    log::error!("Firmware reset unavailable; halting");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// --- Delays ---
+
+/// Waits roughly a microsecond by writing to the unused POST code port.
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
+
+fn settle() {
+    for _ in 0..SETTLE_IO_WAITS {
+        io_wait();
+    }
+}
@@ -1,12 +1,35 @@
+// Module for the CMOS real-time clock
+//
+// Besides reading the date and time, the RTC raises IRQ8 when its alarm
+// matches and, optionally, at the end of every once-a-second update. These
+// are delivered as `rtc-alarm` and `rtc-update` events. Reading the time can
+// wait out an update, so the IRQ handler only latches the Status C flags and
+// the events are emitted from a timer in the kernel loop. Alarms are given in
+// UTC and written in the firmware's local time, which is what it keeps in the
+// RTC.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
 use x86_64::instructions::port::Port;
 
+use crate::event_loop::{self, EventKind, EventPayload};
+use crate::hardware::{self, Instant, BOOT_ARCH_CMOS_RTC_NOT_PRESENT};
+use crate::interrupts::{self, IrqError, IRQ_RTC};
+use crate::time;
+use crate::timer::{self, TimerAction};
+
 const RTC_ADDRESS_PORT: u16 = 0x70;
 const RTC_DATA_PORT: u16 = 0x71;
 
 // RTC Registers
 const RTC_SECONDS: u8 = 0x00;
+const RTC_SECONDS_ALARM: u8 = 0x01;
 const RTC_MINUTES: u8 = 0x02;
+const RTC_MINUTES_ALARM: u8 = 0x03;
 const RTC_HOURS: u8 = 0x04;
+const RTC_HOURS_ALARM: u8 = 0x05;
 const RTC_DAY_OF_WEEK: u8 = 0x06; // Sunday = 1, ...
 const RTC_DAY_OF_MONTH: u8 = 0x07;
 const RTC_MONTH: u8 = 0x08;
@@ -14,6 +37,7 @@ const RTC_YEAR: u8 = 0x09;
 const RTC_CENTURY: u8 = 0x32; // Optional, depends on RTC
 const RTC_STATUS_A: u8 = 0x0A;
 const RTC_STATUS_B: u8 = 0x0B;
+const RTC_STATUS_C: u8 = 0x0C;
 
 // Status Register A Flags
 const RTC_UIP_FLAG: u8 = 0x80; // Update In Progress
@@ -21,8 +45,41 @@ const RTC_UIP_FLAG: u8 = 0x80; // Update In Progress
 // Status Register B Flags
 const RTC_FORMAT_BINARY: u8 = 0x04; // Data in binary format (if set)
 const RTC_FORMAT_24HOUR: u8 = 0x02; // 24-hour mode (if set)
+const RTC_UPDATE_INT_ENABLE: u8 = 0x10;
+const RTC_SET: u8 = 0x80; // Inhibits updates while the time is written
+const RTC_ALARM_INT_ENABLE: u8 = 0x20;
 
-#[derive(Debug, Clone, Copy)]
+// Status Register C Flags (reading the register acknowledges the interrupt)
+const RTC_UPDATE_INT_FLAG: u8 = 0x10;
+const RTC_ALARM_INT_FLAG: u8 = 0x20;
+
+/// Bit 7 of the hour register marks PM in 12-hour mode.
+const RTC_HOUR_PM: u8 = 0x80;
+
+/// Status A polls before deciding the update-in-progress flag is stuck.
+const MAX_UPDATE_WAIT_POLLS: usize = 1_000_000;
+/// Passes over the registers before giving up on two matching reads.
+const MAX_READ_ATTEMPTS: usize = 8;
+
+/// Errors returned by the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// The update-in-progress flag never cleared; there is no working RTC.
+    NotPresent,
+    /// Consecutive reads kept disagreeing.
+    Unstable,
+    /// The registers hold a value that is not a valid date and time.
+    InvalidDateTime,
+    /// RTC interrupts need the kernel IDT, see `interrupts::init`.
+    InterruptsInactive,
+    /// IRQ8 could not be claimed.
+    Irq(IrqError),
+    /// The alarm time has already passed.
+    AlarmInPast,
+}
+
+/// Fields are in order of significance, so comparisons are chronological.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DateTime {
     pub year: u16,
     pub month: u8,
@@ -42,89 +99,456 @@ fn read_rtc_register(reg: u8) -> u8 {
     }
 }
 
-fn wait_for_rtc_update_done() {
-    // Wait until UIP bit (Update In Progress) in Status Register A is clear
-    while (read_rtc_register(RTC_STATUS_A) & RTC_UIP_FLAG) != 0 {
-        // Spin wait
+fn write_rtc_register(reg: u8, value: u8) {
+    let mut addr_port = Port::new(RTC_ADDRESS_PORT);
+    let mut data_port = Port::new(RTC_DATA_PORT);
+
+    unsafe {
+        addr_port.write(reg);
+        data_port.write(value);
+    }
+}
+
+fn wait_for_rtc_update_done() -> Result<(), RtcError> {
+    // Wait until UIP bit (Update In Progress) in Status Register A is clear.
+    // An update takes under 2 ms; a bit that never clears means no RTC.
+    for _ in 0..MAX_UPDATE_WAIT_POLLS {
+        if (read_rtc_register(RTC_STATUS_A) & RTC_UIP_FLAG) == 0 {
+            return Ok(());
+        }
         core::hint::spin_loop();
     }
+    Err(RtcError::NotPresent)
 }
 
 fn bcd_to_binary(bcd_val: u8) -> u8 {
     (bcd_val & 0x0F) + ((bcd_val >> 4) * 10)
 }
 
-pub fn get_datetime() -> DateTime {
-    wait_for_rtc_update_done();
-
-    let mut second = read_rtc_register(RTC_SECONDS);
-    let mut minute = read_rtc_register(RTC_MINUTES);
-    let mut hour = read_rtc_register(RTC_HOURS);
-    let mut day = read_rtc_register(RTC_DAY_OF_MONTH);
-    let mut month = read_rtc_register(RTC_MONTH);
-    let mut year = read_rtc_register(RTC_YEAR);
-    // It's common to read century separately if RTC supports it, e.g. ACPI PM Timer
-    // For standard CMOS RTC, year is 00-99. Assume 20xx for now.
-    // let century = read_rtc_register(RTC_CENTURY); // Needs BCD conversion too
-
-    let status_b = read_rtc_register(RTC_STATUS_B);
-    let is_binary_mode = (status_b & RTC_FORMAT_BINARY) != 0;
-    let is_24_hour_mode = (status_b & RTC_FORMAT_24HOUR) != 0;
-
-    if !is_binary_mode {
-        second = bcd_to_binary(second);
-        minute = bcd_to_binary(minute);
-        // Hour BCD conversion needs to preserve AM/PM bit if 12-hour mode
-        let hour_val = read_rtc_register(RTC_HOURS); // Reread for AM/PM bit
-        hour = bcd_to_binary(hour_val & 0x7F); // Mask out AM/PM bit for conversion
-        day = bcd_to_binary(day);
-        month = bcd_to_binary(month);
-        year = bcd_to_binary(year);
-        // year_full = bcd_to_binary(century) * 100 + bcd_to_binary(year);
-    }
-
-    // Handle 12-hour to 24-hour conversion
-    // This must be done AFTER BCD conversion if hour was in BCD
-    if !is_24_hour_mode {
-        let am_pm_bit_set = (read_rtc_register(RTC_HOURS) & 0x80) != 0; // Read original hour for AM/PM if BCD
-                                                                  // or use the already binary 'hour' if not BCD.
-        // If it was BCD, the 'hour' variable here is already 1-12 (binary).
-        // If it was binary, it might be 1-12 (12hr) or 0-23 (24hr).
-        // The RTC_HOURS register gives PM status if bit 7 is set (only in 12hr mode)
-        
-        let is_pm = if is_binary_mode {
-            // In binary 12-hour mode, hour register directly has PM bit if RTC supports it.
-            // However, many RTCs just give 1-12 and AM/PM is separate or implied.
-            // The check `(read_rtc_register(RTC_HOURS) & 0x80) != 0` is more standard for BCD 12hr.
-            // For simplicity, let's assume if not 24hr and binary, it is 1-12 for hour and we need to infer AM/PM if possible
-            // or rely on a typical configuration (e.g. some systems might just give 1-12 and you don't get AM/PM bit separately in binary mode).
-            // The original code for BCD handling of AM/PM bit is safer:
-            (read_rtc_register(RTC_HOURS) & 0x80) != 0 
-        } else {
-            // If BCD, we used `hour_val` before which was the raw BCD hour read.
-            (read_rtc_register(RTC_HOURS) & 0x80) != 0
-        };
-
-        if hour == 12 { // Special handling for 12 AM (midnight) and 12 PM (noon)
-            if !is_pm { // 12 AM
-                hour = 0;
+fn binary_to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Encodes a value for the RTC according to the Status B data mode.
+fn encode(value: u8, status_b: u8) -> u8 {
+    if status_b & RTC_FORMAT_BINARY != 0 {
+        value
+    } else {
+        binary_to_bcd(value)
+    }
+}
+
+/// Encodes a 0-23 hour, converting to 12-hour form with the PM bit when
+/// Status B selects it.
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    if status_b & RTC_FORMAT_24HOUR != 0 {
+        return encode(hour, status_b);
+    }
+    let twelve_hour = match hour % 12 {
+        0 => 12,
+        h => h,
+    };
+    let pm = if hour >= 12 { RTC_HOUR_PM } else { 0 };
+    encode(twelve_hour, status_b) | pm
+}
+
+/// Raw register values from one pass over the clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(century_register: Option<u8>) -> RawTime {
+    RawTime {
+        second: read_rtc_register(RTC_SECONDS),
+        minute: read_rtc_register(RTC_MINUTES),
+        hour: read_rtc_register(RTC_HOURS),
+        day: read_rtc_register(RTC_DAY_OF_MONTH),
+        month: read_rtc_register(RTC_MONTH),
+        year: read_rtc_register(RTC_YEAR),
+        century: century_register.map_or(0, read_rtc_register),
+    }
+}
+
+/// Whether the FADT says the machine has no CMOS RTC.
+fn cmos_absent() -> bool {
+    hardware::acpi_tables()
+        .and_then(|tables| tables.fadt)
+        .is_some_and(|fadt| fadt.boot_architecture & BOOT_ARCH_CMOS_RTC_NOT_PRESENT != 0)
+}
+
+/// CMOS index of the century register. ACPI machines report it in the FADT
+/// (0 meaning there is none); older machines conventionally use 0x32.
+fn century_register() -> Option<u8> {
+    match hardware::acpi_tables() {
+        Some(tables) => tables.fadt.map(|fadt| fadt.century).filter(|&index| index != 0),
+        None => Some(RTC_CENTURY),
+    }
+}
+
+/// Decodes a register per the Status B data mode, rejecting invalid BCD.
+fn decode(value: u8, status_b: u8) -> Result<u8, RtcError> {
+    if status_b & RTC_FORMAT_BINARY != 0 {
+        return Ok(value);
+    }
+    if value & 0x0F > 9 || value >> 4 > 9 {
+        return Err(RtcError::InvalidDateTime);
+    }
+    Ok(bcd_to_binary(value))
+}
+
+/// Decodes the hour register into 0-23, handling 12-hour mode's PM bit.
+fn decode_hour(value: u8, status_b: u8) -> Result<u8, RtcError> {
+    if status_b & RTC_FORMAT_24HOUR != 0 {
+        return decode(value, status_b);
+    }
+    let hour = decode(value & !RTC_HOUR_PM, status_b)?;
+    if !(1..=12).contains(&hour) {
+        return Err(RtcError::InvalidDateTime);
+    }
+    let pm = value & RTC_HOUR_PM != 0;
+    Ok(match (hour, pm) {
+        (12, false) => 0,
+        (12, true) => 12,
+        (hour, false) => hour,
+        (hour, true) => hour + 12,
+    })
+}
+
+/// Reads the date and time. The registers are read until two passes agree,
+/// so an update landing mid-read cannot produce a torn value.
+pub fn get_datetime() -> Result<DateTime, RtcError> {
+    if cmos_absent() {
+        return Err(RtcError::NotPresent);
+    }
+    let century_register = century_register();
+    let (raw, status_b) = cpu_interrupts::without_interrupts(|| {
+        for _ in 0..MAX_READ_ATTEMPTS {
+            wait_for_rtc_update_done()?;
+            let first = read_raw(century_register);
+            wait_for_rtc_update_done()?;
+            let second = read_raw(century_register);
+            if first == second {
+                return Ok((first, read_rtc_register(RTC_STATUS_B)));
+            }
+        }
+        Err(RtcError::Unstable)
+    })?;
+
+    let year = decode(raw.year, status_b)? as u16;
+    let century = match century_register {
+        Some(_) => decode(raw.century, status_b)? as u16,
+        None => 20, // Without a century register, assume 20xx.
+    };
+    let datetime = DateTime {
+        year: century * 100 + year,
+        month: decode(raw.month, status_b)?,
+        day: decode(raw.day, status_b)?,
+        hour: decode_hour(raw.hour, status_b)?,
+        minute: decode(raw.minute, status_b)?,
+        second: decode(raw.second, status_b)?,
+    };
+    if !datetime.is_valid() {
+        return Err(RtcError::InvalidDateTime);
+    }
+    Ok(datetime)
+}
+
+/// Writes the date and time in the format Status B selects. Updates are
+/// inhibited during the write so the clock cannot tick between registers.
+pub fn set_datetime(datetime: DateTime) -> Result<(), RtcError> {
+    if cmos_absent() {
+        return Err(RtcError::NotPresent);
+    }
+    let century_register = century_register();
+    // Without a century register only 20xx can be stored.
+    let representable = match century_register {
+        Some(_) => (1900..=2999).contains(&datetime.year),
+        None => (2000..=2099).contains(&datetime.year),
+    };
+    if !datetime.is_valid() || !representable {
+        return Err(RtcError::InvalidDateTime);
+    }
+
+    cpu_interrupts::without_interrupts(|| {
+        wait_for_rtc_update_done()?;
+        let status_b = read_rtc_register(RTC_STATUS_B);
+        write_rtc_register(RTC_STATUS_B, status_b | RTC_SET);
+
+        write_rtc_register(RTC_SECONDS, encode(datetime.second, status_b));
+        write_rtc_register(RTC_MINUTES, encode(datetime.minute, status_b));
+        write_rtc_register(RTC_HOURS, encode_hour(datetime.hour, status_b));
+        write_rtc_register(RTC_DAY_OF_WEEK, encode(datetime.weekday() as u8, status_b));
+        write_rtc_register(RTC_DAY_OF_MONTH, encode(datetime.day, status_b));
+        write_rtc_register(RTC_MONTH, encode(datetime.month, status_b));
+        write_rtc_register(RTC_YEAR, encode((datetime.year % 100) as u8, status_b));
+        if let Some(register) = century_register {
+            write_rtc_register(register, encode((datetime.year / 100) as u8, status_b));
+        }
+
+        write_rtc_register(RTC_STATUS_B, status_b & !RTC_SET);
+        Ok(())
+    })
+}
+
+// --- Calendar ---
+
+/// Days of the week, numbered as in the RTC's weekday register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weekday {
+    Sunday = 1,
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Sunday,
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Weekday::Sunday => "Sunday",
+            Weekday::Monday => "Monday",
+            Weekday::Tuesday => "Tuesday",
+            Weekday::Wednesday => "Wednesday",
+            Weekday::Thursday => "Thursday",
+            Weekday::Friday => "Friday",
+            Weekday::Saturday => "Saturday",
+        }
+    }
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+fn is_leap_year(year: u16) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month as i64 + 9) % 12; // March = 0
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date `days` after 1970-01-01, as (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// Whether every field is in range, including the day for its month.
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    pub fn weekday(&self) -> Weekday {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        // 1970-01-01 was a Thursday.
+        Weekday::ALL[(days + 4).rem_euclid(7) as usize]
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC, treating this time as UTC.
+    pub fn to_unix(self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        days * SECONDS_PER_DAY + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    /// The UTC time `timestamp` seconds after the Unix epoch, or `None` if
+    /// the year does not fit.
+    pub fn from_unix(timestamp: i64) -> Option<DateTime> {
+        let days = timestamp.div_euclid(SECONDS_PER_DAY);
+        let seconds = timestamp.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        Some(DateTime {
+            year: u16::try_from(year).ok()?,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        })
+    }
+}
+
+// --- Interrupts ---
+
+/// A pending alarm, in UTC and in the RTC's own time.
+#[derive(Debug, Clone, Copy)]
+struct Alarm {
+    utc: DateTime,
+    cmos: DateTime,
+}
+
+static ALARM: Mutex<Option<Alarm>> = Mutex::new(None);
+
+/// Whether `rtc-update` events are emitted each second.
+static UPDATE_EVENTS: AtomicBool = AtomicBool::new(false);
+
+fn set_interrupt_enable(bits: u8, enabled: bool) {
+    cpu_interrupts::without_interrupts(|| {
+        let status_b = read_rtc_register(RTC_STATUS_B);
+        let status_b = if enabled { status_b | bits } else { status_b & !bits };
+        write_rtc_register(RTC_STATUS_B, status_b);
+    });
+}
+
+/// Status C flags latched by the IRQ handler and not yet handled.
+static PENDING_FLAGS: AtomicU8 = AtomicU8::new(0);
+
+fn rtc_irq() {
+    // Reading Status C acknowledges the interrupt; without it IRQ8 never
+    // fires again.
+    let flags = read_rtc_register(RTC_STATUS_C) & (RTC_UPDATE_INT_FLAG | RTC_ALARM_INT_FLAG);
+    if flags != 0 && PENDING_FLAGS.fetch_or(flags, Ordering::SeqCst) == 0 {
+        timer::schedule(Instant::now(), Duration::ZERO, TimerAction::Call(handle_interrupt_flags));
+    }
+}
+
+/// Emits the events for the flags latched by `rtc_irq`.
+fn handle_interrupt_flags() {
+    let status_c = PENDING_FLAGS.swap(0, Ordering::SeqCst);
+
+    if status_c & RTC_UPDATE_INT_FLAG != 0 && UPDATE_EVENTS.load(Ordering::SeqCst) {
+        if let Ok(now) = get_datetime() {
+            let _ = event_loop::emit(EventKind::RtcUpdate, EventPayload::DateTime(now));
+        }
+    }
+
+    if status_c & RTC_ALARM_INT_FLAG != 0 {
+        // The hardware only matches the time of day (and the day of the month
+        // on some machines), so the alarm fires daily until the date is reached.
+        let now = get_datetime();
+        let due = cpu_interrupts::without_interrupts(|| {
+            let mut alarm = ALARM.lock();
+            match *alarm {
+                Some(target) if now.is_ok_and(|now| now >= target.cmos) => alarm.take(),
+                _ => None,
+            }
+        });
+        if let Some(Alarm { utc: target, .. }) = due {
+            set_interrupt_enable(RTC_ALARM_INT_ENABLE, false);
+            if event_loop::emit(EventKind::RtcAlarm, EventPayload::DateTime(target)).is_err() {
+                log::warn!("rtc-alarm event for {:?} was dropped", target);
             }
-            // if is_pm, it's 12 PM, hour remains 12. 
-        } else if is_pm {
-            hour = (hour + 12) % 24; // For 1 PM to 11 PM
         }
-        // If not PM and not 12, hour is already correct (1 AM to 11 AM)
     }
+}
 
-    // Assuming current century is 2000. Add 2000 to the two-digit year.
-    let current_year: u16 = 2000 + u16::from(year);
+/// Claims IRQ8 for the RTC. Requires `interrupts::init`.
+pub fn init_interrupts() -> Result<(), RtcError> {
+    if !interrupts::is_active() {
+        return Err(RtcError::InterruptsInactive);
+    }
+    if cmos_absent() {
+        return Err(RtcError::NotPresent);
+    }
+    interrupts::register_irq(IRQ_RTC, rtc_irq).map_err(RtcError::Irq)?;
+    // Clear any interrupt that was latched before we were listening.
+    read_rtc_register(RTC_STATUS_C);
+    Ok(())
+}
+
+/// Wakes the system with an `rtc-alarm` event at `when` (UTC), replacing
+/// any pending alarm. The CPU can stay halted until then.
+pub fn set_alarm(when: DateTime) -> Result<(), RtcError> {
+    if !interrupts::is_active() {
+        return Err(RtcError::InterruptsInactive);
+    }
+    if !when.is_valid() {
+        return Err(RtcError::InvalidDateTime);
+    }
+    // The registers hold whatever time the firmware keeps in the RTC, which
+    // is its local time when it records a timezone.
+    let offset = time::cmos_offset_minutes() as i64;
+    let cmos = DateTime::from_unix(when.to_unix() + offset * 60).ok_or(RtcError::InvalidDateTime)?;
+    if cmos <= get_datetime()? {
+        return Err(RtcError::AlarmInPast);
+    }
+    // The FADT gives the CMOS index of the day-of-month alarm, if any.
+    let day_alarm = hardware::acpi_tables()
+        .and_then(|tables| tables.fadt)
+        .map_or(0, |fadt| fadt.day_alarm);
+
+    cpu_interrupts::without_interrupts(|| {
+        *ALARM.lock() = Some(Alarm { utc: when, cmos });
+        let status_b = read_rtc_register(RTC_STATUS_B);
+        write_rtc_register(RTC_SECONDS_ALARM, encode(cmos.second, status_b));
+        write_rtc_register(RTC_MINUTES_ALARM, encode(cmos.minute, status_b));
+        write_rtc_register(RTC_HOURS_ALARM, encode_hour(cmos.hour, status_b));
+        if day_alarm != 0 {
+            write_rtc_register(day_alarm, encode(cmos.day, status_b));
+        }
+        write_rtc_register(RTC_STATUS_B, status_b | RTC_ALARM_INT_ENABLE);
+    });
+    Ok(())
+}
+
+/// The UTC time of the pending alarm, if any.
+pub fn pending_alarm() -> Option<DateTime> {
+    cpu_interrupts::without_interrupts(|| ALARM.lock().map(|alarm| alarm.utc))
+}
 
-    DateTime {
-        year: current_year,
-        month,
-        day,
-        hour,
-        minute,
-        second,
+/// Cancels the pending alarm, if any.
+pub fn cancel_alarm() {
+    cpu_interrupts::without_interrupts(|| {
+        *ALARM.lock() = None;
+        set_interrupt_enable(RTC_ALARM_INT_ENABLE, false);
+    });
+}
+
+/// Turns the once-a-second `rtc-update` event on or off.
+pub fn set_update_events(enabled: bool) -> Result<(), RtcError> {
+    if !interrupts::is_active() {
+        return Err(RtcError::InterruptsInactive);
     }
-} 
\ No newline at end of file
+    cpu_interrupts::without_interrupts(|| {
+        UPDATE_EVENTS.store(enabled, Ordering::SeqCst);
+        set_interrupt_enable(RTC_UPDATE_INT_ENABLE, enabled);
+    });
+    Ok(())
+}
@@ -0,0 +1,65 @@
+// Module for the hand-over from the firmware to the kernel
+//
+// Once the boot stage has read everything it needs, `exit_boot_services`
+// takes the machine from the firmware: its timers, drivers and the PS/2 and
+// serial handlers it may have installed stop running. Only the runtime
+// services remain. They are told through SetVirtualAddressMap that their
+// memory stays identity-mapped, since the kernel keeps the firmware's page
+// tables, and are then called through the table it returns.
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use spin::Once;
+use uefi::table::boot::{MemoryAttribute, MemoryDescriptor};
+use uefi::table::runtime::RuntimeServices;
+use uefi::table::{Boot, Runtime, SystemTable};
+
+use crate::memory;
+
+struct RuntimeTable(SystemTable<Runtime>);
+
+// Safety: the runtime services table is only read after the hand-over, and
+// its services are called with interrupts disabled by their users.
+unsafe impl Send for RuntimeTable {}
+unsafe impl Sync for RuntimeTable {}
+
+static RUNTIME: Once<RuntimeTable> = Once::new();
+
+/// Exits boot services and sets up runtime services for the kernel. The
+/// final memory map is kept for `memory::stats`.
+pub fn exit_boot_services(system_table: SystemTable<Boot>) {
+    log::info!("Exiting boot services");
+    // Resets the machine if the firmware refuses twice.
+    let (system_table, memory_map) = system_table.exit_boot_services();
+    memory::record_final_map(memory_map.entries());
+
+    let mut runtime_map: Vec<MemoryDescriptor> = memory_map
+        .entries()
+        .filter(|descriptor| descriptor.att.contains(MemoryAttribute::RUNTIME))
+        .map(|descriptor| MemoryDescriptor {
+            virt_start: descriptor.phys_start,
+            ..*descriptor
+        })
+        .collect();
+    let table_address = system_table.get_current_system_table_addr();
+    // Safety: every runtime region is mapped at its physical address, so the
+    // system table keeps its address too.
+    match unsafe { system_table.set_virtual_address_map(&mut runtime_map, table_address) } {
+        Ok(system_table) => {
+            log::info!("Runtime services mapped ({} regions)", runtime_map.len());
+            RUNTIME.call_once(|| RuntimeTable(system_table));
+        }
+        // The runtime services are undefined after a failed call, so
+        // `services` stays None and callers fall back to the hardware.
+        Err(e) => log::error!("SetVirtualAddressMap failed: {:?}", e.status()),
+    }
+}
+
+/// The firmware's runtime services, once boot services have exited. None if
+/// they could not be mapped.
+pub fn services() -> Option<NonNull<RuntimeServices>> {
+    // Safety: the table stays valid for as long as the machine runs.
+    RUNTIME
+        .r#try()
+        .map(|table| NonNull::from(unsafe { table.0.runtime_services() }))
+}
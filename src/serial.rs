@@ -1,74 +1,474 @@
-// Module for basic serial port interaction (COM1)
+// Module for 16550 UART serial ports (COM1-COM4)
+//
+// Ports start out polled. Once the kernel IDT is up, `enable_interrupts`
+// switches a port to buffered operation: received bytes are collected into a
+// ring buffer by the IRQ handler and writes are queued and drained as the
+// transmitter empties.
 
+use alloc::string::String;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
 use x86_64::instructions::port::Port;
 
-// Define the base address for the COM1 port
-const PORT_COM1_BASE: u16 = 0x3f8;
+use crate::event_loop::{self, EventKind, EventPayload};
+use crate::interrupts::{self, IrqError};
 
-// Define offsets for the COM1 registers
-const PORT_DATA_OFFSET: u16 = 0;        // Data register (read/write)
-const PORT_INT_ENABLE_OFFSET: u16 = 1;  // Interrupt Enable Register
-const PORT_FIFO_CTRL_OFFSET: u16 = 2;   // FIFO Control Register
+// Define the base addresses for the standard COM ports
+pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
+pub const COM3: u16 = 0x3e8;
+pub const COM4: u16 = 0x2e8;
+
+// Define offsets for the UART registers
+const PORT_DATA_OFFSET: u16 = 0;        // Data register (read/write), divisor lo with DLAB
+const PORT_INT_ENABLE_OFFSET: u16 = 1;  // Interrupt Enable Register, divisor hi with DLAB
+const PORT_FIFO_CTRL_OFFSET: u16 = 2;   // FIFO Control Register (write)
+const PORT_INT_IDENT_OFFSET: u16 = 2;   // Interrupt Identification Register (read)
 const PORT_LINE_CTRL_OFFSET: u16 = 3;   // Line Control Register
 const PORT_MODEM_CTRL_OFFSET: u16 = 4;  // Modem Control Register
 const PORT_LINE_STATUS_OFFSET: u16 = 5; // Line Status Register
+const PORT_MODEM_STATUS_OFFSET: u16 = 6; // Modem Status Register
+
+// Line Status Register flags
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+// Interrupt Enable Register flags
+const INT_ENABLE_RX_AVAILABLE: u8 = 0x01;
+const INT_ENABLE_TX_EMPTY: u8 = 0x02;
+
+// Interrupt Identification Register values (bits 1-3; bit 0 clear = pending)
+const INT_IDENT_NONE_PENDING: u8 = 0x01;
+const INT_IDENT_MODEM_STATUS: u8 = 0x00;
+const INT_IDENT_TX_EMPTY: u8 = 0x02;
+const INT_IDENT_RX_AVAILABLE: u8 = 0x04;
+const INT_IDENT_LINE_STATUS: u8 = 0x06;
+const INT_IDENT_RX_TIMEOUT: u8 = 0x0C;
+const INT_IDENT_MASK: u8 = 0x0E;
+
+/// Bytes the transmit FIFO accepts once it reports empty.
+const UART_FIFO_SIZE: usize = 16;
+
+/// Received bytes buffered before further input is dropped.
+pub const RX_BUFFER_SIZE: usize = 256;
+
+/// Queued output bytes before writers fall back to draining synchronously.
+pub const TX_BUFFER_SIZE: usize = 1024;
 
-/// Initializes the COM1 serial port.
-/// Configures it for 115200 baud, 8 data bits, no parity, 1 stop bit.
-pub fn initialize_port() {
-    // Disable interrupts
-    let mut int_enable_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_INT_ENABLE_OFFSET);
-    unsafe { int_enable_port.write(0x00); }
+/// Upper bound on interrupt causes serviced per IRQ, in case the UART
+/// keeps reporting one.
+const MAX_INTERRUPT_CAUSES: usize = 16;
 
-    // Enable DLAB (Divisor Latch Access Bit) to set baud rate
-    let mut line_ctrl_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_LINE_CTRL_OFFSET);
-    unsafe { line_ctrl_port.write(0x80); }
+/// The UART input clock divided by 16; the divisor for a baud rate is this
+/// value divided by the rate.
+const UART_BASE_BAUD: u32 = 115200;
 
-    // Set divisor to 3 (lo byte) for 38400 baud (115200 / 3 = 38400)
-    let mut divisor_lo_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_DATA_OFFSET); // DLAB on, this is divisor lo
-    unsafe { divisor_lo_port.write(0x03); }
-    let mut divisor_hi_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_INT_ENABLE_OFFSET); // DLAB on, this is divisor hi
-    unsafe { divisor_hi_port.write(0x00); }
+/// Byte written in loopback mode to check the chip is present.
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
 
-    // Set line control: 8 bits, no parity, one stop bit (8N1)
-    unsafe { line_ctrl_port.write(0x03); } // DLAB off
+/// Errors returned when setting up a serial port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// The baud rate is zero or does not divide the UART clock evenly.
+    InvalidBaudRate(u32),
+    /// The byte sent in loopback mode did not come back; no working UART at this port.
+    LoopbackFailed { base: u16 },
+    /// The port has not been set up, e.g. because `init_console` failed.
+    NotInitialized,
+    /// Interrupt mode needs the kernel IDT, see `interrupts::init`.
+    InterruptsInactive,
+    /// The port's IRQ line could not be claimed.
+    Irq(IrqError),
+}
 
-    // Initialize FIFO control register
-    let mut fifo_ctrl_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_FIFO_CTRL_OFFSET);
-    unsafe { fifo_ctrl_port.write(0xC7); }
+/// A fixed-size byte queue that never allocates, safe to use from IRQ handlers.
+pub(crate) struct ByteRing<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    len: usize,
+}
 
-    // Initialize modem control register
-    let mut modem_ctrl_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_MODEM_CTRL_OFFSET);
-    unsafe { modem_ctrl_port.write(0x0B); } // IRQs enabled, RTS/DSR set
+impl<const N: usize> ByteRing<N> {
+    pub(crate) const fn new() -> ByteRing<N> {
+        ByteRing {
+            data: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
 
-    // Set in loopback mode to test the serial chip
-    unsafe { modem_ctrl_port.write(0x1E); } 
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.len == N
+    }
 
-    // Test serial chip (send byte 0xAE and check if reads back)
-    let mut data_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_DATA_OFFSET);
-    unsafe { data_port.write(0xAE); }
-    unsafe {
-        if data_port.read() != 0xAE {
-            // TODO: Handle serial port initialization failure (e.g., panic or log)
-            // For now, we assume it works.
+    /// Appends a byte, returning false if the ring is full.
+    pub(crate) fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
         }
+        self.data[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
     }
 
-    // Set back to normal operation mode
-    unsafe { modem_ctrl_port.write(0x0F); }
+    pub(crate) fn peek(&self) -> Option<u8> {
+        (!self.is_empty()).then(|| self.data[self.head])
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
 }
 
-fn is_transmit_empty() -> bool {
-    let mut line_status_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_LINE_STATUS_OFFSET);
-    unsafe { (line_status_port.read() & 0x20) != 0 }
+/// A 16550-compatible UART, configured for 8 data bits, no parity, 1 stop bit.
+pub struct SerialPort {
+    base: u16,
+    baud_rate: u32,
+    /// Set once the port is driven by its IRQ rather than polled.
+    interrupt_driven: bool,
+    /// Shadow of the Interrupt Enable Register.
+    int_enable: u8,
+    rx: ByteRing<RX_BUFFER_SIZE>,
+    tx: ByteRing<TX_BUFFER_SIZE>,
+    /// Bytes received while the receive buffer was full.
+    rx_dropped: u64,
 }
 
-/// Writes a single byte to the serial port.
-pub fn write_byte(byte: u8) {
-    while !is_transmit_empty() {
-        // Spin wait for the transmitter to be empty
-        core::hint::spin_loop();
+impl SerialPort {
+    /// Creates a handle for the UART at `base`. Call `init` before use.
+    pub const fn new(base: u16, baud_rate: u32) -> SerialPort {
+        SerialPort {
+            base,
+            baud_rate,
+            interrupt_driven: false,
+            int_enable: 0,
+            rx: ByteRing::new(),
+            tx: ByteRing::new(),
+            rx_dropped: 0,
+        }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
+
+    /// The ISA IRQ line of the standard COM ports: IRQ4 for COM1/COM3,
+    /// IRQ3 for COM2/COM4.
+    pub fn irq(&self) -> u8 {
+        match self.base {
+            COM2 | COM4 => interrupts::IRQ_COM2,
+            _ => interrupts::IRQ_COM1,
+        }
+    }
+
+    pub fn is_interrupt_driven(&self) -> bool {
+        self.interrupt_driven
+    }
+
+    /// Bytes lost because the receive buffer was full.
+    pub fn rx_dropped(&self) -> u64 {
+        self.rx_dropped
+    }
+
+    fn port(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// Programs the baud rate and line settings and runs the loopback self-test.
+    pub fn init(&mut self) -> Result<(), SerialError> {
+        if self.baud_rate == 0 || !UART_BASE_BAUD.is_multiple_of(self.baud_rate) {
+            return Err(SerialError::InvalidBaudRate(self.baud_rate));
+        }
+        let divisor = (UART_BASE_BAUD / self.baud_rate) as u16;
+
+        let mut int_enable = self.port(PORT_INT_ENABLE_OFFSET);
+        let mut line_ctrl = self.port(PORT_LINE_CTRL_OFFSET);
+        let mut data = self.port(PORT_DATA_OFFSET);
+        let mut fifo_ctrl = self.port(PORT_FIFO_CTRL_OFFSET);
+        let mut modem_ctrl = self.port(PORT_MODEM_CTRL_OFFSET);
+
+        unsafe {
+            // Disable interrupts
+            int_enable.write(0x00);
+            self.int_enable = 0;
+            self.interrupt_driven = false;
+
+            // Enable DLAB (Divisor Latch Access Bit) to set the baud rate
+            line_ctrl.write(0x80);
+            data.write((divisor & 0xFF) as u8); // DLAB on, this is divisor lo
+            int_enable.write((divisor >> 8) as u8); // DLAB on, this is divisor hi
+
+            // Set line control: 8 bits, no parity, one stop bit (8N1), DLAB off
+            line_ctrl.write(0x03);
+
+            // Enable FIFO, clear them, with 14-byte threshold
+            fifo_ctrl.write(0xC7);
+
+            // Set in loopback mode to test the serial chip
+            let saved_modem_ctrl = modem_ctrl.read();
+            modem_ctrl.write(0x1E);
+            data.write(LOOPBACK_TEST_BYTE);
+            if data.read() != LOOPBACK_TEST_BYTE {
+                // Leave whatever is on the line as it was found
+                modem_ctrl.write(saved_modem_ctrl);
+                return Err(SerialError::LoopbackFailed { base: self.base });
+            }
+
+            // Set back to normal operation mode: DTR, RTS and OUT2 (IRQ line) set
+            modem_ctrl.write(0x0F);
+        }
+        Ok(())
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.port(PORT_LINE_STATUS_OFFSET).read() }
+    }
+
+    fn is_transmit_empty(&self) -> bool {
+        self.line_status() & LINE_STATUS_TRANSMIT_EMPTY != 0
+    }
+
+    fn set_int_enable(&mut self, value: u8) {
+        if self.int_enable != value {
+            self.int_enable = value;
+            unsafe { self.port(PORT_INT_ENABLE_OFFSET).write(value) };
+        }
+    }
+
+    /// Switches the port to interrupt-driven operation. The caller must route
+    /// the port's IRQ to `handle_interrupt`.
+    pub fn enable_interrupts(&mut self) {
+        self.interrupt_driven = true;
+        self.set_int_enable(INT_ENABLE_RX_AVAILABLE);
+    }
+
+    /// Busy-waits for the transmitter and sends one byte directly.
+    fn transmit_now(&mut self, byte: u8) {
+        while !self.is_transmit_empty() {
+            // Spin wait for the transmitter to be empty
+            core::hint::spin_loop();
+        }
+        unsafe { self.port(PORT_DATA_OFFSET).write(byte) };
     }
-    let mut data_port: Port<u8> = Port::new(PORT_COM1_BASE + PORT_DATA_OFFSET);
-    unsafe { data_port.write(byte); }
-} 
\ No newline at end of file
+
+    /// Writes a single byte. In interrupt mode the byte is queued and sent
+    /// from the IRQ handler; a full queue is drained synchronously, since the
+    /// caller may be running with interrupts disabled.
+    pub fn write_byte(&mut self, byte: u8) {
+        if !self.interrupt_driven {
+            self.transmit_now(byte);
+            return;
+        }
+        if self.tx.is_full() {
+            if let Some(oldest) = self.tx.pop() {
+                self.transmit_now(oldest);
+            }
+        }
+        self.tx.push(byte);
+        self.set_int_enable(self.int_enable | INT_ENABLE_TX_EMPTY);
+    }
+
+    /// Reads a byte if one has been received.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if self.interrupt_driven {
+            return self.rx.pop();
+        }
+        if self.line_status() & LINE_STATUS_DATA_READY == 0 {
+            return None;
+        }
+        Some(unsafe { self.port(PORT_DATA_OFFSET).read() })
+    }
+
+    /// Services every pending interrupt cause: fills the receive buffer and
+    /// refills the transmit FIFO from the queue.
+    pub fn handle_interrupt(&mut self) {
+        for _ in 0..MAX_INTERRUPT_CAUSES {
+            let ident = unsafe { self.port(PORT_INT_IDENT_OFFSET).read() };
+            if ident & INT_IDENT_NONE_PENDING != 0 {
+                break;
+            }
+            match ident & INT_IDENT_MASK {
+                INT_IDENT_RX_AVAILABLE | INT_IDENT_RX_TIMEOUT => self.receive(),
+                INT_IDENT_TX_EMPTY => self.transmit(),
+                INT_IDENT_LINE_STATUS => {
+                    self.line_status();
+                }
+                INT_IDENT_MODEM_STATUS => unsafe {
+                    self.port(PORT_MODEM_STATUS_OFFSET).read();
+                },
+                _ => break,
+            }
+        }
+    }
+
+    fn receive(&mut self) {
+        while self.line_status() & LINE_STATUS_DATA_READY != 0 {
+            let byte = unsafe { self.port(PORT_DATA_OFFSET).read() };
+            if !self.rx.push(byte) {
+                self.rx_dropped += 1;
+            }
+        }
+    }
+
+    fn transmit(&mut self) {
+        for _ in 0..UART_FIFO_SIZE {
+            match self.tx.pop() {
+                Some(byte) => unsafe { self.port(PORT_DATA_OFFSET).write(byte) },
+                None => break,
+            }
+        }
+        if self.tx.is_empty() {
+            self.set_int_enable(self.int_enable & !INT_ENABLE_TX_EMPTY);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    /// Writes text, translating `\n` to `\r\n` for terminals.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+// --- Global Console Port ---
+
+/// The port used for kernel log output, COM1 at 115200 baud.
+/// Holds `None` until `init_console` succeeds.
+pub static CONSOLE_PORT: Mutex<Option<SerialPort>> = Mutex::new(None);
+
+/// Initializes COM1 as the console port. Output is silently dropped if no
+/// UART answers the self-test.
+pub fn init_console() -> Result<(), SerialError> {
+    let mut port = SerialPort::new(COM1, 115200);
+    port.init()?;
+    *CONSOLE_PORT.lock() = Some(port);
+    Ok(())
+}
+
+/// Makes the console port interrupt-driven and starts delivering received
+/// bytes as `serial-input` events. Requires `interrupts::init`.
+pub fn enable_console_interrupts() -> Result<(), SerialError> {
+    if !interrupts::is_active() {
+        return Err(SerialError::InterruptsInactive);
+    }
+    cpu_interrupts::without_interrupts(|| {
+        let mut guard = CONSOLE_PORT.lock();
+        let port = guard.as_mut().ok_or(SerialError::NotInitialized)?;
+        interrupts::register_irq(port.irq(), console_irq).map_err(SerialError::Irq)?;
+        port.enable_interrupts();
+        Ok(())
+    })
+}
+
+fn console_irq() {
+    let base = match CONSOLE_PORT.lock().as_mut() {
+        Some(port) => {
+            port.handle_interrupt();
+            port.base()
+        }
+        None => return,
+    };
+    deliver_console_input(base);
+}
+
+/// Turns buffered console input into `serial-input` events. A byte that a
+/// full blocking subscription refuses stays buffered for the next interrupt.
+fn deliver_console_input(base: u16) {
+    loop {
+        let Some(byte) = CONSOLE_PORT.lock().as_ref().and_then(|port| port.rx.peek()) else {
+            return;
+        };
+        if event_loop::emit(EventKind::SerialInput, EventPayload::SerialInput { port: base, byte }).is_err() {
+            return;
+        }
+        if let Some(port) = CONSOLE_PORT.lock().as_mut() {
+            port.rx.pop();
+        }
+    }
+}
+
+/// Helper for writing formatted text to the console port.
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    cpu_interrupts::without_interrupts(|| {
+        if let Some(port) = CONSOLE_PORT.lock().as_mut() {
+            let _ = port.write_fmt(args);
+        }
+    });
+}
+
+// --- Line Editing ---
+
+/// Longest line a `LineEditor` accepts; further input is ignored.
+pub const MAX_LINE_LENGTH: usize = 256;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const CTRL_U: u8 = 0x15;
+
+/// Assembles terminal input into lines, echoing as it goes. Supports
+/// backspace/delete and Ctrl-U (erase line); accepts printable ASCII only.
+pub struct LineEditor {
+    line: String,
+    /// Set after a CR so that a following LF does not end an empty line.
+    after_cr: bool,
+}
+
+impl LineEditor {
+    pub const fn new() -> LineEditor {
+        LineEditor {
+            line: String::new(),
+            after_cr: false,
+        }
+    }
+
+    /// Feeds one input byte, writing the echo to `echo`. Returns the line
+    /// once Enter is pressed.
+    pub fn feed(&mut self, byte: u8, echo: &mut dyn fmt::Write) -> Option<String> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                let _ = echo.write_str("\n");
+                Some(core::mem::take(&mut self.line))
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    let _ = echo.write_str("\x08 \x08");
+                }
+                None
+            }
+            CTRL_U => {
+                for _ in 0..self.line.len() {
+                    let _ = echo.write_str("\x08 \x08");
+                }
+                self.line.clear();
+                None
+            }
+            0x20..=0x7E if self.line.len() < MAX_LINE_LENGTH => {
+                self.line.push(byte as char);
+                let _ = echo.write_char(byte as char);
+                None
+            }
+            _ => None,
+        }
+    }
+}
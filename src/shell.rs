@@ -0,0 +1,760 @@
+// Module for the kernel debug shell
+//
+// Lines typed on the serial console or the keyboard are run as commands for
+// inspecting the running system. Output goes to both the framebuffer console
+// and the serial port. Production (release) builds only
+// allow the read-only commands; refusals are recorded in the audit log.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::audit::{self, Outcome};
+use crate::event_loop::{
+    self, Event, EventError, EventKind, EventPayload, EventSet, Handler, Priority, QueuePolicy, SubscriptionId,
+    DEFAULT_QUEUE_DEPTH,
+};
+use crate::hardware::Instant;
+use crate::keyboard::{KeyCode, KeyEvent};
+use crate::loader::{LoaderError, ProcessEntry, ProcessId};
+use crate::manifest::{Capability, CapabilitySet, ExecutionContext, Manifest};
+use crate::power::ShutdownAction;
+use crate::rtc::DateTime;
+use crate::serial::LineEditor;
+use crate::timer::{TimerAction, TimerId};
+use crate::{console, heap, loader, logger, memory, power, rtc, serial, time, timer, tz};
+
+/// Release builds are production builds.
+const PRODUCTION: bool = !cfg!(debug_assertions);
+
+const PROMPT: &str = "\x1b[1;36moptios>\x1b[0m ";
+
+/// Queued keystrokes before further input is dropped.
+const INPUT_QUEUE_DEPTH: usize = 64;
+
+/// Entries shown by `audit` when no count is given.
+const DEFAULT_AUDIT_LINES: usize = 10;
+
+/// Records shown by `log` when no count is given.
+const DEFAULT_LOG_LINES: usize = 20;
+
+/// Slack given to timers set from the shell.
+const TIMER_SLACK: Duration = Duration::from_secs(1);
+
+static SHELL_MANIFEST: Manifest = Manifest {
+    name: "debug-shell",
+    version: 1,
+    allowed_events: EventSet::empty()
+        .with(EventKind::SerialInput)
+        .with(EventKind::KeyInput)
+        .with(EventKind::RtcAlarm)
+        .with(EventKind::RtcUpdate),
+    capabilities: CapabilitySet::empty()
+        .with(Capability::ConsoleOutput)
+        .with(Capability::SerialOutput)
+        .with(Capability::Power)
+        .with(Capability::SetSystemTime),
+    // Commands run from event handlers; keys are read by a foreground process.
+    contexts: ExecutionContext::Both,
+    snapshot_size: 0,
+};
+
+/// Reports `test` events, so `fire`, `after` and `daily` can be seen working.
+static MONITOR_MANIFEST: Manifest = Manifest {
+    name: "event-monitor",
+    version: 1,
+    allowed_events: EventSet::empty().with(EventKind::Test),
+    capabilities: CapabilitySet::empty()
+        .with(Capability::ConsoleOutput)
+        .with(Capability::SerialOutput),
+    contexts: ExecutionContext::Background,
+    snapshot_size: 0,
+};
+
+// --- Commands ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Inspects state only; available in every build.
+    ReadOnly,
+    /// Changes state or exposes event payloads; refused in production builds.
+    Privileged,
+}
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    access: Access,
+    run: fn(&mut Output, &[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "help", usage: "help", help: "list commands", access: Access::ReadOnly, run: cmd_help },
+    Command { name: "handlers", usage: "handlers", help: "registered handlers and their manifests", access: Access::ReadOnly, run: cmd_handlers },
+    Command { name: "snapshots", usage: "snapshots", help: "snapshot size of each handler", access: Access::ReadOnly, run: cmd_snapshots },
+    Command { name: "queue", usage: "queue", help: "dump queued events", access: Access::Privileged, run: cmd_queue },
+    Command { name: "audit", usage: "audit [count]", help: "tail the audit log", access: Access::ReadOnly, run: cmd_audit },
+    Command { name: "log", usage: "log [count]", help: "tail the kernel log", access: Access::ReadOnly, run: cmd_log },
+    Command { name: "fire", usage: "fire [event]", help: "emit an event (default: test)", access: Access::Privileged, run: cmd_fire },
+    Command { name: "after", usage: "after SECONDS [event]", help: "emit an event after a delay", access: Access::Privileged, run: cmd_after },
+    Command { name: "daily", usage: "daily HH:MM [event]", help: "emit an event every day at a local time", access: Access::Privileged, run: cmd_daily },
+    Command { name: "cancel", usage: "cancel TIMER", help: "cancel a timer set by after or daily", access: Access::Privileged, run: cmd_cancel },
+    Command { name: "serial", usage: "serial", help: "serial console status", access: Access::ReadOnly, run: cmd_serial },
+    Command { name: "mem", usage: "mem", help: "memory statistics", access: Access::ReadOnly, run: cmd_mem },
+    Command { name: "date", usage: "date", help: "show the local and UTC system time", access: Access::ReadOnly, run: cmd_date },
+    Command { name: "settime", usage: "settime YYYY-MM-DD HH:MM:SS", help: "set the system time (UTC)", access: Access::Privileged, run: cmd_settime },
+    Command { name: "alarm", usage: "alarm [YYYY-MM-DD HH:MM:SS | cancel]", help: "show, set (UTC) or cancel the RTC alarm", access: Access::Privileged, run: cmd_alarm },
+    Command { name: "ticks", usage: "ticks on|off", help: "print the RTC's once-a-second update event", access: Access::Privileged, run: cmd_ticks },
+    Command { name: "ps", usage: "ps", help: "foreground processes", access: Access::ReadOnly, run: cmd_ps },
+    Command { name: "run", usage: "run PROGRAM", help: "launch a foreground program (keys)", access: Access::Privileged, run: cmd_run },
+    Command { name: "fg", usage: "fg PID", help: "give a foreground process keyboard focus", access: Access::Privileged, run: cmd_fg },
+    Command { name: "reboot", usage: "reboot", help: "reboot the machine", access: Access::Privileged, run: cmd_reboot },
+    Command { name: "shutdown", usage: "shutdown", help: "power off the machine", access: Access::Privileged, run: cmd_shutdown },
+];
+
+/// Writes to both the framebuffer console and the serial console.
+struct Output;
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        console::_print(format_args!("{}", s));
+        serial::_print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+fn cmd_help(out: &mut Output, _args: &[&str]) {
+    for command in COMMANDS {
+        let restricted = PRODUCTION && command.access == Access::Privileged;
+        let _ = writeln!(
+            out,
+            "  {:<14} {}{}",
+            command.usage,
+            command.help,
+            if restricted { " (restricted)" } else { "" }
+        );
+    }
+}
+
+fn cmd_handlers(out: &mut Output, _args: &[&str]) {
+    let subscriptions = event_loop::with_event_loop(|event_loop| event_loop.subscriptions());
+    if subscriptions.is_empty() {
+        let _ = writeln!(out, "  no handlers registered");
+    }
+    for s in subscriptions {
+        let manifest = s.manifest;
+        let _ = writeln!(
+            out,
+            "  #{:<3} {} v{} on {} ({:?}, {:?}, {}/{} queued, {} dropped)",
+            s.id, manifest.name, manifest.version, s.kind.name(), s.priority, s.policy, s.queued, s.depth, s.dropped
+        );
+        let events: Vec<&str> = EventKind::ALL
+            .iter()
+            .filter(|kind| manifest.allowed_events.contains(**kind))
+            .map(|kind| kind.name())
+            .collect();
+        let capabilities: Vec<&str> = Capability::ALL
+            .iter()
+            .filter(|capability| manifest.allows(**capability))
+            .map(|capability| capability.name())
+            .collect();
+        let _ = writeln!(
+            out,
+            "       events: {}; capabilities: {}; context: {}",
+            events.join(", "),
+            capabilities.join(", "),
+            manifest.contexts.name()
+        );
+    }
+}
+
+fn cmd_snapshots(out: &mut Output, _args: &[&str]) {
+    // A program with several subscriptions still has a single snapshot.
+    let mut sizes = BTreeMap::new();
+    for s in event_loop::with_event_loop(|event_loop| event_loop.subscriptions()) {
+        sizes.insert((s.manifest.name, s.manifest.version), s.manifest.snapshot_size);
+    }
+    for ((name, version), size) in &sizes {
+        let _ = writeln!(out, "  {:<20} v{:<4} {:>10} bytes", name, version, size);
+    }
+    let _ = writeln!(out, "  total {} bytes in {} snapshots", sizes.values().sum::<usize>(), sizes.len());
+}
+
+fn cmd_queue(out: &mut Output, _args: &[&str]) {
+    let queued = event_loop::with_event_loop(|event_loop| event_loop.queued_events());
+    if queued.is_empty() {
+        let _ = writeln!(out, "  event queue is empty");
+    }
+    for (subscription, event) in queued {
+        let _ = writeln!(
+            out,
+            "  #{:<3} {:<20} {:?} t={} {:?}",
+            subscription, event.kind.name(), event.priority, event.timestamp, event.payload
+        );
+    }
+}
+
+/// Parses an optional count argument, reporting a bad one.
+fn parse_count(out: &mut Output, args: &[&str], default: usize) -> Option<usize> {
+    match args.first() {
+        Some(arg) => match arg.parse() {
+            Ok(count) => Some(count),
+            Err(_) => {
+                let _ = writeln!(out, "  invalid count: {}", arg);
+                None
+            }
+        },
+        None => Some(default),
+    }
+}
+
+fn cmd_audit(out: &mut Output, args: &[&str]) {
+    let Some(count) = parse_count(out, args, DEFAULT_AUDIT_LINES) else {
+        return;
+    };
+    for entry in audit::recent(count) {
+        let _ = writeln!(
+            out,
+            "  [{:>16}] {:?} {}: {}",
+            entry.timestamp, entry.outcome, entry.subject, entry.action
+        );
+    }
+}
+
+fn cmd_log(out: &mut Output, args: &[&str]) {
+    let Some(count) = parse_count(out, args, DEFAULT_LOG_LINES) else {
+        return;
+    };
+    for entry in logger::recent(count) {
+        let _ = writeln!(
+            out,
+            "  [{:>12}] {:<5} {}: {}",
+            entry.timestamp, entry.level, entry.target, entry.message
+        );
+    }
+}
+
+/// The event named by `arg`, `test` if there is none.
+fn parse_event(out: &mut Output, arg: Option<&&str>) -> Option<EventKind> {
+    let name = arg.copied().unwrap_or(EventKind::Test.name());
+    let kind = EventKind::from_name(name);
+    if kind.is_none() {
+        let _ = writeln!(out, "  unknown event: {}", name);
+    }
+    kind
+}
+
+fn cmd_fire(out: &mut Output, args: &[&str]) {
+    let Some(kind) = parse_event(out, args.first()) else {
+        return;
+    };
+    // Commands run in the kernel loop, not in a handler, so this can wait
+    // for a full blocking subscription to drain.
+    match event_loop::emit_blocking(kind, EventPayload::None) {
+        Ok(()) => {
+            let _ = writeln!(out, "  emitted {}", kind.name());
+        }
+        Err(e) => {
+            let _ = writeln!(out, "  failed to emit {}: {:?}", kind.name(), e);
+        }
+    }
+}
+
+fn cmd_after(out: &mut Output, args: &[&str]) {
+    let Some(seconds) = args.first().and_then(|arg| arg.parse::<u64>().ok()) else {
+        let _ = writeln!(out, "  usage: after SECONDS [event]");
+        return;
+    };
+    let Some(kind) = parse_event(out, args.get(1)) else {
+        return;
+    };
+    let id = timer::emit_after(Duration::from_secs(seconds), TIMER_SLACK, kind, EventPayload::None);
+    let _ = writeln!(out, "  timer {}: {} in {}s", id, kind.name(), seconds);
+}
+
+fn cmd_daily(out: &mut Output, args: &[&str]) {
+    let time_of_day = args.first().and_then(|arg| {
+        let (hour, minute) = arg.split_once(':')?;
+        Some((hour.parse::<u8>().ok()?, minute.parse::<u8>().ok()?))
+    });
+    let Some((hour, minute)) = time_of_day else {
+        let _ = writeln!(out, "  usage: daily HH:MM [event]");
+        return;
+    };
+    let Some(kind) = parse_event(out, args.get(1)) else {
+        return;
+    };
+    let action = TimerAction::Emit { kind, payload: EventPayload::None };
+    match timer::schedule_daily(hour, minute, TIMER_SLACK, action) {
+        Some(id) => {
+            let _ = writeln!(out, "  timer {}: {} daily at {:02}:{:02} local time", id, kind.name(), hour, minute);
+        }
+        None => {
+            let _ = writeln!(out, "  invalid time, or the clock is unavailable");
+        }
+    }
+}
+
+fn cmd_cancel(out: &mut Output, args: &[&str]) {
+    let Some(id) = args.first().and_then(|arg| arg.parse::<TimerId>().ok()) else {
+        let _ = writeln!(out, "  usage: cancel TIMER");
+        return;
+    };
+    if timer::cancel(id) {
+        let _ = writeln!(out, "  timer {} cancelled", id);
+    } else {
+        let _ = writeln!(out, "  no timer {}", id);
+    }
+}
+
+fn cmd_mem(out: &mut Output, _args: &[&str]) {
+    match memory::stats() {
+        Some(stats) => {
+            let rows = [
+                ("total", stats.total_pages),
+                ("free", stats.free_pages),
+                ("kernel", stats.kernel_pages),
+                ("boot services", stats.boot_services_pages),
+                ("runtime services", stats.runtime_pages),
+                ("acpi", stats.acpi_pages),
+                ("reserved", stats.reserved_pages),
+            ];
+            for (label, pages) in rows {
+                let _ = writeln!(out, "  {:<17} {:>10} KiB", label, pages * memory::PAGE_SIZE / 1024);
+            }
+        }
+        None => {
+            let _ = writeln!(out, "  memory map unavailable");
+        }
+    }
+    let (heap_size, heap_used) = heap::usage();
+    let _ = writeln!(out, "  {:<17} {:>10} KiB of {} KiB", "heap used", heap_used / 1024, heap_size / 1024);
+    let pending = event_loop::with_event_loop(|event_loop| event_loop.pending_events());
+    let _ = writeln!(out, "  {:<17} {:>10}", "queued events", pending);
+    let dropped = event_loop::with_event_loop(|event_loop| event_loop.pending_drops());
+    let _ = writeln!(out, "  {:<17} {:>10}", "unreported drops", dropped);
+    let _ = writeln!(out, "  {:<17} {:>10}", "log entries", logger::recent(logger::LOG_RING_CAPACITY).len());
+}
+
+fn cmd_date(out: &mut Output, _args: &[&str]) {
+    match time::wall_time() {
+        Ok(now) => {
+            let utc = now.utc;
+            let local = tz::to_local(utc);
+            let _ = writeln!(
+                out,
+                "  {} {} {}",
+                local.datetime.weekday().name(),
+                format_datetime(&local.datetime),
+                local.abbreviation
+            );
+            let _ = writeln!(out, "  {} {} UTC ({:?} clock)", utc.weekday().name(), format_datetime(&utc), now.source);
+        }
+        Err(e) => {
+            let _ = writeln!(out, "  time unavailable: {:?}", e);
+        }
+    }
+}
+
+fn cmd_settime(out: &mut Output, args: &[&str]) {
+    let Some(datetime) = parse_datetime(args) else {
+        let _ = writeln!(out, "  usage: settime YYYY-MM-DD HH:MM:SS");
+        return;
+    };
+    match time::set_system_time(&SHELL_MANIFEST, datetime) {
+        Ok(()) => {
+            let _ = writeln!(out, "  system time set to {} UTC", format_datetime(&datetime));
+        }
+        Err(e) => {
+            let _ = writeln!(out, "  failed to set time: {:?}", e);
+        }
+    }
+}
+
+fn cmd_alarm(out: &mut Output, args: &[&str]) {
+    match args {
+        [] => match rtc::pending_alarm() {
+            Some(alarm) => {
+                let local = tz::to_local(alarm);
+                let _ = writeln!(
+                    out,
+                    "  alarm at {} {} ({} UTC)",
+                    format_datetime(&local.datetime),
+                    local.abbreviation,
+                    format_datetime(&alarm)
+                );
+            }
+            None => {
+                let _ = writeln!(out, "  no alarm set");
+            }
+        },
+        ["cancel"] => {
+            rtc::cancel_alarm();
+            let _ = writeln!(out, "  alarm cancelled");
+        }
+        _ => {
+            let Some(datetime) = parse_datetime(args) else {
+                let _ = writeln!(out, "  usage: alarm [YYYY-MM-DD HH:MM:SS | cancel]");
+                return;
+            };
+            match rtc::set_alarm(datetime) {
+                Ok(()) => {
+                    let _ = writeln!(out, "  alarm set for {} UTC", format_datetime(&datetime));
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "  failed to set alarm: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// The shell's `rtc-update` subscription while `ticks` is on.
+static TICKS: Mutex<Option<SubscriptionId>> = Mutex::new(None);
+
+fn cmd_ticks(out: &mut Output, args: &[&str]) {
+    let enabled = match args {
+        ["on"] => true,
+        ["off"] => false,
+        _ => {
+            let _ = writeln!(out, "  usage: ticks on|off");
+            return;
+        }
+    };
+    // Only the latest ticks matter to a slow reader.
+    let subscription: Result<(), EventError> = event_loop::with_event_loop(|event_loop| {
+        let mut ticks = TICKS.lock();
+        match (enabled, *ticks) {
+            (true, None) => {
+                *ticks = Some(event_loop.subscribe(
+                    EventKind::RtcUpdate,
+                    &SHELL_MANIFEST,
+                    on_rtc_event,
+                    EventKind::RtcUpdate.default_priority(),
+                    QueuePolicy::DropOldest,
+                    DEFAULT_QUEUE_DEPTH,
+                )?);
+            }
+            (false, Some(id)) => {
+                event_loop.unsubscribe(id);
+                *ticks = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    });
+    if let Err(e) = subscription {
+        let _ = writeln!(out, "  failed to subscribe to rtc-update: {:?}", e);
+        return;
+    }
+    if let Err(e) = rtc::set_update_events(enabled) {
+        let _ = writeln!(out, "  failed to switch update events: {:?}", e);
+    }
+}
+
+fn cmd_serial(out: &mut Output, _args: &[&str]) {
+    let status = interrupts::without_interrupts(|| {
+        serial::CONSOLE_PORT
+            .lock()
+            .as_ref()
+            .map(|port| (port.base(), port.baud_rate(), port.is_interrupt_driven(), port.rx_dropped()))
+    });
+    let Some((base, baud_rate, interrupt_driven, rx_dropped)) = status else {
+        let _ = writeln!(out, "  no serial console");
+        return;
+    };
+    let _ = writeln!(out, "  {:<17} {:#x}", "port", base);
+    let _ = writeln!(out, "  {:<17} {}", "baud rate", baud_rate);
+    let _ = writeln!(out, "  {:<17} {}", "interrupt driven", interrupt_driven);
+    let _ = writeln!(out, "  {:<17} {}", "bytes dropped", rx_dropped);
+}
+
+fn format_datetime(datetime: &DateTime) -> String {
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+        datetime.year, datetime.month, datetime.day, datetime.hour, datetime.minute, datetime.second
+    )
+}
+
+/// Parses `YYYY-MM-DD HH:MM:SS` split into two words.
+fn parse_datetime(args: &[&str]) -> Option<DateTime> {
+    let [date, time] = args else {
+        return None;
+    };
+    let mut date = date.split('-').map(|part| part.parse::<u16>().ok());
+    let mut time = time.split(':').map(|part| part.parse::<u8>().ok());
+    let datetime = DateTime {
+        year: date.next()??,
+        month: u8::try_from(date.next()??).ok()?,
+        day: u8::try_from(date.next()??).ok()?,
+        hour: time.next()??,
+        minute: time.next()??,
+        second: time.next()??,
+    };
+    (date.next().is_none() && time.next().is_none() && datetime.is_valid()).then_some(datetime)
+}
+
+fn cmd_ps(out: &mut Output, _args: &[&str]) {
+    for process in loader::processes() {
+        let _ = writeln!(
+            out,
+            "  {:>4} {:<12} {:<10}{}{}",
+            process.pid,
+            process.name,
+            format!("{:?}", process.state),
+            if process.focused { " focused" } else { "" },
+            if process.kernel { " kernel" } else { "" }
+        );
+    }
+}
+
+fn cmd_run(out: &mut Output, args: &[&str]) {
+    let [name] = args else {
+        let _ = writeln!(out, "  usage: run PROGRAM");
+        return;
+    };
+    let Some(&(manifest, entry)) = PROGRAMS.iter().find(|(manifest, _)| manifest.name == *name) else {
+        let _ = writeln!(out, "  unknown program: {}", name);
+        return;
+    };
+    match loader::launch(manifest, entry) {
+        Ok(pid) => {
+            let _ = writeln!(out, "  started {} as process {}", name, pid);
+        }
+        Err(LoaderError::ForegroundLimitReached { limit }) => {
+            let _ = writeln!(out, "  limit of {} foreground program(s) reached", limit);
+        }
+        Err(e) => {
+            let _ = writeln!(out, "  failed to start {}: {:?}", name, e);
+        }
+    }
+}
+
+fn cmd_fg(out: &mut Output, args: &[&str]) {
+    let Some(pid) = args.first().and_then(|arg| arg.parse::<ProcessId>().ok()) else {
+        let _ = writeln!(out, "  usage: fg PID");
+        return;
+    };
+    if let Err(e) = loader::focus(pid) {
+        let _ = writeln!(out, "  {:?}", e);
+    }
+}
+
+fn cmd_reboot(out: &mut Output, _args: &[&str]) {
+    request_power(out, ShutdownAction::Reboot);
+}
+
+fn cmd_shutdown(out: &mut Output, _args: &[&str]) {
+    request_power(out, ShutdownAction::PowerOff);
+}
+
+fn request_power(out: &mut Output, action: ShutdownAction) {
+    if !SHELL_MANIFEST.allows(Capability::Power) {
+        let _ = writeln!(out, "  permission denied");
+        return;
+    }
+    power::request(action);
+}
+
+// --- Programs ---
+
+static KEYS_MANIFEST: Manifest = Manifest {
+    name: "keys",
+    version: 1,
+    allowed_events: EventSet::empty(),
+    capabilities: CapabilitySet::empty()
+        .with(Capability::ConsoleOutput)
+        .with(Capability::SerialOutput),
+    contexts: ExecutionContext::Foreground,
+    snapshot_size: 0,
+};
+
+/// Foreground programs `run` can launch.
+const PROGRAMS: &[(&Manifest, ProcessEntry)] = &[(&KEYS_MANIFEST, keys_program)];
+
+/// Prints each key event until Escape is pressed.
+fn keys_program() {
+    let _ = writeln!(Output, "  printing key events; press Escape to quit");
+    while let Some(key) = loader::wait_key() {
+        if key.key == KeyCode::Escape {
+            break;
+        }
+        let _ = writeln!(
+            Output,
+            "  {:?} {} {:?}",
+            key.key,
+            if key.pressed { "down" } else { "up" },
+            key.character
+        );
+    }
+    prompt();
+}
+
+// --- Input ---
+
+// Each input source has its own line editor, so typing on one never mixes
+// into a line being typed on the other. Finished lines are queued and run
+// from the kernel loop, never from the keyboard process.
+static SERIAL_EDITOR: Mutex<LineEditor> = Mutex::new(LineEditor::new());
+static KEYBOARD_EDITOR: Mutex<LineEditor> = Mutex::new(LineEditor::new());
+
+/// Lines waiting for `run_queued`.
+static LINES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Runs one command line.
+fn run(line: &str) {
+    let mut out = Output;
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    let args: Vec<&str> = words.collect();
+
+    let Some(command) = COMMANDS.iter().find(|c| c.name == name) else {
+        let _ = writeln!(out, "unknown command: {} (try `help`)", name);
+        return;
+    };
+    if command.access == Access::Privileged {
+        let permitted = !PRODUCTION;
+        audit::record(
+            SHELL_MANIFEST.name,
+            format!("run {}", command.name),
+            if permitted { Outcome::Allowed } else { Outcome::Denied },
+        );
+        if !permitted {
+            let _ = writeln!(out, "{}: not available in production builds", name);
+            return;
+        }
+    }
+    (command.run)(&mut out, &args);
+}
+
+/// Runs the queued lines; a timer action in the kernel loop.
+fn run_queued() {
+    while let Some(line) = interrupts::without_interrupts(|| LINES.lock().pop_front()) {
+        run(&line);
+        prompt();
+    }
+}
+
+/// Feeds one byte of input to `editor`, queueing the line on Enter.
+fn feed(editor: &Mutex<LineEditor>, byte: u8) {
+    let queued = interrupts::without_interrupts(|| {
+        let line = editor.lock().feed(byte, &mut Output)?;
+        let mut lines = LINES.lock();
+        lines.push_back(line);
+        Some(lines.len() == 1)
+    });
+    if queued == Some(true) {
+        timer::schedule(Instant::now(), Duration::ZERO, TimerAction::Call(run_queued));
+    }
+}
+
+fn prompt() {
+    let _ = Output.write_str(PROMPT);
+}
+
+fn on_serial_input(event: &Event) {
+    if let EventPayload::SerialInput { byte, .. } = event.payload {
+        feed(&SERIAL_EDITOR, byte);
+    }
+}
+
+/// Reports RTC alarms and update ticks on their own line.
+fn on_rtc_event(event: &Event) {
+    let EventPayload::DateTime(datetime) = event.payload else {
+        return;
+    };
+    let local = tz::to_local(datetime);
+    let _ = writeln!(
+        Output,
+        "\r\n  {} {} {}",
+        event.kind.name(),
+        format_datetime(&local.datetime),
+        local.abbreviation
+    );
+    prompt();
+}
+
+fn type_key(key: KeyEvent) {
+    match key.character {
+        Some('\n') => feed(&KEYBOARD_EDITOR, b'\r'),
+        Some(c) if c.is_ascii() => feed(&KEYBOARD_EDITOR, c as u8),
+        _ => {}
+    }
+}
+
+/// Reports a `test` event and how long it waited in the queue.
+fn on_test_event(event: &Event) {
+    let _ = writeln!(
+        Output,
+        "\r\n  {} event after {} ms in the queue",
+        event.kind.name(),
+        event.timestamp.elapsed().as_millis()
+    );
+    prompt();
+}
+
+/// Types keys into the shell while no foreground program has focus.
+fn on_key_input(event: &Event) {
+    let EventPayload::Key(key) = event.payload else {
+        return;
+    };
+    if loader::focused().is_none() {
+        type_key(key);
+    }
+}
+
+/// Foreground program for the shell on the keyboard and screen. While it
+/// has focus, keys reach the shell through it rather than `key-input`; it
+/// only edits the line, which runs in the kernel loop.
+fn keyboard_program() {
+    while let Some(key) = loader::wait_key() {
+        type_key(key);
+    }
+}
+
+/// Subscribes the shell to serial and keyboard input and RTC alarms, starts
+/// the keyboard process and shows the first prompt.
+pub fn init() {
+    let subscription: Result<(), EventError> = event_loop::with_event_loop(|event_loop| {
+        let inputs: [(EventKind, Handler); 3] = [
+            (EventKind::SerialInput, on_serial_input),
+            (EventKind::KeyInput, on_key_input),
+            (EventKind::RtcAlarm, on_rtc_event),
+        ];
+        for (kind, handler) in inputs {
+            event_loop.subscribe(
+                kind,
+                &SHELL_MANIFEST,
+                handler,
+                Priority::High,
+                QueuePolicy::DropNewest,
+                INPUT_QUEUE_DEPTH,
+            )?;
+        }
+        event_loop.subscribe(
+            EventKind::Test,
+            &MONITOR_MANIFEST,
+            on_test_event,
+            EventKind::Test.default_priority(),
+            QueuePolicy::CoalesceLatest,
+            DEFAULT_QUEUE_DEPTH,
+        )?;
+        Ok(())
+    });
+    if let Err(e) = subscription {
+        log::error!("Debug shell unavailable: {:?}", e);
+        return;
+    }
+    if let Err(e) = loader::launch_kernel(&SHELL_MANIFEST, keyboard_program) {
+        log::error!("Failed to start the shell on the keyboard: {:?}", e);
+    }
+    prompt();
+}
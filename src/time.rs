@@ -0,0 +1,186 @@
+// Module for wall-clock time
+//
+// The firmware's GetTime/SetTime runtime services are the primary clock,
+// since some machines have no usable CMOS RTC. They are called through the
+// runtime table that `runtime` sets up after boot services exit, with the
+// firmware's memory identity-mapped by SetVirtualAddressMap. When the
+// firmware fails, or its services could not be mapped, the CMOS RTC is used
+// directly. Times are kept in UTC; `tz` converts them to local time for
+// display and calendar timers.
+//
+// Only programs whose manifest grants `set-system-time` may change the
+// clock; every attempt is audited, and a successful change is announced to
+// handlers as `clock-changed`.
+
+use alloc::format;
+use uefi::table::runtime::{Daylight, Time, TimeParams};
+use x86_64::instructions::interrupts;
+
+use crate::audit::{self, Outcome};
+use crate::event_loop::{self, EventKind, EventPayload};
+use crate::manifest::{Capability, Manifest};
+use crate::rtc::{self, DateTime, RtcError};
+use crate::{runtime, timer};
+use crate::tz::{self, LocalTime};
+
+/// Errors returned when reading or setting the system time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError {
+    /// The caller's manifest lacks the `set-system-time` capability.
+    NotPermitted,
+    /// Both the firmware and the CMOS RTC failed; this is the RTC's error.
+    Rtc(RtcError),
+}
+
+/// Where a wall-clock reading came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Firmware,
+    Cmos,
+}
+
+/// A wall-clock reading with the firmware's timezone information.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WallTime {
+    pub utc: DateTime,
+    pub nanosecond: u32,
+    /// The firmware's offset of local time from UTC, in minutes, if it
+    /// records one.
+    pub utc_offset_minutes: Option<i16>,
+    /// The firmware adjusts this clock for daylight saving time.
+    pub adjusts_for_daylight: bool,
+    /// Daylight saving time is currently in effect.
+    pub in_daylight: bool,
+    pub source: ClockSource,
+}
+
+/// Shifts a time by whole minutes through the Unix timestamp.
+fn offset_by_minutes(datetime: DateTime, minutes: i64) -> Option<DateTime> {
+    DateTime::from_unix(datetime.to_unix() + minutes * 60)
+}
+
+/// Reads the firmware clock and converts it to UTC. A clock without a
+/// timezone is taken to be UTC, like the CMOS RTC.
+fn firmware_time() -> Option<WallTime> {
+    let rt = runtime::services()?;
+    let time = interrupts::without_interrupts(|| unsafe { rt.as_ref() }.get_time()).ok()?;
+    let local = DateTime {
+        year: time.year(),
+        month: time.month(),
+        day: time.day(),
+        hour: time.hour(),
+        minute: time.minute(),
+        second: time.second(),
+    };
+    if !local.is_valid() {
+        return None;
+    }
+    // UEFI 2.8 and later define local time as UTC plus the offset.
+    let utc = match time.time_zone() {
+        Some(offset) => offset_by_minutes(local, -(offset as i64))?,
+        None => local,
+    };
+    Some(WallTime {
+        utc,
+        nanosecond: time.nanosecond(),
+        utc_offset_minutes: time.time_zone(),
+        adjusts_for_daylight: time.daylight().contains(Daylight::ADJUST_DAYLIGHT),
+        in_daylight: time.daylight().contains(Daylight::IN_DAYLIGHT),
+        source: ClockSource::Firmware,
+    })
+}
+
+/// Sets the firmware clock, keeping its timezone and daylight fields so
+/// firmware setup screens go on showing the time they expect.
+fn set_firmware_time(utc: DateTime) -> bool {
+    let Some(mut rt) = runtime::services() else {
+        return false;
+    };
+    let current = interrupts::without_interrupts(|| unsafe { rt.as_ref() }.get_time()).ok();
+    let time_zone = current.and_then(|time| time.time_zone());
+    let Some(local) = offset_by_minutes(utc, time_zone.unwrap_or(0) as i64) else {
+        return false;
+    };
+    let time = Time::new(TimeParams {
+        year: local.year,
+        month: local.month,
+        day: local.day,
+        hour: local.hour,
+        minute: local.minute,
+        second: local.second,
+        nanosecond: 0,
+        time_zone,
+        daylight: current.map_or(Daylight::empty(), |time| time.daylight()),
+    });
+    match time {
+        // Safety: SetTime has no memory safety requirements; the runtime
+        // services table is only borrowed for the call. Runtime services are
+        // not reentrant, so interrupts stay off across it.
+        Ok(time) => interrupts::without_interrupts(|| unsafe { rt.as_mut().set_time(&time) }).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// The current wall-clock time, from the firmware or else the CMOS RTC.
+pub fn wall_time() -> Result<WallTime, TimeError> {
+    if let Some(time) = firmware_time() {
+        return Ok(time);
+    }
+    let utc = rtc::get_datetime().map_err(TimeError::Rtc)?;
+    Ok(WallTime {
+        utc,
+        nanosecond: 0,
+        utc_offset_minutes: None,
+        adjusts_for_daylight: false,
+        in_daylight: false,
+        source: ClockSource::Cmos,
+    })
+}
+
+/// How far the CMOS RTC runs ahead of UTC, in minutes. The firmware keeps
+/// its local time there, so this is its timezone offset, or zero when it
+/// records none or cannot be reached.
+pub fn cmos_offset_minutes() -> i16 {
+    firmware_time().and_then(|time| time.utc_offset_minutes).unwrap_or(0)
+}
+
+/// The current UTC date and time.
+pub fn now() -> Result<DateTime, TimeError> {
+    wall_time().map(|time| time.utc)
+}
+
+/// The current local time in the system timezone.
+pub fn local_now() -> Result<LocalTime, TimeError> {
+    now().map(tz::to_local)
+}
+
+/// Sets the system clock on behalf of `manifest`'s program.
+pub fn set_system_time(manifest: &'static Manifest, datetime: DateTime) -> Result<(), TimeError> {
+    let permitted = manifest.allows(Capability::SetSystemTime);
+    audit::record(
+        manifest.name,
+        format!("set-system-time {:?}", datetime),
+        if permitted { Outcome::Allowed } else { Outcome::Denied },
+    );
+    if !permitted {
+        return Err(TimeError::NotPermitted);
+    }
+
+    let previous = now().ok();
+    if !set_firmware_time(datetime) {
+        log::warn!("Firmware SetTime failed; writing the CMOS RTC directly");
+        rtc::set_datetime(datetime).map_err(TimeError::Rtc)?;
+    }
+    log::info!("System time set to {:?} by {}", datetime, manifest.name);
+    timer::calendar_changed();
+
+    let adjustment_secs = previous.map_or(0, |previous| datetime.to_unix() - previous.to_unix());
+    let payload = EventPayload::ClockChanged {
+        current: datetime,
+        adjustment_secs,
+    };
+    if let Err(e) = event_loop::emit(EventKind::ClockChanged, payload) {
+        log::warn!("Could not announce the clock change: {:?}", e);
+    }
+    Ok(())
+}
@@ -0,0 +1,315 @@
+// Module for one-shot kernel timers
+//
+// There is no periodic tick. Pending timers are kept in a heap ordered by the
+// latest time each may fire, and the local APIC timer is armed for the
+// earliest of those. When it fires, every timer whose deadline has passed
+// runs, so timers with overlapping slack share one wakeup. Between wakeups
+// the CPU idles in `hlt`.
+//
+// Timers run from the kernel loop through `run_expired`, never from the
+// interrupt handler, so they may emit events and take locks freely.
+//
+// Calendar timers such as "daily at 03:00" fire at a local wall-clock time.
+// Each occurrence is converted to UTC through the system timezone, so DST
+// changes move it with the clocks, and is re-computed whenever the clock is
+// set or the timezone changes.
+
+use alloc::collections::{BTreeMap, BinaryHeap};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::event_loop::{self, EventKind, EventPayload};
+use crate::hardware::{self, Instant};
+use crate::rtc::DateTime;
+use crate::{time, tz};
+
+/// Slack given to the periodic `background-schedule` event, which has no
+/// need to fire at an exact time.
+const SCHEDULE_SLACK: Duration = Duration::from_secs(1);
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub type TimerId = u32;
+
+/// What happens when a timer expires.
+#[derive(Debug, Clone, Copy)]
+pub enum TimerAction {
+    /// Emits an event stamped with the time the timer ran.
+    Emit { kind: EventKind, payload: EventPayload },
+    /// Calls a kernel function, e.g. to enforce a timeout.
+    Call(fn()),
+}
+
+/// When a timer is re-armed after it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    Once,
+    /// This far after each deadline.
+    Every(Duration),
+    /// At the next occurrence of this local time of day.
+    DailyAt { hour: u8, minute: u8 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    deadline: Instant,
+    /// How much later than `deadline` the timer may run.
+    slack: Duration,
+    repeat: Repeat,
+    action: TimerAction,
+}
+
+impl Timer {
+    fn latest(&self) -> Instant {
+        self.deadline + self.slack
+    }
+}
+
+struct TimerQueue {
+    timers: BTreeMap<TimerId, Timer>,
+    /// `(latest, id)` for each armed timer. Cancelled timers are left in the
+    /// heap and skipped when they reach the top.
+    heap: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    next_id: TimerId,
+}
+
+impl TimerQueue {
+    const fn new() -> TimerQueue {
+        TimerQueue {
+            timers: BTreeMap::new(),
+            heap: BinaryHeap::new(),
+            next_id: 1,
+        }
+    }
+
+    fn insert(&mut self, timer: Timer) -> TimerId {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.arm(id, timer);
+        id
+    }
+
+    fn arm(&mut self, id: TimerId, timer: Timer) {
+        self.heap.push(Reverse((timer.latest(), id)));
+        self.timers.insert(id, timer);
+    }
+
+    /// The earliest time any timer must run, dropping stale heap entries.
+    fn next_wakeup(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((latest, id))) = self.heap.peek() {
+            match self.timers.get(&id) {
+                Some(timer) if timer.latest() == latest => return Some(latest),
+                _ => {
+                    self.heap.pop();
+                }
+            }
+        }
+        None
+    }
+
+    /// Removes every timer whose deadline has passed, re-arming periodic ones.
+    /// Calendar timers are held a day ahead and returned so their next
+    /// occurrence can be computed outside the lock.
+    fn take_expired(&mut self, now: Instant) -> (Vec<TimerAction>, Vec<TimerId>) {
+        let expired: Vec<TimerId> = self
+            .timers
+            .iter()
+            .filter(|(_, timer)| timer.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+
+        let mut actions = Vec::with_capacity(expired.len());
+        let mut calendar = Vec::new();
+        for id in expired {
+            let Some(mut timer) = self.timers.remove(&id) else {
+                continue;
+            };
+            actions.push(timer.action);
+            match timer.repeat {
+                Repeat::Once => continue,
+                Repeat::Every(period) => {
+                    // Skip missed periods rather than firing them back to back.
+                    while timer.deadline <= now {
+                        timer.deadline = timer.deadline + period;
+                    }
+                }
+                Repeat::DailyAt { .. } => {
+                    timer.deadline = now + DAY;
+                    calendar.push(id);
+                }
+            }
+            self.arm(id, timer);
+        }
+        (actions, calendar)
+    }
+}
+
+static TIMERS: Mutex<TimerQueue> = Mutex::new(TimerQueue::new());
+
+/// The next time the local clock reads `hour:minute`, or None if the
+/// wall-clock time is unavailable.
+fn next_daily(hour: u8, minute: u8) -> Option<Instant> {
+    let now = time::now().ok()?;
+    let local = tz::to_local(now).datetime;
+    let mut target = DateTime { hour, minute, second: 0, ..local };
+    // Two days covers a target skipped by a DST gap on the first.
+    for _ in 0..3 {
+        let utc = tz::resolve_local(target).earliest();
+        if utc > now {
+            let ahead = (utc.to_unix() - now.to_unix()) as u64;
+            return Some(Instant::now() + Duration::from_secs(ahead));
+        }
+        target = DateTime::from_unix(target.to_unix() + DAY.as_secs() as i64)?;
+    }
+    None
+}
+
+fn with_timers<R>(f: impl FnOnce(&mut TimerQueue) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TIMERS.lock()))
+}
+
+// --- Public API ---
+
+/// Runs `action` once at `deadline`, or up to `slack` later so the wakeup
+/// can be shared with other timers.
+pub fn schedule(deadline: Instant, slack: Duration, action: TimerAction) -> TimerId {
+    with_timers(|timers| {
+        timers.insert(Timer {
+            deadline,
+            slack,
+            repeat: Repeat::Once,
+            action,
+        })
+    })
+}
+
+/// Runs `action` every `period`, starting one period from now.
+pub fn schedule_periodic(period: Duration, slack: Duration, action: TimerAction) -> TimerId {
+    let period = period.max(Duration::from_millis(1));
+    with_timers(|timers| {
+        timers.insert(Timer {
+            deadline: Instant::now() + period,
+            slack,
+            repeat: Repeat::Every(period),
+            action,
+        })
+    })
+}
+
+/// Runs `action` every day when the local clock reads `hour:minute`. Returns
+/// None if the time is out of range or the wall-clock time is unavailable.
+pub fn schedule_daily(hour: u8, minute: u8, slack: Duration, action: TimerAction) -> Option<TimerId> {
+    if hour > 23 || minute > 59 {
+        return None;
+    }
+    let deadline = next_daily(hour, minute)?;
+    Some(with_timers(|timers| {
+        timers.insert(Timer {
+            deadline,
+            slack,
+            repeat: Repeat::DailyAt { hour, minute },
+            action,
+        })
+    }))
+}
+
+/// Emits an event after `delay`, coalesced with any other timer whose
+/// window overlaps `slack`.
+pub fn emit_after(delay: Duration, slack: Duration, kind: EventKind, payload: EventPayload) -> TimerId {
+    schedule(Instant::now() + delay, slack, TimerAction::Emit { kind, payload })
+}
+
+/// Cancels a timer. Returns false if it already ran or was cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    with_timers(|timers| timers.timers.remove(&id).is_some())
+}
+
+/// Moves calendar timers to their next occurrence. Reading the clock calls
+/// into the firmware, so this runs outside the timer lock.
+fn rearm_calendar(ids: Vec<TimerId>) {
+    for id in ids {
+        let repeat = with_timers(|timers| timers.timers.get(&id).map(|timer| timer.repeat));
+        let Some(Repeat::DailyAt { hour, minute }) = repeat else {
+            continue;
+        };
+        let Some(deadline) = next_daily(hour, minute) else {
+            continue;
+        };
+        with_timers(|timers| {
+            if let Some(mut timer) = timers.timers.get(&id).copied() {
+                timer.deadline = deadline;
+                timers.arm(id, timer);
+            }
+        });
+    }
+}
+
+/// Re-computes calendar timers after the clock is set or the timezone
+/// changes.
+pub fn calendar_changed() {
+    let calendar = with_timers(|timers| {
+        timers
+            .timers
+            .iter()
+            .filter(|(_, timer)| matches!(timer.repeat, Repeat::DailyAt { .. }))
+            .map(|(&id, _)| id)
+            .collect()
+    });
+    rearm_calendar(calendar);
+}
+
+/// Runs every expired timer. Returns the number that ran.
+pub fn run_expired() -> usize {
+    let (actions, calendar) = with_timers(|timers| timers.take_expired(Instant::now()));
+    rearm_calendar(calendar);
+    for action in &actions {
+        match *action {
+            TimerAction::Emit { kind, payload } => {
+                if let Err(e) = event_loop::emit(kind, payload) {
+                    log::warn!("Timer could not emit {}: {:?}", kind.name(), e);
+                }
+            }
+            TimerAction::Call(function) => function(),
+        }
+    }
+    actions.len()
+}
+
+/// Sleeps until the next timer, interrupt or `wake_by`, with the APIC timer
+/// armed for the earliest of them. Returns at once if interrupts are
+/// disabled, or if a deadline is pending and the APIC timer is not running,
+/// since nothing could wake the CPU in time.
+pub fn idle(wake_by: Option<Instant>) {
+    if !interrupts::are_enabled() {
+        return;
+    }
+    interrupts::disable();
+    let next = match (TIMERS.lock().next_wakeup(), wake_by) {
+        (Some(timer), Some(wake)) => Some(timer.min(wake)),
+        (timer, wake) => timer.or(wake),
+    };
+    let due = next.is_some_and(|deadline| deadline <= Instant::now());
+    if due || (next.is_some() && !hardware::apic_timer_active()) {
+        interrupts::enable();
+        return;
+    }
+    hardware::set_timer_deadline(next);
+    // `sti; hlt` so an interrupt arriving in between still wakes us.
+    interrupts::enable_and_hlt();
+}
+
+/// Starts the periodic `background-schedule` event from the kernel policy.
+pub fn init() {
+    let interval = Duration::from_secs(event_loop::policy().schedule_interval_secs);
+    schedule_periodic(
+        interval,
+        SCHEDULE_SLACK,
+        TimerAction::Emit {
+            kind: EventKind::BackgroundSchedule,
+            payload: EventPayload::None,
+        },
+    );
+}
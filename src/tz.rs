@@ -0,0 +1,562 @@
+// Module for timezones and local time
+//
+// Timezones are compiled TZif files, as found in /usr/share/zoneinfo, read
+// from the ESP at boot. A file lists the UTC instants at which the local
+// offset changes; times after the last listed transition follow the POSIX
+// TZ rule in the file's footer, which is how current zic output describes
+// ongoing daylight saving time. Without a timezone, local time is UTC.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::rtc::DateTime;
+
+const TZIF_MAGIC: &[u8; 4] = b"TZif";
+const TZIF_HEADER_SIZE: usize = 44;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Errors returned when parsing a timezone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TzError {
+    /// The data does not start with the TZif magic.
+    BadMagic,
+    /// The data ends before the counts in its header say it should.
+    Truncated,
+    /// A transition refers to a local time type that does not exist.
+    InvalidType,
+    /// The footer is not a POSIX TZ string we understand.
+    InvalidRule,
+}
+
+/// One of a zone's local time types, e.g. GMT or BST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalTimeType {
+    /// Seconds to add to UTC to get local time.
+    pub utc_offset: i32,
+    pub is_dst: bool,
+    pub abbreviation: String,
+}
+
+/// A UTC time converted to local time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalTime {
+    pub datetime: DateTime,
+    pub utc_offset: i32,
+    pub is_dst: bool,
+    pub abbreviation: String,
+}
+
+/// The UTC time a local time refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalResult {
+    Single(DateTime),
+    /// The local time happens twice as the clocks go back; both UTC times,
+    /// earlier first.
+    Ambiguous(DateTime, DateTime),
+    /// The local time is skipped as the clocks go forward. Holds the UTC
+    /// time the reading would have had under the earlier offset, which
+    /// falls after the skipped interval.
+    Gap(DateTime),
+}
+
+impl LocalResult {
+    /// The first UTC time at or after the local time, for schedules.
+    pub fn earliest(&self) -> DateTime {
+        match *self {
+            LocalResult::Single(utc) | LocalResult::Ambiguous(utc, _) | LocalResult::Gap(utc) => utc,
+        }
+    }
+}
+
+// --- POSIX TZ Rules ---
+
+/// The day a DST rule switches on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RuleDay {
+    /// `Jn`: day 1-365, never counting February 29.
+    Julian1(u16),
+    /// `n`: day 0-365, counting February 29.
+    Julian0(u16),
+    /// `Mm.w.d`: day `d` (0 = Sunday) of week `w` (5 = last) of month `m`.
+    MonthWeekDay { month: u8, week: u8, weekday: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RuleTime {
+    day: RuleDay,
+    /// Local time of day of the switch, in seconds; may be negative or past 24h.
+    time: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DstRule {
+    dst: LocalTimeType,
+    start: RuleTime,
+    end: RuleTime,
+}
+
+/// A POSIX TZ string such as `GMT0BST,M3.5.0/1,M10.5.0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PosixRule {
+    std: LocalTimeType,
+    dst: Option<DstRule>,
+}
+
+struct RuleParser<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> RuleParser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.rest.first().copied()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        if self.peek() == Some(byte) {
+            self.rest = &self.rest[1..];
+            return true;
+        }
+        false
+    }
+
+    fn number(&mut self) -> Result<i32, TzError> {
+        let digits = self.rest.iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 {
+            return Err(TzError::InvalidRule);
+        }
+        let value = self.rest[..digits]
+            .iter()
+            .fold(0i32, |value, &b| value.saturating_mul(10).saturating_add((b - b'0') as i32));
+        self.rest = &self.rest[digits..];
+        Ok(value)
+    }
+
+    /// A zone abbreviation: letters, or anything between `<` and `>`.
+    fn name(&mut self) -> Result<String, TzError> {
+        let (name, consumed) = if self.eat(b'<') {
+            let length = self.rest.iter().position(|&b| b == b'>').ok_or(TzError::InvalidRule)?;
+            (&self.rest[..length], length + 1)
+        } else {
+            let length = self.rest.iter().take_while(|b| b.is_ascii_alphabetic()).count();
+            (&self.rest[..length], length)
+        };
+        if name.len() < 3 {
+            return Err(TzError::InvalidRule);
+        }
+        let name = String::from_utf8(name.to_vec()).map_err(|_| TzError::InvalidRule)?;
+        self.rest = &self.rest[consumed..];
+        Ok(name)
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds.
+    fn time(&mut self) -> Result<i32, TzError> {
+        let sign = if self.eat(b'-') {
+            -1
+        } else {
+            self.eat(b'+');
+            1
+        };
+        let mut seconds = self.number()?.checked_mul(3600).ok_or(TzError::InvalidRule)?;
+        if self.eat(b':') {
+            let minutes = self.number()?.checked_mul(60).ok_or(TzError::InvalidRule)?;
+            seconds = seconds.checked_add(minutes).ok_or(TzError::InvalidRule)?;
+            if self.eat(b':') {
+                seconds = seconds.checked_add(self.number()?).ok_or(TzError::InvalidRule)?;
+            }
+        }
+        Ok(sign * seconds)
+    }
+
+    fn rule_time(&mut self) -> Result<RuleTime, TzError> {
+        let day = if self.eat(b'M') {
+            let month = self.number()?;
+            let week = if self.eat(b'.') { self.number()? } else { return Err(TzError::InvalidRule) };
+            let weekday = if self.eat(b'.') { self.number()? } else { return Err(TzError::InvalidRule) };
+            if !(1..=12).contains(&month) || !(1..=5).contains(&week) || !(0..=6).contains(&weekday) {
+                return Err(TzError::InvalidRule);
+            }
+            RuleDay::MonthWeekDay {
+                month: month as u8,
+                week: week as u8,
+                weekday: weekday as u8,
+            }
+        } else if self.eat(b'J') {
+            RuleDay::Julian1(self.number()?.clamp(1, 365) as u16)
+        } else {
+            RuleDay::Julian0(self.number()?.clamp(0, 365) as u16)
+        };
+        // Switches happen at 02:00 local time unless stated.
+        let time = if self.eat(b'/') { self.time()? } else { 2 * 3600 };
+        Ok(RuleTime { day, time })
+    }
+
+    fn rule(&mut self) -> Result<PosixRule, TzError> {
+        let std_name = self.name()?;
+        // POSIX offsets are west of Greenwich, the opposite of TZif's.
+        let std_offset = -self.time()?;
+        let std = LocalTimeType {
+            utc_offset: std_offset,
+            is_dst: false,
+            abbreviation: std_name,
+        };
+        if self.peek().is_none() {
+            return Ok(PosixRule { std, dst: None });
+        }
+
+        let dst_name = self.name()?;
+        let dst_offset = match self.peek() {
+            Some(b',') | None => std_offset + 3600,
+            _ => -self.time()?,
+        };
+        if !self.eat(b',') {
+            return Err(TzError::InvalidRule);
+        }
+        let start = self.rule_time()?;
+        if !self.eat(b',') {
+            return Err(TzError::InvalidRule);
+        }
+        let end = self.rule_time()?;
+        if self.peek().is_some() {
+            return Err(TzError::InvalidRule);
+        }
+        Ok(PosixRule {
+            std,
+            dst: Some(DstRule {
+                dst: LocalTimeType {
+                    utc_offset: dst_offset,
+                    is_dst: true,
+                    abbreviation: dst_name,
+                },
+                start,
+                end,
+            }),
+        })
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+/// Unix day number of January 1st of `year`.
+fn year_start(year: i64) -> i64 {
+    let jan1 = DateTime {
+        year: year.clamp(0, u16::MAX as i64) as u16,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    jan1.to_unix().div_euclid(SECONDS_PER_DAY)
+}
+
+impl RuleTime {
+    /// Unix day number of the switch in `year`.
+    fn day_in(&self, year: i64) -> i64 {
+        let jan1 = year_start(year);
+        match self.day {
+            RuleDay::Julian0(day) => jan1 + day as i64,
+            RuleDay::Julian1(day) => {
+                let after_february = day > 59 && is_leap_year(year);
+                jan1 + day as i64 - 1 + after_february as i64
+            }
+            RuleDay::MonthWeekDay { month, week, weekday } => {
+                let first = DateTime {
+                    year: year.clamp(0, u16::MAX as i64) as u16,
+                    month,
+                    day: 1,
+                    hour: 0,
+                    minute: 0,
+                    second: 0,
+                };
+                let first_day = first.to_unix().div_euclid(SECONDS_PER_DAY);
+                // 1970-01-01 was a Thursday (4).
+                let first_weekday = (first_day + 4).rem_euclid(7);
+                let mut day = first_day + (weekday as i64 - first_weekday).rem_euclid(7) + (week as i64 - 1) * 7;
+                let next_month = if month == 12 { year_start(year + 1) } else {
+                    let next = DateTime { month: month + 1, ..first };
+                    next.to_unix().div_euclid(SECONDS_PER_DAY)
+                };
+                while day >= next_month {
+                    day -= 7;
+                }
+                day
+            }
+        }
+    }
+
+    /// The UTC instant of the switch in `year`, given the offset in force
+    /// just before it.
+    fn instant_in(&self, year: i64, offset_before: i32) -> i64 {
+        self.day_in(year) * SECONDS_PER_DAY + self.time as i64 - offset_before as i64
+    }
+}
+
+impl PosixRule {
+    fn type_at(&self, utc: i64) -> &LocalTimeType {
+        let Some(rule) = &self.dst else {
+            return &self.std;
+        };
+        let year = DateTime::from_unix(utc + self.std.utc_offset as i64).map_or(1970, |d| d.year as i64);
+        let start = rule.start.instant_in(year, self.std.utc_offset);
+        let end = rule.end.instant_in(year, rule.dst.utc_offset);
+        // Southern hemisphere zones start DST late in the year and end it early.
+        let in_dst = if start < end {
+            utc >= start && utc < end
+        } else {
+            utc >= start || utc < end
+        };
+        if in_dst {
+            &rule.dst
+        } else {
+            &self.std
+        }
+    }
+}
+
+// --- TZif ---
+
+/// A parsed timezone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timezone {
+    pub name: String,
+    /// UTC instants at which the local time type changes, ascending.
+    transitions: Vec<i64>,
+    /// Index into `types` for each transition.
+    transition_types: Vec<u8>,
+    types: Vec<LocalTimeType>,
+    /// Rule for times after the last transition.
+    footer: Option<PosixRule>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], TzError> {
+        let end = self.offset.checked_add(length).ok_or(TzError::Truncated)?;
+        let bytes = self.data.get(self.offset..end).ok_or(TzError::Truncated)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, TzError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i32(&mut self) -> Result<i32, TzError> {
+        let bytes = self.bytes(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i64(&mut self) -> Result<i64, TzError> {
+        let bytes = self.bytes(8)?;
+        let mut be = [0u8; 8];
+        be.copy_from_slice(bytes);
+        Ok(i64::from_be_bytes(be))
+    }
+}
+
+/// Counts from a TZif header, in file order.
+struct TzifCounts {
+    isutcnt: usize,
+    isstdcnt: usize,
+    leapcnt: usize,
+    timecnt: usize,
+    typecnt: usize,
+    charcnt: usize,
+}
+
+impl TzifCounts {
+    fn read(reader: &mut Reader) -> Result<(u8, TzifCounts), TzError> {
+        let header = reader.bytes(TZIF_HEADER_SIZE)?;
+        if &header[..4] != TZIF_MAGIC {
+            return Err(TzError::BadMagic);
+        }
+        let count = |index: usize| {
+            let at = 20 + index * 4;
+            u32::from_be_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]) as usize
+        };
+        Ok((
+            header[4],
+            TzifCounts {
+                isutcnt: count(0),
+                isstdcnt: count(1),
+                leapcnt: count(2),
+                timecnt: count(3),
+                typecnt: count(4),
+                charcnt: count(5),
+            },
+        ))
+    }
+
+    /// Size of the data block with `time_size`-byte times.
+    fn block_size(&self, time_size: usize) -> usize {
+        self.timecnt * (time_size + 1) + self.typecnt * 6 + self.charcnt + self.leapcnt * (time_size + 4) + self.isstdcnt + self.isutcnt
+    }
+}
+
+impl Timezone {
+    /// Parses a compiled TZif file. Version 2+ files are read from their
+    /// 64-bit block and footer; version 1 files from the 32-bit block.
+    pub fn parse(name: &str, data: &[u8]) -> Result<Timezone, TzError> {
+        let mut reader = Reader { data, offset: 0 };
+        let (version, mut counts) = TzifCounts::read(&mut reader)?;
+        let mut time_size = 4;
+        if version >= b'2' {
+            reader.bytes(counts.block_size(4))?;
+            counts = TzifCounts::read(&mut reader)?.1;
+            time_size = 8;
+        }
+        // Check the counts before sizing any allocation by them.
+        if counts.block_size(time_size) > data.len() - reader.offset {
+            return Err(TzError::Truncated);
+        }
+
+        let mut transitions = Vec::with_capacity(counts.timecnt);
+        for _ in 0..counts.timecnt {
+            transitions.push(if time_size == 8 { reader.i64()? } else { reader.i32()? as i64 });
+        }
+        let transition_types = reader.bytes(counts.timecnt)?.to_vec();
+
+        let mut raw_types = Vec::with_capacity(counts.typecnt);
+        for _ in 0..counts.typecnt {
+            raw_types.push((reader.i32()?, reader.u8()? != 0, reader.u8()? as usize));
+        }
+        let abbreviations = reader.bytes(counts.charcnt)?;
+        reader.bytes(counts.leapcnt * (time_size + 4) + counts.isstdcnt + counts.isutcnt)?;
+
+        let types: Vec<LocalTimeType> = raw_types
+            .into_iter()
+            .map(|(utc_offset, is_dst, index)| {
+                let name = abbreviations.get(index..).unwrap_or_default();
+                let length = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                LocalTimeType {
+                    utc_offset,
+                    is_dst,
+                    abbreviation: String::from_utf8_lossy(&name[..length]).into_owned(),
+                }
+            })
+            .collect();
+        if types.is_empty() || transition_types.iter().any(|&t| t as usize >= types.len()) {
+            return Err(TzError::InvalidType);
+        }
+
+        // The footer is the TZ string between two newlines; empty means none.
+        let footer = if time_size == 8 {
+            let rest = &data[reader.offset..];
+            match rest.split(|&b| b == b'\n').nth(1) {
+                Some(rule) if !rule.is_empty() => Some(RuleParser { rest: rule }.rule()?),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        Ok(Timezone {
+            name: String::from(name),
+            transitions,
+            transition_types,
+            types,
+            footer,
+        })
+    }
+
+    /// Coordinated Universal Time.
+    pub fn utc() -> Timezone {
+        Timezone {
+            name: String::from("UTC"),
+            transitions: Vec::new(),
+            transition_types: Vec::new(),
+            types: alloc::vec![LocalTimeType {
+                utc_offset: 0,
+                is_dst: false,
+                abbreviation: String::from("UTC"),
+            }],
+            footer: None,
+        }
+    }
+
+    /// The local time type in force at a UTC Unix time.
+    pub fn type_at(&self, utc: i64) -> &LocalTimeType {
+        let index = self.transitions.partition_point(|&t| t <= utc);
+        if index == 0 {
+            // Before the first transition: the first standard-time type.
+            return self.types.iter().find(|t| !t.is_dst).unwrap_or(&self.types[0]);
+        }
+        if index == self.transitions.len() {
+            if let Some(footer) = &self.footer {
+                return footer.type_at(utc);
+            }
+        }
+        &self.types[self.transition_types[index - 1] as usize]
+    }
+
+    /// Converts a UTC time to local time.
+    pub fn to_local(&self, utc: DateTime) -> LocalTime {
+        let unix = utc.to_unix();
+        let local_type = self.type_at(unix);
+        LocalTime {
+            datetime: DateTime::from_unix(unix + local_type.utc_offset as i64).unwrap_or(utc),
+            utc_offset: local_type.utc_offset,
+            is_dst: local_type.is_dst,
+            abbreviation: local_type.abbreviation.clone(),
+        }
+    }
+
+    /// Finds the UTC time for a local time, which may be ambiguous or skipped
+    /// around DST transitions.
+    pub fn resolve_local(&self, local: DateTime) -> LocalResult {
+        let naive = local.to_unix();
+        // Offsets in force a day either side bracket any transition.
+        let before = self.type_at(naive - SECONDS_PER_DAY).utc_offset as i64;
+        let after = self.type_at(naive + SECONDS_PER_DAY).utc_offset as i64;
+        let fits = |offset: i64| self.type_at(naive - offset).utc_offset as i64 == offset;
+        let utc = |offset: i64| DateTime::from_unix(naive - offset).unwrap_or(local);
+
+        match (fits(before), fits(after)) {
+            (true, true) if before != after => {
+                let (first, second) = (utc(before), utc(after));
+                LocalResult::Ambiguous(first.min(second), first.max(second))
+            }
+            (true, _) => LocalResult::Single(utc(before)),
+            (false, true) => LocalResult::Single(utc(after)),
+            (false, false) => LocalResult::Gap(utc(before)),
+        }
+    }
+}
+
+// --- System Timezone ---
+
+static TIMEZONE: Mutex<Option<Timezone>> = Mutex::new(None);
+
+/// Sets the timezone local time is shown in.
+pub fn set_timezone(timezone: Timezone) {
+    log::info!("Timezone: {}", timezone.name);
+    interrupts::without_interrupts(|| *TIMEZONE.lock() = Some(timezone));
+    crate::timer::calendar_changed();
+}
+
+/// Runs `f` with the system timezone, UTC if none was set. Interrupts stay
+/// off so a preempted process never holds the lock.
+pub fn with_timezone<R>(f: impl FnOnce(&Timezone) -> R) -> R {
+    interrupts::without_interrupts(|| match TIMEZONE.lock().as_ref() {
+        Some(timezone) => f(timezone),
+        None => f(&Timezone::utc()),
+    })
+}
+
+/// Converts a UTC time to local time in the system timezone.
+pub fn to_local(utc: DateTime) -> LocalTime {
+    with_timezone(|timezone| timezone.to_local(utc))
+}
+
+/// Finds the UTC time for a local time in the system timezone.
+pub fn resolve_local(local: DateTime) -> LocalResult {
+    with_timezone(|timezone| timezone.resolve_local(local))
+}
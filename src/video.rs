@@ -0,0 +1,261 @@
+// Module for selecting the GOP graphics mode during boot
+
+use alloc::vec::Vec;
+use uefi::prelude::*;
+use uefi::proto::console::gop::{GraphicsOutput, Mode, ModeInfo, PixelFormat};
+
+/// How the bootloader picks a graphics mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModePolicy {
+    /// The largest mode within the configured bounds.
+    Native,
+    /// Leave the mode the firmware set up alone.
+    KeepFirmware,
+    /// Resolutions to try in order of preference, then `Native`.
+    Preferred(Vec<(usize, usize)>),
+}
+
+impl ModePolicy {
+    /// Parses `native`, `keep` or a comma-separated list like `1280x800, 1024x768`.
+    pub fn parse(value: &str) -> Option<ModePolicy> {
+        match value {
+            "native" | "largest" => return Some(ModePolicy::Native),
+            "keep" | "firmware" => return Some(ModePolicy::KeepFirmware),
+            _ => {}
+        }
+
+        let mut resolutions = Vec::new();
+        for entry in value.split(',') {
+            let (w, h) = entry.trim().split_once('x')?;
+            let w: usize = w.trim().parse().ok()?;
+            let h: usize = h.trim().parse().ok()?;
+            if w == 0 || h == 0 {
+                return None;
+            }
+            resolutions.push((w, h));
+        }
+        Some(ModePolicy::Preferred(resolutions))
+    }
+}
+
+// --- Pixel Layout ---
+
+/// Where each color channel lives inside a framebuffer pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelLayout {
+    pub red_shift: u8,
+    pub red_bits: u8,
+    pub green_shift: u8,
+    pub green_bits: u8,
+    pub blue_shift: u8,
+    pub blue_bits: u8,
+    pub bytes_per_pixel: usize,
+}
+
+impl PixelLayout {
+    /// Derives the layout from a mode, or None for BltOnly modes which have no
+    /// linear framebuffer.
+    pub fn from_mode_info(info: &ModeInfo) -> Option<PixelLayout> {
+        match info.pixel_format() {
+            PixelFormat::Rgb => Some(PixelLayout::from_masks(0xFF, 0xFF00, 0xFF_0000, 0xFF00_0000)),
+            PixelFormat::Bgr => Some(PixelLayout::from_masks(0xFF_0000, 0xFF00, 0xFF, 0xFF00_0000)),
+            PixelFormat::Bitmask => {
+                let mask = info.pixel_bitmask()?;
+                if mask.red == 0 || mask.green == 0 || mask.blue == 0 {
+                    return None;
+                }
+                Some(PixelLayout::from_masks(mask.red, mask.green, mask.blue, mask.reserved))
+            }
+            PixelFormat::BltOnly => None,
+        }
+    }
+
+    fn from_masks(red: u32, green: u32, blue: u32, reserved: u32) -> PixelLayout {
+        let all = red | green | blue | reserved;
+        let top_bit = 32 - all.leading_zeros() as usize;
+        PixelLayout {
+            red_shift: red.trailing_zeros() as u8,
+            red_bits: red.count_ones() as u8,
+            green_shift: green.trailing_zeros() as u8,
+            green_bits: green.count_ones() as u8,
+            blue_shift: blue.trailing_zeros() as u8,
+            blue_bits: blue.count_ones() as u8,
+            bytes_per_pixel: top_bit.div_ceil(8),
+        }
+    }
+
+    /// Packs an 8-bit-per-channel color into this layout.
+    pub fn encode(&self, red: u8, green: u8, blue: u8) -> u32 {
+        fn channel(value: u8, shift: u8, bits: u8) -> u32 {
+            let scaled = if bits >= 8 {
+                (value as u32) << (bits - 8)
+            } else {
+                (value as u32) >> (8 - bits)
+            };
+            scaled << shift
+        }
+        channel(red, self.red_shift, self.red_bits)
+            | channel(green, self.green_shift, self.green_bits)
+            | channel(blue, self.blue_shift, self.blue_bits)
+    }
+}
+
+// --- Framebuffer ---
+
+/// The linear framebuffer handed to the kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    pub base: *mut u8,
+    pub size: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Pixels per scanline, which may be larger than `width`.
+    pub stride: usize,
+    pub layout: PixelLayout,
+}
+
+// The framebuffer is plain memory-mapped I/O owned by whoever holds the info;
+// access is serialised by the console lock.
+unsafe impl Send for FramebufferInfo {}
+
+impl FramebufferInfo {
+    /// Writes a single pixel already encoded with `layout.encode`.
+    ///
+    /// # Safety
+    /// `base` must still point at the mapped framebuffer.
+    pub unsafe fn write_pixel(&self, x: usize, y: usize, value: u32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let bpp = self.layout.bytes_per_pixel;
+        let offset = (y * self.stride + x) * bpp;
+        if bpp == 4 {
+            (self.base.add(offset) as *mut u32).write_volatile(value);
+        } else {
+            let bytes = value.to_le_bytes();
+            for (i, byte) in bytes.iter().take(bpp).enumerate() {
+                self.base.add(offset + i).write_volatile(*byte);
+            }
+        }
+    }
+
+    /// Fills the visible area with one color.
+    pub fn fill(&self, red: u8, green: u8, blue: u8) {
+        let value = self.layout.encode(red, green, blue);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                unsafe { self.write_pixel(x, y, value) };
+            }
+        }
+    }
+}
+
+// --- Mode Selection ---
+
+/// Indices of the modes to try, best first, according to the policy.
+fn candidates(modes: &[Mode], policy: &ModePolicy, max_width: usize, max_height: usize) -> Vec<usize> {
+    let usable: Vec<usize> = (0..modes.len())
+        .filter(|&i| PixelLayout::from_mode_info(modes[i].info()).is_some())
+        .collect();
+    let resolution = |i: usize| modes[i].info().resolution();
+    let mut order = Vec::new();
+
+    if let ModePolicy::Preferred(resolutions) = policy {
+        for &wanted in resolutions {
+            if let Some(&i) = usable.iter().find(|&&i| resolution(i) == wanted) {
+                order.push(i);
+            }
+        }
+    }
+
+    // Native, and the fallback for a preferred list: largest first within bounds.
+    let mut bounded: Vec<usize> = usable
+        .iter()
+        .copied()
+        .filter(|&i| {
+            let (w, h) = resolution(i);
+            w <= max_width && h <= max_height
+        })
+        .collect();
+    bounded.sort_by_key(|&i| {
+        let (w, h) = resolution(i);
+        core::cmp::Reverse(w * h)
+    });
+    for i in bounded {
+        if !order.contains(&i) {
+            order.push(i);
+        }
+    }
+    order
+}
+
+/// Sets a graphics mode per the policy and returns the resulting framebuffer.
+/// Modes that fail `set_mode` are skipped; if all fail, the firmware mode is
+/// kept when it has a usable framebuffer.
+pub fn init(
+    bt: &BootServices,
+    policy: &ModePolicy,
+    max_width: usize,
+    max_height: usize,
+) -> Option<FramebufferInfo> {
+    let gop_handle = match bt.get_handle_for_protocol::<GraphicsOutput>() {
+        Ok(handle) => handle,
+        Err(e) => {
+            log::error!("Failed to get GOP handle: {:?}", e);
+            return None;
+        }
+    };
+    let mut gop = match bt.open_protocol_exclusive::<GraphicsOutput>(gop_handle) {
+        Ok(gop) => gop,
+        Err(e) => {
+            log::error!("Failed to open GOP protocol (exclusive): {:?}", e);
+            return None;
+        }
+    };
+
+    let modes: Vec<Mode> = gop.modes().collect();
+    for (i, mode) in modes.iter().enumerate() {
+        let info = mode.info();
+        let (w, h) = info.resolution();
+        log::info!("Mode {}: {}x{} Format: {:?}", i, w, h, info.pixel_format());
+    }
+
+    if *policy != ModePolicy::KeepFirmware {
+        let order = candidates(&modes, policy, max_width, max_height);
+        let mut selected = false;
+        for index in order {
+            let mode = &modes[index];
+            let (w, h) = mode.info().resolution();
+            match gop.set_mode(mode) {
+                Ok(()) => {
+                    log::info!("Selected Mode {}: {}x{}", index, w, h);
+                    selected = true;
+                    break;
+                }
+                Err(e) => log::warn!("Failed to set graphics mode {}: {:?}", index, e),
+            }
+        }
+        if !selected {
+            log::warn!("No requested graphics mode could be set, keeping firmware mode.");
+        }
+    }
+
+    let info = gop.current_mode_info();
+    let layout = match PixelLayout::from_mode_info(&info) {
+        Some(layout) => layout,
+        None => {
+            log::error!("Current graphics mode has no linear framebuffer.");
+            return None;
+        }
+    };
+    let (width, height) = info.resolution();
+    let mut frame_buffer = gop.frame_buffer();
+    Some(FramebufferInfo {
+        base: frame_buffer.as_mut_ptr(),
+        size: frame_buffer.size(),
+        width,
+        height,
+        stride: info.stride(),
+        layout,
+    })
+}